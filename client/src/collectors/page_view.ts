@@ -1,11 +1,20 @@
+import pagePath from "../page_path";
+
 class PageView {
   constructor(endpoint: URL, appId: string) {
     document?.addEventListener("DOMContentLoaded", () => {
-      const path = window?.location?.pathname;
+      const path = window?.location ? pagePath(window.location) : undefined;
+      const referrer = document.referrer || undefined;
       path &&
         navigator.sendBeacon(
           endpoint.toString(),
-          JSON.stringify({ entity: "page", action: "view", path, appId }),
+          JSON.stringify({
+            entity: "page",
+            action: "view",
+            path,
+            referrer,
+            appId,
+          }),
         );
     });
   }
@@ -1,3 +1,5 @@
+import pagePath from "../page_path";
+
 interface TurboEvent extends Event {
   detail: {
     url: string;
@@ -45,7 +47,7 @@ class TurboDrive {
       JSON.stringify({
         entity: "page",
         action: "view",
-        path: url?.pathname,
+        path: url && pagePath(url),
         appId: this.appId,
       }),
     );
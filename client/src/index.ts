@@ -2,6 +2,8 @@ import Anchor from "./collectors/anchor";
 import PageView from "./collectors/page_view";
 import TurboDrive from "./collectors/turbo_drive";
 
+type PropertyValue = string | number | boolean;
+
 type InitializeOptions = {
   endpoint: URL;
   appId: string;
@@ -14,6 +16,7 @@ class AnalyticsCollector {
   public static initialize(
     endpoint: string,
     appId: string,
+    key?: string,
   ): AnalyticsCollector {
     const endpointURL = URL.parse(endpoint);
 
@@ -21,6 +24,11 @@ class AnalyticsCollector {
       throw new Error("Invalid endpoint URL");
     }
 
+    // sendBeacon cannot set headers, so the ingest key travels in the query string
+    if (key) {
+      endpointURL.searchParams.set("key", key);
+    }
+
     return new AnalyticsCollector({ endpoint: endpointURL, appId });
   }
 
@@ -34,6 +42,19 @@ class AnalyticsCollector {
     new Anchor(this.endpoint, this.appId);
     new TurboDrive(this.endpoint, this.appId);
   }
+
+  public track(name: string, props?: Record<string, PropertyValue>): void {
+    navigator.sendBeacon(
+      this.endpoint.toString(),
+      JSON.stringify({
+        entity: "custom",
+        action: "track",
+        name,
+        props,
+        appId: this.appId,
+      }),
+    );
+  }
 }
 
 export default AnalyticsCollector;
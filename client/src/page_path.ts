@@ -0,0 +1,30 @@
+// The collector only reads the campaign and ad click ids from a page view's query string and
+// stores the pathname, so every other parameter is left out.
+const CAMPAIGN_PARAMETERS = [
+  "utm_source",
+  "utm_medium",
+  "utm_campaign",
+  "utm_term",
+  "utm_content",
+  "gclid",
+  "gbraid",
+  "wbraid",
+  "dclid",
+  "msclkid",
+];
+
+function pagePath(url: { pathname: string; search: string }): string {
+  const search = new URLSearchParams();
+
+  new URLSearchParams(url.search).forEach((value, key) => {
+    if (CAMPAIGN_PARAMETERS.includes(key)) {
+      search.append(key, value);
+    }
+  });
+
+  const query = search.toString();
+
+  return query ? `${url.pathname}?${query}` : url.pathname;
+}
+
+export default pagePath;
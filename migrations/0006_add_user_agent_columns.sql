@@ -0,0 +1,6 @@
+ALTER TABLE events
+    ADD COLUMN browser TEXT,
+    ADD COLUMN browser_version TEXT,
+    ADD COLUMN os TEXT,
+    ADD COLUMN os_version TEXT,
+    ADD COLUMN device TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE events
+    ADD COLUMN session_id TEXT;
@@ -0,0 +1,11 @@
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    recorded_by TEXT NOT NULL,
+    visitor_id TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    last_seen_at TEXT NOT NULL,
+    entry_path TEXT,
+    exit_path TEXT,
+    page_count BIGINT NOT NULL,
+    duration_seconds BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE events
+    ADD COLUMN referrer_domain TEXT,
+    ADD COLUMN channel TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE events
+    ADD COLUMN is_bot BOOLEAN;
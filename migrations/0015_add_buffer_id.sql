@@ -0,0 +1,2 @@
+ALTER TABLE events
+    ADD COLUMN buffer_id TEXT;
@@ -29,12 +29,52 @@ CREATE TABLE public.events (
     id text NOT NULL,
     recorded_at text NOT NULL,
     event text NOT NULL,
-    recorded_by text NOT NULL
+    recorded_by text NOT NULL,
+    schema_version text,
+    browser text,
+    browser_version text,
+    os text,
+    os_version text,
+    device text,
+    country text,
+    region text,
+    city text,
+    visitor_id text,
+    session_id text,
+    referrer_domain text,
+    channel text,
+    utm_source text,
+    utm_medium text,
+    utm_campaign text,
+    utm_term text,
+    utm_content text,
+    is_bot boolean,
+    sequence bigint,
+    buffer_id text
 );
 
 
 ALTER TABLE public.events OWNER TO analytics_collector;
 
+--
+-- Name: sessions; Type: TABLE; Schema: public; Owner: analytics_collector
+--
+
+CREATE TABLE public.sessions (
+    id text NOT NULL,
+    recorded_by text NOT NULL,
+    visitor_id text NOT NULL,
+    started_at text NOT NULL,
+    last_seen_at text NOT NULL,
+    entry_path text,
+    exit_path text,
+    page_count bigint NOT NULL,
+    duration_seconds bigint NOT NULL
+);
+
+
+ALTER TABLE public.sessions OWNER TO analytics_collector;
+
 --
 -- Name: events events_pkey; Type: CONSTRAINT; Schema: public; Owner: analytics_collector
 --
@@ -43,6 +83,14 @@ ALTER TABLE ONLY public.events
     ADD CONSTRAINT events_pkey PRIMARY KEY (id);
 
 
+--
+-- Name: sessions sessions_pkey; Type: CONSTRAINT; Schema: public; Owner: analytics_collector
+--
+
+ALTER TABLE ONLY public.sessions
+    ADD CONSTRAINT sessions_pkey PRIMARY KEY (id);
+
+
 --
 -- PostgreSQL database dump complete
 --
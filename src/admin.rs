@@ -0,0 +1,609 @@
+//! Endpoints for operators on the internal port, served only when `ADMIN_TOKEN` is set.
+//!
+//! Dead letters can be browsed, deleted, and replayed. A replay runs the stored payload through
+//! the same validation, authorization, and enrichment as a new event, using the headers it was
+//! received with, and removes the dead letter once the event is stored.
+//!
+//! Export checkpoints can be inspected, moved to export events again or skip them, and deleted
+//! to start an exporter over. Exporters read their checkpoint at the start of every export.
+
+use crate::{
+    AppState, InternalState,
+    enrichment::RequestContext,
+    errors::ApplicationError,
+    ingest::{Prepared, in_flight, prepare_event},
+    middleware::require_admin_token,
+    responses::store_events,
+    storage::{
+        checkpoints::{self, Checkpoint},
+        dead_letters::{DeadLetter, DeadLetterFilter},
+    },
+};
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    middleware::from_fn_with_state,
+    response::IntoResponse,
+    routing::{get, post},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub fn router(state: InternalState) -> Router<InternalState> {
+    Router::new()
+        .route("/dead-letters", get(list_dead_letters))
+        .route("/dead-letters/replay", post(replay_dead_letters))
+        .route(
+            "/dead-letters/{id}",
+            get(get_dead_letter).delete(delete_dead_letter),
+        )
+        .route("/dead-letters/{id}/replay", post(replay_dead_letter))
+        .route("/checkpoints", get(list_checkpoints))
+        .route(
+            "/checkpoints/{exporter}",
+            get(get_checkpoint)
+                .put(put_checkpoint)
+                .delete(delete_checkpoint),
+        )
+        .route_layer(from_fn_with_state(state, require_admin_token))
+}
+
+async fn list_dead_letters(
+    State(state): State<InternalState>,
+    Query(filter): Query<DeadLetterFilter>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let letters = state
+        .app
+        .dead_letters
+        .list(&state.app.connection, &filter)
+        .await?;
+
+    Ok(Json(letters))
+}
+
+async fn get_dead_letter(
+    State(state): State<InternalState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    Ok(Json(find_dead_letter(&state.app, &id).await?))
+}
+
+async fn delete_dead_letter(
+    State(state): State<InternalState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    if !state
+        .app
+        .dead_letters
+        .delete(&state.app.connection, &id)
+        .await?
+    {
+        return Err(not_found(&id));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replays a single dead letter. A non-empty body replaces the stored payload, so a payload
+/// can be corrected before it is ingested.
+async fn replay_dead_letter(
+    State(state): State<InternalState>,
+    Path(id): Path<String>,
+    payload: String,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let mut letter = find_dead_letter(&state.app, &id).await?;
+
+    if !payload.trim().is_empty() {
+        letter.payload = payload;
+    }
+
+    replay(&state.app, &letter).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ReplayReport {
+    replayed: usize,
+    failed: usize,
+    failures: Vec<ReplayFailure>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayFailure {
+    id: String,
+    error: String,
+}
+
+/// Replays every dead letter matching the filter, keeping the ones that fail again.
+async fn replay_dead_letters(
+    State(state): State<InternalState>,
+    Query(filter): Query<DeadLetterFilter>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let letters = state
+        .app
+        .dead_letters
+        .list(&state.app.connection, &filter)
+        .await?;
+
+    let mut report = ReplayReport::default();
+
+    for letter in letters {
+        match replay(&state.app, &letter).await {
+            Ok(()) => report.replayed += 1,
+            Err(e) => {
+                report.failed += 1;
+                report.failures.push(ReplayFailure {
+                    id: letter.id,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(Json(report))
+}
+
+/// Ingests a dead letter's payload like a new event, dated when it was first received, and
+/// deletes the dead letter once that succeeds. Duplicates and filtered bot events count as
+/// replayed.
+async fn replay(state: &AppState, letter: &DeadLetter) -> Result<(), ApplicationError> {
+    let payload: Value = serde_json::from_str(&letter.payload)
+        .map_err(|e| ApplicationError::InvalidPayload(e.to_string()))?;
+
+    // Only authorized requests are kept as dead letters, but without their key, so the admin
+    // token stands in for the key of the app the payload is for
+    let app_id = payload.get("appId").and_then(|v| v.as_str());
+    let request = RequestContext {
+        api_key: app_id
+            .and_then(|app_id| state.apps.get(app_id))
+            .map(|app| app.key.clone()),
+        ..RequestContext::from(&letter.headers)
+    };
+
+    match prepare_event(
+        state,
+        &request,
+        &payload,
+        request.idempotency_key.as_deref(),
+    )? {
+        Prepared::Event(mut event) => {
+            event.recorded_at = letter.received_at;
+            store_events(state, vec![*event]).await?;
+        }
+        // The dead letter is kept until it is known whether the other request stored it
+        Prepared::InFlight(_) => return Err(in_flight()),
+        Prepared::Duplicate(_) | Prepared::Filtered => {}
+    }
+
+    state
+        .dead_letters
+        .delete(&state.connection, &letter.id)
+        .await?;
+
+    Ok(())
+}
+
+async fn find_dead_letter(state: &AppState, id: &str) -> Result<DeadLetter, ApplicationError> {
+    state
+        .dead_letters
+        .get(&state.connection, id)
+        .await?
+        .ok_or_else(|| not_found(id))
+}
+
+fn not_found(id: &str) -> ApplicationError {
+    ApplicationError::NotFound(format!("Dead letter {id} does not exist"))
+}
+
+/// A checkpoint with the number of buffered events the exporter has yet to publish.
+#[derive(Debug, Serialize)]
+struct CheckpointStatus {
+    #[serde(flatten)]
+    checkpoint: Checkpoint,
+    pending: u64,
+}
+
+async fn status(
+    state: &AppState,
+    checkpoint: Checkpoint,
+) -> Result<CheckpointStatus, ApplicationError> {
+    let pending = checkpoints::pending(&state.connection, &checkpoint).await?;
+
+    Ok(CheckpointStatus {
+        checkpoint,
+        pending,
+    })
+}
+
+async fn list_checkpoints(
+    State(state): State<InternalState>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let mut statuses = Vec::new();
+    for checkpoint in checkpoints::list(&state.app.connection).await? {
+        statuses.push(status(&state.app, checkpoint).await?);
+    }
+
+    Ok(Json(statuses))
+}
+
+async fn get_checkpoint(
+    State(state): State<InternalState>,
+    Path(exporter): Path<String>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let checkpoint = checkpoints::get(&state.app.connection, &exporter)
+        .await?
+        .ok_or_else(|| checkpoint_not_found(&exporter))?;
+
+    Ok(Json(status(&state.app, checkpoint).await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckpointUpdate {
+    sequence: i64,
+    /// Sessions written after it are exported again, by default the checkpoint's current one.
+    session_sequence: Option<i64>,
+    exported_at: Option<DateTime<Utc>>,
+}
+
+/// Moves a checkpoint back to export events again, or forward to skip them.
+async fn put_checkpoint(
+    State(state): State<InternalState>,
+    Path(exporter): Path<String>,
+    Json(update): Json<CheckpointUpdate>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    if update.sequence < 0 || update.session_sequence.is_some_and(|sequence| sequence < 0) {
+        return Err(ApplicationError::InvalidPayload(
+            "The sequence cannot be negative".to_string(),
+        ));
+    }
+
+    let current = checkpoints::get(&state.app.connection, &exporter)
+        .await?
+        .unwrap_or_else(|| Checkpoint::start(&exporter));
+
+    let checkpoint = Checkpoint {
+        sequence: update.sequence,
+        session_sequence: update.session_sequence.unwrap_or(current.session_sequence),
+        exported_at: update.exported_at.unwrap_or(current.exported_at),
+        ..current
+    };
+    checkpoints::set(&state.app.connection, &checkpoint).await?;
+
+    Ok(Json(status(&state.app, checkpoint).await?))
+}
+
+/// Deletes a checkpoint, so the exporter exports the whole buffer again.
+async fn delete_checkpoint(
+    State(state): State<InternalState>,
+    Path(exporter): Path<String>,
+) -> Result<impl IntoResponse, ApplicationError> {
+    if !checkpoints::delete(&state.app.connection, &exporter).await? {
+        return Err(checkpoint_not_found(&exporter));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn checkpoint_not_found(exporter: &str) -> ApplicationError {
+    ApplicationError::NotFound(format!("Exporter {exporter} has no checkpoint"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apps::{App, AppRegistry},
+        storage::dead_letters::RequestHeaders,
+    };
+    use axum::{
+        body::{Body, to_bytes},
+        http::{
+            Request,
+            header::{AUTHORIZATION, CONTENT_TYPE},
+        },
+    };
+    use chrono::Utc;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn admin_app() -> (Router, InternalState) {
+        let state = InternalState {
+            app: AppState::for_tests().await,
+            instance_id: "test".to_string(),
+            admin_token: Some(Arc::from("secret")),
+        };
+
+        let app = Router::new()
+            .nest("/admin", router(state.clone()))
+            .with_state(state.clone());
+
+        (app, state)
+    }
+
+    async fn store(state: &InternalState, payload: &str) -> DeadLetter {
+        let letter = DeadLetter {
+            id: crate::utilities::generate_uuid_v4(),
+            received_at: Utc::now() - chrono::TimeDelta::hours(1),
+            recorded_by: Some("test-app".to_string()),
+            reason: "schema_validation".to_string(),
+            error: "The event does not match its schema".to_string(),
+            errors: Vec::new(),
+            payload: payload.to_string(),
+            headers: RequestHeaders::default(),
+        };
+
+        state
+            .app
+            .dead_letters
+            .insert(&state.app.connection, std::slice::from_ref(&letter))
+            .await
+            .unwrap();
+
+        letter
+    }
+
+    fn admin_request(method: &str, uri: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn json_request(method: &str, uri: &str, body: &str) -> Request<Body> {
+        let mut request = admin_request(method, uri, body);
+        request
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        request
+    }
+
+    async fn store_event(state: &InternalState) {
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+        let payload = serde_json::from_str(event).unwrap();
+        let Prepared::Event(event) =
+            prepare_event(&state.app, &RequestContext::default(), &payload, None).unwrap()
+        else {
+            panic!("the event was not prepared");
+        };
+
+        store_events(&state.app, vec![*event]).await.unwrap();
+    }
+
+    async fn count_events(state: &InternalState) -> i64 {
+        let mut rows = state
+            .app
+            .connection
+            .query("SELECT count(*) FROM events", ())
+            .await
+            .unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_requires_admin_token() {
+        let (app, _) = admin_app().await;
+
+        for authorization in [None, Some("Bearer wrong"), Some("secret")] {
+            let mut request = Request::builder().uri("/admin/dead-letters");
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 401);
+        }
+
+        let response = app
+            .oneshot(admin_request("GET", "/admin/dead-letters", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_get_dead_letters() {
+        let (app, state) = admin_app().await;
+        let letter = store(&state, r#"{"appId":"test-app"}"#).await;
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "GET",
+                "/admin/dead-letters?reason=schema_validation",
+                "",
+            ))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let letters: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0]["id"], letter.id);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "GET",
+                &format!("/admin/dead-letters/{}", letter.id),
+                "",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = app
+            .oneshot(admin_request("GET", "/admin/dead-letters/missing", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_replay_with_corrected_payload() {
+        let (app, state) = admin_app().await;
+        let letter = store(&state, r#"{"entity":"page","action":"click"}"#).await;
+        let uri = format!("/admin/dead-letters/{}/replay", letter.id);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("POST", &uri, ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(count_events(&state).await, 0);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(
+                "POST",
+                &uri,
+                r#"{"entity":"page","action":"view","appId":"test-app"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 202);
+        assert_eq!(count_events(&state).await, 1);
+
+        let response = app.oneshot(admin_request("POST", &uri, "")).await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_replay_dates_events_when_received_without_a_stored_key() {
+        let (_, state) = admin_app().await;
+        let state = InternalState {
+            app: AppState {
+                apps: Arc::new(
+                    AppRegistry::from_apps(vec![App {
+                        id: "test-app".to_string(),
+                        key: "pk_test".to_string(),
+                        origins: Vec::new(),
+                        redirect_secret: None,
+                    }])
+                    .unwrap(),
+                ),
+                ..state.app
+            },
+            ..state
+        };
+        let app = Router::new()
+            .nest("/admin", router(state.clone()))
+            .with_state(state.clone());
+
+        let letter = store(
+            &state,
+            r#"{"entity":"page","action":"view","appId":"test-app"}"#,
+        )
+        .await;
+        let response = app
+            .oneshot(admin_request(
+                "POST",
+                &format!("/admin/dead-letters/{}/replay", letter.id),
+                "",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 202);
+
+        let mut rows = state
+            .app
+            .connection
+            .query("SELECT recorded_at FROM events", ())
+            .await
+            .unwrap();
+        let recorded_at: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(recorded_at, letter.received_at.to_rfc3339());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_replay_reports_failures() {
+        let (app, state) = admin_app().await;
+        store(
+            &state,
+            r#"{"entity":"page","action":"view","appId":"test-app"}"#,
+        )
+        .await;
+        let broken = store(&state, r#"{"entity":"page"}"#).await;
+
+        let response = app
+            .oneshot(admin_request("POST", "/admin/dead-letters/replay", ""))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let report: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(report["replayed"], 1);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["failures"][0]["id"], broken.id);
+        assert_eq!(count_events(&state).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_move_and_delete_checkpoints() {
+        let (app, state) = admin_app().await;
+        for _ in 0..3 {
+            store_event(&state).await;
+        }
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", "/admin/checkpoints/parquet", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "PUT",
+                "/admin/checkpoints/parquet",
+                r#"{"sequence":1}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("GET", "/admin/checkpoints", ""))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let checkpoints: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(checkpoints[0]["exporter"], "parquet");
+        assert_eq!(checkpoints[0]["sequence"], 1);
+        assert_eq!(checkpoints[0]["pending"], 2);
+
+        let response = app
+            .clone()
+            .oneshot(json_request(
+                "PUT",
+                "/admin/checkpoints/parquet",
+                r#"{"sequence":-1}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request("DELETE", "/admin/checkpoints/parquet", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 204);
+        assert_eq!(
+            checkpoints::get(&state.app.connection, "parquet")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! The registry of applications allowed to send events.
+//!
+//! Each application has a public ingest key, which is embedded in the pages that send events
+//! and therefore only identifies the app rather than authenticating it, and the set of origins
+//! its pages are served from. Without a registry every `appId` is accepted.
+
+use crate::{enrichment::RequestContext, errors::ApplicationError, redirects::Redirect};
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct App {
+    pub id: String,
+    pub key: String,
+    /// Origins such as `https://example.com` or `https://*.example.com`. An empty list
+    /// allows every origin.
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// Secret that signs the app's click-tracking redirects. Unlike the key it must never be
+    /// sent to browsers. Redirects are disabled for apps without one.
+    #[serde(default)]
+    pub redirect_secret: Option<String>,
+}
+
+impl App {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        let origin = normalize_origin(origin);
+
+        self.origins.is_empty()
+            || self
+                .origins
+                .iter()
+                .any(|allowed| origin_matches(&normalize_origin(allowed), &origin))
+    }
+
+    /// Whether the app's pages are served from `origin`. Unlike `allows_origin`, an app
+    /// without origins is not served from any.
+    pub fn serves_origin(&self, origin: &str) -> bool {
+        !self.origins.is_empty() && self.allows_origin(origin)
+    }
+}
+
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_lowercase()
+}
+
+/// Matches an origin against an allowed origin, where `*` allows everything and a `*.` in
+/// place of the host's first label allows every subdomain.
+fn origin_matches(allowed: &str, origin: &str) -> bool {
+    if allowed == "*" || allowed == origin {
+        return true;
+    }
+
+    let Some((scheme, domain)) = allowed.split_once("://*.") else {
+        return false;
+    };
+
+    origin
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .and_then(|host| host.strip_suffix(domain))
+        .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.'))
+}
+
+#[derive(Debug, Default)]
+pub struct AppRegistry {
+    apps: Option<HashMap<String, App>>,
+}
+
+impl AppRegistry {
+    /// Loads the registry from `APPS_CONFIG_PATH`. When it is unset every app is accepted.
+    pub fn build() -> Result<Self> {
+        match std::env::var("APPS_CONFIG_PATH") {
+            Ok(path) => Self::load(Path::new(&path)),
+            Err(_) => {
+                warn!("APPS_CONFIG_PATH is not set, accepting events for any appId");
+                Ok(Self::default())
+            }
+        }
+    }
+
+    /// Reads a JSON array of apps, e.g.
+    /// `[{"id": "blog", "key": "pk_blog", "origins": ["https://example.com"]}]`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("could not read app registry {}", path.display()))?;
+        let apps: Vec<App> = serde_json::from_str(&contents)
+            .with_context(|| format!("could not parse app registry {}", path.display()))?;
+
+        Self::from_apps(apps)
+    }
+
+    pub fn from_apps(apps: Vec<App>) -> Result<Self> {
+        let mut registry = HashMap::new();
+
+        for app in apps {
+            if app.key.is_empty() {
+                bail!("app {} has an empty key", app.id);
+            }
+
+            if let Some(duplicate) = registry.insert(app.id.clone(), app) {
+                bail!("app {} is registered more than once", duplicate.id);
+            }
+        }
+
+        info!("Loaded {} apps into the registry", registry.len());
+
+        Ok(Self {
+            apps: Some(registry),
+        })
+    }
+
+    pub fn get(&self, app_id: &str) -> Option<&App> {
+        self.apps.as_ref().and_then(|apps| apps.get(app_id))
+    }
+
+    /// Whether any app may be sent events from `origin`, used to answer CORS requests before
+    /// the `appId` of the event is known.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        match &self.apps {
+            None => true,
+            Some(apps) => apps.values().any(|app| app.allows_origin(origin)),
+        }
+    }
+
+    /// Whether a request carries the key of any registered app, for rejections that happen
+    /// before the `appId` of the event is known. Without a registry every request does.
+    pub fn authenticates(&self, request: &RequestContext) -> bool {
+        match (&self.apps, request.api_key.as_deref()) {
+            (None, _) => true,
+            (Some(apps), Some(key)) => apps.values().any(|app| app.key == key),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Checks the signature of a click-tracking redirect and returns the app it was signed for.
+    /// Without a registry there are no secrets, so every redirect is forbidden.
+    pub fn verify_redirect(&self, redirect: &Redirect) -> Result<&App, ApplicationError> {
+        let app = self
+            .apps
+            .as_ref()
+            .and_then(|apps| apps.get(&redirect.app_id))
+            .ok_or_else(|| {
+                ApplicationError::Forbidden(format!("Unknown appId '{}'", redirect.app_id))
+            })?;
+
+        let Some(secret) = app.redirect_secret.as_deref() else {
+            return Err(ApplicationError::Forbidden(format!(
+                "Redirects are not enabled for appId '{}'",
+                redirect.app_id
+            )));
+        };
+
+        if !redirect.verify(secret) {
+            return Err(ApplicationError::Forbidden(
+                "Invalid redirect signature".to_string(),
+            ));
+        }
+
+        Ok(app)
+    }
+
+    /// Accepts events for a registered app sent with its key from one of its origins.
+    /// Unknown apps and other origins are forbidden, a missing or mismatched key is
+    /// unauthorized. Requests without an `Origin` or `Referer`, such as server-side clients,
+    /// are not checked for their origin.
+    pub fn authorize(
+        &self,
+        app_id: &str,
+        request: &RequestContext,
+    ) -> Result<(), ApplicationError> {
+        let Some(apps) = &self.apps else {
+            return Ok(());
+        };
+
+        let app = apps
+            .get(app_id)
+            .ok_or_else(|| ApplicationError::Forbidden(format!("Unknown appId '{app_id}'")))?;
+
+        match request.api_key.as_deref() {
+            Some(key) if key == app.key => {}
+            Some(_) => {
+                return Err(ApplicationError::Unauthorized(format!(
+                    "Invalid key for appId '{app_id}'"
+                )));
+            }
+            None => {
+                return Err(ApplicationError::Unauthorized(format!(
+                    "Missing key for appId '{app_id}'"
+                )));
+            }
+        }
+
+        match request.origin() {
+            Some(origin) if !app.allows_origin(&origin) => Err(ApplicationError::Forbidden(
+                format!("Origin '{origin}' is not allowed for appId '{app_id}'"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> AppRegistry {
+        AppRegistry::from_apps(vec![App {
+            id: "blog".to_string(),
+            key: "pk_blog".to_string(),
+            origins: vec!["https://example.com".to_string()],
+            redirect_secret: Some("s3cret".to_string()),
+        }])
+        .unwrap()
+    }
+
+    fn request(key: Option<&str>, origin: Option<&str>, referer: Option<&str>) -> RequestContext {
+        RequestContext {
+            api_key: key.map(str::to_string),
+            origin: origin.map(str::to_string),
+            referer: referer.map(str::to_string),
+            ..RequestContext::default()
+        }
+    }
+
+    #[test]
+    fn test_authorize_registered_app_with_key() {
+        assert!(
+            registry()
+                .authorize("blog", &request(Some("pk_blog"), None, None))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_authorize_rejects_unknown_app() {
+        assert!(matches!(
+            registry().authorize("garbage", &request(Some("pk_blog"), None, None)),
+            Err(ApplicationError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_authorize_rejects_missing_and_mismatched_keys() {
+        let registry = registry();
+
+        assert!(matches!(
+            registry.authorize("blog", &request(None, None, None)),
+            Err(ApplicationError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            registry.authorize("blog", &request(Some("pk_shop"), None, None)),
+            Err(ApplicationError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_authorize_checks_origin_then_referer() {
+        let registry = registry();
+        let authorize = |origin, referer| {
+            registry.authorize("blog", &request(Some("pk_blog"), origin, referer))
+        };
+
+        assert!(authorize(Some("https://example.com"), None).is_ok());
+        assert!(authorize(None, Some("https://example.com/pricing?plan=pro")).is_ok());
+        assert!(matches!(
+            authorize(Some("https://evil.example"), None),
+            Err(ApplicationError::Forbidden(_))
+        ));
+        assert!(matches!(
+            authorize(None, Some("https://evil.example/")),
+            Err(ApplicationError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_authenticates_with_any_registered_key() {
+        let registry = registry();
+
+        assert!(registry.authenticates(&request(Some("pk_blog"), None, None)));
+        assert!(!registry.authenticates(&request(Some("pk_shop"), None, None)));
+        assert!(!registry.authenticates(&request(None, None, None)));
+        assert!(AppRegistry::default().authenticates(&request(None, None, None)));
+    }
+
+    #[test]
+    fn test_allows_origin_with_wildcard_subdomains() {
+        let app = App {
+            id: "blog".to_string(),
+            key: "pk_blog".to_string(),
+            origins: vec!["https://*.example.com".to_string()],
+            redirect_secret: None,
+        };
+
+        assert!(app.allows_origin("https://blog.example.com"));
+        assert!(app.allows_origin("https://a.b.example.com"));
+        assert!(!app.allows_origin("https://example.com"));
+        assert!(!app.allows_origin("https://blogexample.com"));
+        assert!(!app.allows_origin("http://blog.example.com"));
+    }
+
+    #[test]
+    fn test_verify_redirect_requires_the_apps_signature() {
+        let registry = registry();
+        let redirect = Redirect::signed("s3cret", "blog", "email", "https://example.com/").unwrap();
+
+        assert_eq!(registry.verify_redirect(&redirect).unwrap().id, "blog");
+
+        let forged = Redirect::signed("guess", "blog", "email", "https://example.com/").unwrap();
+        assert!(matches!(
+            registry.verify_redirect(&forged),
+            Err(ApplicationError::Forbidden(_))
+        ));
+
+        // Without a registry there is no secret to check against
+        assert!(AppRegistry::default().verify_redirect(&redirect).is_err());
+    }
+
+    #[test]
+    fn test_disabled_registry_accepts_every_app() {
+        let registry = AppRegistry::default();
+
+        assert!(
+            registry
+                .authorize("anything", &request(None, Some("https://a.example"), None))
+                .is_ok()
+        );
+        assert!(registry.allows_origin("https://a.example"));
+    }
+
+    #[test]
+    fn test_load_reads_apps_and_rejects_duplicates() {
+        let path = std::env::temp_dir().join(crate::utilities::generate_uuid_v4());
+
+        fs::write(
+            &path,
+            r#"[{"id": "blog", "key": "pk_blog", "origins": ["https://example.com"]}]"#,
+        )
+        .unwrap();
+        let registry = AppRegistry::load(&path).unwrap();
+        assert!(registry.allows_origin("https://example.com"));
+        assert!(!registry.allows_origin("https://other.example"));
+
+        fs::write(
+            &path,
+            r#"[{"id": "blog", "key": "a"}, {"id": "blog", "key": "b"}]"#,
+        )
+        .unwrap();
+        let result = AppRegistry::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
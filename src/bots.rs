@@ -0,0 +1,263 @@
+//! Recognises events sent by crawlers, headless browsers and datacenter hosts.
+//!
+//! User-Agent patterns are built in from `src/bots/*.txt` and can be extended with
+//! `BOT_USER_AGENTS_PATH`. Datacenter ranges change too often to be built in, so they are only
+//! read from `BOT_IP_RANGES_PATH`.
+
+use anyhow::{Context, Result, bail};
+use ipnetwork::IpNetwork;
+use std::{fs, net::IpAddr, path::Path};
+use tracing::info;
+
+const CRAWLERS: &str = include_str!("bots/crawlers.txt");
+const HEADLESS: &str = include_str!("bots/headless.txt");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BotReason {
+    Crawler,
+    Headless,
+    Datacenter,
+}
+
+impl BotReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BotReason::Crawler => "crawler",
+            BotReason::Headless => "headless",
+            BotReason::Datacenter => "datacenter",
+        }
+    }
+}
+
+/// What happens to events recognised as bots.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BotFilterMode {
+    /// Events are not checked.
+    Off,
+    /// Events are stored with `is_bot` set.
+    #[default]
+    Tag,
+    /// Events are accepted but not stored.
+    Drop,
+}
+
+impl BotFilterMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "tag" => Ok(Self::Tag),
+            "drop" => Ok(Self::Drop),
+            _ => bail!("invalid bot filter mode {value}, expected off, tag or drop"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BotFilter {
+    pub mode: BotFilterMode,
+    crawlers: Vec<String>,
+    headless: Vec<String>,
+    datacenters: Vec<IpNetwork>,
+}
+
+impl Default for BotFilter {
+    fn default() -> Self {
+        Self {
+            mode: BotFilterMode::default(),
+            crawlers: patterns(CRAWLERS).collect(),
+            headless: patterns(HEADLESS).collect(),
+            datacenters: Vec::new(),
+        }
+    }
+}
+
+/// Non-empty lines that are not `#` comments, lowercased.
+fn patterns(contents: &str) -> impl Iterator<Item = String> + '_ {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+}
+
+fn read(path: &Path, kind: &str) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("could not read {kind} {}", path.display()))
+}
+
+impl BotFilter {
+    /// Reads `BOT_FILTER_MODE`, `BOT_USER_AGENTS_PATH` and `BOT_IP_RANGES_PATH`.
+    pub fn build() -> Result<Self> {
+        let mut filter = Self::default();
+
+        if let Ok(mode) = std::env::var("BOT_FILTER_MODE") {
+            filter.mode = BotFilterMode::parse(&mode)?;
+        }
+
+        if let Ok(path) = std::env::var("BOT_USER_AGENTS_PATH") {
+            filter.load_user_agents(Path::new(&path))?;
+        }
+
+        if let Ok(path) = std::env::var("BOT_IP_RANGES_PATH") {
+            filter.load_ip_ranges(Path::new(&path))?;
+        }
+
+        Ok(filter)
+    }
+
+    /// Adds crawler User-Agent substrings, one per line.
+    pub fn load_user_agents(&mut self, path: &Path) -> Result<()> {
+        let contents = read(path, "bot user agents")?;
+        let count = self.crawlers.len();
+
+        self.crawlers.extend(patterns(&contents));
+        info!("Loaded {} bot user agents", self.crawlers.len() - count);
+
+        Ok(())
+    }
+
+    /// Adds datacenter addresses and CIDR ranges, one per line.
+    pub fn load_ip_ranges(&mut self, path: &Path) -> Result<()> {
+        let contents = read(path, "bot IP ranges")?;
+
+        for line in patterns(&contents) {
+            let network = line
+                .parse::<IpNetwork>()
+                .with_context(|| format!("invalid bot IP range {line} in {}", path.display()))?;
+            self.datacenters.push(network);
+        }
+
+        info!("Loaded {} bot IP ranges", self.datacenters.len());
+
+        Ok(())
+    }
+
+    /// Returns why a request looks automated, checking the User-Agent before the address.
+    /// Requests without a User-Agent are not treated as bots.
+    pub fn detect(&self, user_agent: Option<&str>, client_ip: Option<IpAddr>) -> Option<BotReason> {
+        if self.mode == BotFilterMode::Off {
+            return None;
+        }
+
+        if let Some(user_agent) = user_agent.map(str::to_lowercase) {
+            let matches = |patterns: &[String]| {
+                patterns
+                    .iter()
+                    .any(|pattern| user_agent.contains(pattern.as_str()))
+            };
+
+            if matches(&self.crawlers) {
+                return Some(BotReason::Crawler);
+            }
+
+            if matches(&self.headless) {
+                return Some(BotReason::Headless);
+            }
+        }
+
+        client_ip
+            .filter(|ip| self.datacenters.iter().any(|network| network.contains(*ip)))
+            .map(|_| BotReason::Datacenter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::generate_uuid_v4;
+
+    const CHROME: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36";
+
+    #[test]
+    fn test_detect_crawlers_and_headless_browsers() {
+        let filter = BotFilter::default();
+
+        assert_eq!(
+            filter.detect(
+                Some("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"),
+                None
+            ),
+            Some(BotReason::Crawler)
+        );
+        assert_eq!(
+            filter.detect(Some("facebookexternalhit/1.1"), None),
+            Some(BotReason::Crawler)
+        );
+        assert_eq!(
+            filter.detect(
+                Some("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/126.0.0.0 Safari/537.36"),
+                None
+            ),
+            Some(BotReason::Headless)
+        );
+    }
+
+    #[test]
+    fn test_detect_ignores_browsers_and_http_libraries() {
+        let filter = BotFilter::default();
+
+        assert_eq!(filter.detect(Some(CHROME), None), None);
+        assert_eq!(filter.detect(Some("python-requests/2.32.3"), None), None);
+        assert_eq!(filter.detect(None, None), None);
+    }
+
+    #[test]
+    fn test_detect_datacenter_ranges_from_file() {
+        let path = std::env::temp_dir().join(generate_uuid_v4());
+        fs::write(&path, "# Example cloud\n198.51.100.0/24\n\n2001:db8::/32\n").unwrap();
+
+        let mut filter = BotFilter::default();
+        let result = filter.load_ip_ranges(&path);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(
+            filter.detect(Some(CHROME), Some("198.51.100.23".parse().unwrap())),
+            Some(BotReason::Datacenter)
+        );
+        assert_eq!(
+            filter.detect(None, Some("2001:db8::1".parse().unwrap())),
+            Some(BotReason::Datacenter)
+        );
+        assert_eq!(
+            filter.detect(Some(CHROME), Some("203.0.113.7".parse().unwrap())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_load_user_agents_extends_built_in_list() {
+        let path = std::env::temp_dir().join(generate_uuid_v4());
+        fs::write(&path, "InternalAuditor\n").unwrap();
+
+        let mut filter = BotFilter::default();
+        let result = filter.load_user_agents(&path);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(
+            filter.detect(Some("internalauditor/1.0"), None),
+            Some(BotReason::Crawler)
+        );
+        assert_eq!(
+            filter.detect(Some("Twitterbot/1.0"), None),
+            Some(BotReason::Crawler)
+        );
+    }
+
+    #[test]
+    fn test_off_mode_detects_nothing() {
+        let filter = BotFilter {
+            mode: BotFilterMode::Off,
+            ..BotFilter::default()
+        };
+
+        assert_eq!(filter.detect(Some("Googlebot/2.1"), None), None);
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(BotFilterMode::parse("Drop").unwrap(), BotFilterMode::Drop);
+        assert_eq!(BotFilterMode::parse("tag").unwrap(), BotFilterMode::Tag);
+        assert!(BotFilterMode::parse("block").is_err());
+    }
+}
@@ -0,0 +1,452 @@
+pub mod campaign;
+pub mod client_ip;
+pub mod geoip;
+pub mod referrer;
+pub mod user_agent;
+pub mod visitor;
+
+use crate::{AppState, apps::App, storage::memory::EventContext};
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{
+        HeaderMap,
+        header::{ORIGIN, REFERER, USER_AGENT},
+        request::Parts,
+    },
+};
+use chrono::Utc;
+use client_ip::TrustedProxies;
+use geoip::GeoIp;
+use referrer::ReferrerSources;
+use serde_json::Value;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+use visitor::VisitorSalt;
+
+/// Request attributes the collector derives event context from. The client address is only
+/// used for lookups and is never stored.
+#[derive(Debug, Default, Clone)]
+pub struct RequestContext {
+    pub user_agent: Option<String>,
+    pub client_ip: Option<IpAddr>,
+    pub referer: Option<String>,
+    pub origin: Option<String>,
+    /// The app's ingest key, from the `X-Api-Key` header or the `key` query parameter since
+    /// `sendBeacon` cannot set headers.
+    pub api_key: Option<String>,
+    /// The `Idempotency-Key` header, used as the event id when the payload has no `eventId`.
+    pub idempotency_key: Option<String>,
+}
+
+impl RequestContext {
+    pub fn new(
+        headers: &HeaderMap,
+        query: Option<&str>,
+        peer: Option<IpAddr>,
+        trusted_proxies: &TrustedProxies,
+    ) -> Self {
+        let forwarded_for = headers.get("x-forwarded-for").and_then(|v| v.to_str().ok());
+
+        Self {
+            user_agent: headers
+                .get(USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            client_ip: trusted_proxies.client_ip(peer, forwarded_for),
+            referer: headers
+                .get(REFERER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            origin: headers
+                .get(ORIGIN)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            api_key: headers
+                .get("x-api-key")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+                .or_else(|| query.and_then(|query| query_parameter(query, "key"))),
+            idempotency_key: headers
+                .get("idempotency-key")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+impl RequestContext {
+    /// The origin of the page that sent the request, from the `Origin` header or, since
+    /// `sendBeacon` does not always send one, the scheme and host of the `Referer`.
+    pub fn origin(&self) -> Option<String> {
+        if let Some(origin) = &self.origin {
+            return Some(origin.clone());
+        }
+
+        origin_of(self.referer.as_deref()?)
+    }
+}
+
+/// The scheme and host of a URL.
+fn origin_of(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let host = rest.split(['/', '?', '#']).next()?;
+
+    Some(format!("{scheme}://{host}"))
+}
+
+fn query_parameter(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| urlencoding::decode(value).ok())
+        .map(|value| value.into_owned())
+}
+
+impl FromRequestParts<AppState> for RequestContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+
+        Ok(Self::new(
+            &parts.headers,
+            parts.uri.query(),
+            peer,
+            &state.enricher.trusted_proxies,
+        ))
+    }
+}
+
+/// Holds the configuration and lookup tables used to enrich events.
+#[derive(Debug, Default)]
+pub struct Enricher {
+    pub trusted_proxies: TrustedProxies,
+    pub geoip: GeoIp,
+    pub visitors: VisitorSalt,
+    pub referrer_sources: ReferrerSources,
+}
+
+impl Enricher {
+    /// Reads `TRUSTED_PROXIES`, `GEOIP_DATABASE_PATH` and `REFERRER_SOURCES_PATH` from the
+    /// environment.
+    pub fn build() -> Result<Self> {
+        let trusted_proxies = match std::env::var("TRUSTED_PROXIES") {
+            Ok(value) => TrustedProxies::parse(&value)?,
+            Err(_) => TrustedProxies::default(),
+        };
+
+        Ok(Self {
+            trusted_proxies,
+            geoip: GeoIp::build()?,
+            visitors: VisitorSalt::default(),
+            referrer_sources: ReferrerSources::build()?,
+        })
+    }
+
+    /// Fills the request-derived fields of an event's context from the payload as it was sent.
+    /// `app` is the registered app the event was sent for, whose origins are not counted as
+    /// referrers.
+    pub fn enrich(
+        &self,
+        context: &mut EventContext,
+        request: &RequestContext,
+        app_id: &str,
+        app: Option<&App>,
+        payload: &Value,
+    ) {
+        if let Some(user_agent) = request.user_agent.as_deref() {
+            let parsed = user_agent::parse(user_agent);
+
+            context.browser = parsed.browser;
+            context.browser_version = parsed.browser_version;
+            context.os = parsed.os;
+            context.os_version = parsed.os_version;
+            context.device = parsed.device.map(|d| d.as_str().to_string());
+        }
+
+        if let Some(client_ip) = request.client_ip {
+            let location = self.geoip.lookup(client_ip);
+
+            context.country = location.country;
+            context.region = location.region;
+            context.city = location.city;
+
+            context.visitor_id = Some(self.visitors.visitor_id(
+                app_id,
+                client_ip,
+                request.user_agent.as_deref(),
+                Utc::now(),
+            ));
+        }
+
+        // Clicks and custom events happen on a page the visitor already arrived at, so only
+        // page views are attributed to a referrer and channel.
+        if payload.get("entity").and_then(|v| v.as_str()) != Some("page") {
+            return;
+        }
+
+        // The `Referer` header of a beacon is the page that sent it, so the page's own
+        // `document.referrer` in the payload takes precedence and the header is only used
+        // to recognise navigation within the site. Without one the header is the referrer,
+        // e.g. of a tracking pixel, unless it is one of the app's own pages.
+        let payload_referrer = payload.get("referrer").and_then(|v| v.as_str());
+        let (referrer, page_url) = match payload_referrer {
+            Some(referrer) => (Some(referrer), request.referer.as_deref()),
+            None => (request.referer.as_deref(), request.origin.as_deref()),
+        };
+        let referrer = referrer.filter(|referrer| {
+            !origin_of(referrer)
+                .is_some_and(|origin| app.is_some_and(|app| app.serves_origin(&origin)))
+        });
+
+        // The stored path keeps neither the campaign nor ad click ids, so both are read from
+        // the path as it was sent
+        let path = payload.get("path").and_then(|v| v.as_str());
+        let (_, campaign) = campaign::extract(path.unwrap_or_default());
+
+        let referrer = self.referrer_sources.classify(
+            referrer,
+            page_url,
+            path,
+            campaign.utm_medium.as_deref(),
+        );
+
+        context.referrer_domain = referrer.domain;
+        context.channel = referrer.channel.map(|c| c.as_str().to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_request_context_reads_user_agent_and_peer() {
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("curl/8.6.0"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+
+        let request = RequestContext::new(
+            &headers,
+            None,
+            Some("203.0.113.7".parse().unwrap()),
+            &TrustedProxies::default(),
+        );
+
+        assert_eq!(request.user_agent.as_deref(), Some("curl/8.6.0"));
+        assert_eq!(request.client_ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(request.api_key, None);
+    }
+
+    #[test]
+    fn test_request_context_reads_api_key_from_header_or_query() {
+        let trusted_proxies = TrustedProxies::default();
+        let mut headers = HeaderMap::new();
+
+        let from_query =
+            RequestContext::new(&headers, Some("v=1&key=pk%5Fblog"), None, &trusted_proxies);
+        assert_eq!(from_query.api_key.as_deref(), Some("pk_blog"));
+
+        headers.insert("x-api-key", HeaderValue::from_static("pk_header"));
+        let from_header =
+            RequestContext::new(&headers, Some("key=pk_blog"), None, &trusted_proxies);
+        assert_eq!(from_header.api_key.as_deref(), Some("pk_header"));
+    }
+
+    #[test]
+    fn test_enrich_sets_user_agent_fields() {
+        let request = RequestContext {
+            user_agent: Some(
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.6478.71 Mobile Safari/537.36".to_string(),
+            ),
+            ..RequestContext::default()
+        };
+
+        let mut context = EventContext::default();
+        Enricher::default().enrich(&mut context, &request, "test-app", None, &json!({}));
+
+        assert_eq!(context.browser.as_deref(), Some("Chrome"));
+        assert_eq!(context.os.as_deref(), Some("Android"));
+        assert_eq!(context.device.as_deref(), Some("mobile"));
+    }
+
+    #[test]
+    fn test_enrich_sets_visitor_id_only_with_client_ip() {
+        let enricher = Enricher::default();
+        let request = RequestContext {
+            user_agent: Some("Firefox".to_string()),
+            client_ip: Some("203.0.113.7".parse().unwrap()),
+            ..RequestContext::default()
+        };
+
+        let mut with_ip = EventContext::default();
+        enricher.enrich(&mut with_ip, &request, "test-app", None, &json!({}));
+
+        let mut without_ip = EventContext::default();
+        enricher.enrich(
+            &mut without_ip,
+            &RequestContext {
+                client_ip: None,
+                ..request
+            },
+            "test-app",
+            None,
+            &json!({}),
+        );
+
+        assert!(with_ip.visitor_id.is_some());
+        assert_eq!(without_ip.visitor_id, None);
+    }
+
+    #[test]
+    fn test_enrich_without_request_details_only_sets_direct_channel() {
+        let mut context = EventContext::default();
+        Enricher::default().enrich(
+            &mut context,
+            &RequestContext::default(),
+            "test-app",
+            None,
+            &json!({"entity": "page"}),
+        );
+
+        assert_eq!(
+            context,
+            EventContext {
+                channel: Some("direct".to_string()),
+                ..EventContext::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_enrich_prefers_payload_referrer_over_header() {
+        let enricher = Enricher::default();
+        let request = RequestContext {
+            referer: Some("https://example.com/blog/post".to_string()),
+            ..RequestContext::default()
+        };
+
+        let mut external = EventContext::default();
+        enricher.enrich(
+            &mut external,
+            &request,
+            "test-app",
+            None,
+            &json!({"entity": "page", "referrer": "https://www.reddit.com/r/rust/"}),
+        );
+
+        let mut internal = EventContext::default();
+        enricher.enrich(
+            &mut internal,
+            &request,
+            "test-app",
+            None,
+            &json!({"entity": "page", "referrer": "https://example.com/"}),
+        );
+
+        let mut header_only = EventContext::default();
+        enricher.enrich(
+            &mut header_only,
+            &request,
+            "test-app",
+            None,
+            &json!({"entity": "page"}),
+        );
+
+        assert_eq!(external.referrer_domain.as_deref(), Some("reddit.com"));
+        assert_eq!(external.channel.as_deref(), Some("social"));
+        assert_eq!(internal.referrer_domain, None);
+        assert_eq!(internal.channel.as_deref(), Some("direct"));
+        assert_eq!(header_only.referrer_domain.as_deref(), Some("example.com"));
+        assert_eq!(header_only.channel.as_deref(), Some("referral"));
+    }
+
+    #[test]
+    fn test_enrich_ignores_referer_header_from_own_origin() {
+        let enricher = Enricher::default();
+        let app = App {
+            id: "blog".to_string(),
+            key: "pk_blog".to_string(),
+            origins: vec!["https://example.com".to_string()],
+            redirect_secret: None,
+        };
+        let page_view = json!({"entity": "page"});
+
+        // A beacon sent from the app's own page
+        let beacon = RequestContext {
+            referer: Some("https://example.com/blog/post".to_string()),
+            origin: Some("https://example.com".to_string()),
+            ..RequestContext::default()
+        };
+        let mut context = EventContext::default();
+        enricher.enrich(&mut context, &beacon, "blog", None, &page_view);
+        assert_eq!(context.referrer_domain, None);
+        assert_eq!(context.channel.as_deref(), Some("direct"));
+
+        // A tracking pixel on one of the app's pages sends no `Origin`
+        let pixel = RequestContext {
+            referer: Some("https://example.com/blog/post".to_string()),
+            ..RequestContext::default()
+        };
+        let mut context = EventContext::default();
+        enricher.enrich(&mut context, &pixel, "blog", Some(&app), &page_view);
+        assert_eq!(context.referrer_domain, None);
+        assert_eq!(context.channel.as_deref(), Some("direct"));
+    }
+
+    #[test]
+    fn test_enrich_reads_campaign_from_path_as_sent() {
+        let mut paid = EventContext::default();
+        Enricher::default().enrich(
+            &mut paid,
+            &RequestContext::default(),
+            "test-app",
+            None,
+            &json!({"entity": "page", "path": "/landing?gclid=abc"}),
+        );
+
+        let mut email = EventContext::default();
+        Enricher::default().enrich(
+            &mut email,
+            &RequestContext::default(),
+            "test-app",
+            None,
+            &json!({"entity": "page", "path": "/landing?utm_medium=newsletter"}),
+        );
+
+        assert_eq!(paid.channel.as_deref(), Some("paid"));
+        assert_eq!(email.channel.as_deref(), Some("email"));
+    }
+
+    #[test]
+    fn test_enrich_classifies_page_views_only() {
+        let request = RequestContext {
+            referer: Some("https://www.reddit.com/r/rust/".to_string()),
+            ..RequestContext::default()
+        };
+
+        let mut context = EventContext::default();
+        Enricher::default().enrich(
+            &mut context,
+            &request,
+            "test-app",
+            None,
+            &json!({"entity": "anchor", "action": "click"}),
+        );
+
+        assert_eq!(context.referrer_domain, None);
+        assert_eq!(context.channel, None);
+    }
+}
@@ -0,0 +1,141 @@
+//! UTM campaign parameters.
+//!
+//! Page views may report a full URL or a path with a query string. The UTM parameters are
+//! moved out of it into their own fields and the rest of the query is dropped, so the stored
+//! path only identifies the page.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Campaign {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_medium: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_campaign: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_term: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utm_content: Option<String>,
+}
+
+impl Campaign {
+    fn field(&mut self, parameter: &str) -> Option<&mut Option<String>> {
+        match parameter {
+            "utm_source" => Some(&mut self.utm_source),
+            "utm_medium" => Some(&mut self.utm_medium),
+            "utm_campaign" => Some(&mut self.utm_campaign),
+            "utm_term" => Some(&mut self.utm_term),
+            "utm_content" => Some(&mut self.utm_content),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a URL, a path or a bare query string into the page's pathname and the campaign.
+/// Scheme and host of a full URL are dropped, and so are the other query parameters and the
+/// fragment, which would otherwise make every variant of a URL a page of its own. The first
+/// non-empty value of a repeated parameter wins.
+pub fn extract(url: &str) -> (String, Campaign) {
+    let path = match url.split_once("://") {
+        Some((scheme, rest)) if !scheme.contains(['/', '?', '#']) => {
+            match rest.find(['/', '?', '#']) {
+                Some(index) if rest[index..].starts_with('/') => &rest[index..],
+                _ => "/",
+            }
+        }
+        _ => url,
+    };
+
+    let path = path.split('#').next().unwrap_or_default();
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+    let mut campaign = Campaign::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+        if let Some(field) = campaign.field(key)
+            && field.is_none()
+        {
+            *field = decode(value);
+        }
+    }
+
+    (path.to_string(), campaign)
+}
+
+/// Decodes a query string value, where `+` stands for a space.
+fn decode(value: &str) -> Option<String> {
+    let value = value.replace('+', " ");
+    let value = urlencoding::decode(&value)
+        .map(|decoded| decoded.into_owned())
+        .unwrap_or(value);
+    let value = value.trim();
+
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_from_full_url() {
+        let (path, campaign) = extract(
+            "https://example.com/pricing?utm_source=newsletter&plan=pro&utm_medium=email&utm_campaign=spring+sale#faq",
+        );
+
+        assert_eq!(path, "/pricing");
+        assert_eq!(campaign.utm_source.as_deref(), Some("newsletter"));
+        assert_eq!(campaign.utm_medium.as_deref(), Some("email"));
+        assert_eq!(campaign.utm_campaign.as_deref(), Some("spring sale"));
+        assert_eq!(campaign.utm_term, None);
+    }
+
+    #[test]
+    fn test_extract_from_path_and_query() {
+        let (path, campaign) = extract("/blog?utm_term=rust%20analytics&utm_content=hero");
+
+        assert_eq!(path, "/blog");
+        assert_eq!(campaign.utm_term.as_deref(), Some("rust analytics"));
+        assert_eq!(campaign.utm_content.as_deref(), Some("hero"));
+    }
+
+    #[test]
+    fn test_extract_from_bare_query() {
+        let (path, campaign) = extract("?utm_source=x");
+
+        assert_eq!(path, "");
+        assert_eq!(campaign.utm_source.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn test_extract_url_without_path() {
+        assert_eq!(extract("https://example.com").0, "/");
+        assert_eq!(extract("https://example.com?utm_source=x").0, "/");
+    }
+
+    #[test]
+    fn test_extract_keeps_first_non_empty_value() {
+        let (_, campaign) = extract("/?utm_source=&utm_source=first&utm_source=second");
+
+        assert_eq!(campaign.utm_source.as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn test_extract_keeps_only_the_pathname() {
+        let (path, campaign) = extract("/search?q=a+b&page=2#results");
+
+        assert_eq!(path, "/search");
+        assert_eq!(campaign, Campaign::default());
+
+        let (path, _) = extract("/go?to=https://example.com/");
+        assert_eq!(path, "/go");
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{Result, anyhow};
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+
+/// Proxies whose `X-Forwarded-For` entries are trusted when resolving the client address.
+#[derive(Debug, Default, Clone)]
+pub struct TrustedProxies(Vec<IpNetwork>);
+
+impl TrustedProxies {
+    /// Parses a comma-separated list of addresses and CIDR ranges, e.g. `10.0.0.0/8, 127.0.0.1`.
+    pub fn parse(value: &str) -> Result<Self> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNetwork>()
+                    .map_err(|e| anyhow!("invalid trusted proxy {entry}: {e}"))
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Self)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+
+    /// Resolves the originating client address. `X-Forwarded-For` is only consulted when the
+    /// direct peer is a trusted proxy, and is walked from the right so that a client cannot
+    /// spoof its address by sending its own header.
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let peer = peer?;
+
+        if !self.contains(peer) {
+            return Some(peer);
+        }
+
+        let Some(forwarded_for) = forwarded_for else {
+            return Some(peer);
+        };
+
+        let hops: Vec<IpAddr> = forwarded_for
+            .split(',')
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+
+        hops.iter()
+            .rev()
+            .find(|hop| !self.contains(**hop))
+            .or(hops.first())
+            .copied()
+            .or(Some(peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_accepts_addresses_and_ranges() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8, 127.0.0.1,").unwrap();
+
+        assert!(proxies.contains(ip("10.1.2.3")));
+        assert!(proxies.contains(ip("127.0.0.1")));
+        assert!(!proxies.contains(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_entries() {
+        assert!(TrustedProxies::parse("10.0.0.0/8, not-an-ip").is_err());
+    }
+
+    #[test]
+    fn test_client_ip_ignores_forwarded_for_from_untrusted_peer() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+
+        assert_eq!(
+            proxies.client_ip(Some(ip("203.0.113.7")), Some("198.51.100.1")),
+            Some(ip("203.0.113.7"))
+        );
+    }
+
+    #[test]
+    fn test_client_ip_uses_rightmost_untrusted_hop() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+
+        assert_eq!(
+            proxies.client_ip(
+                Some(ip("10.0.0.2")),
+                Some("198.51.100.1, 203.0.113.7, 10.0.0.5")
+            ),
+            Some(ip("203.0.113.7"))
+        );
+    }
+
+    #[test]
+    fn test_client_ip_falls_back_to_peer_without_forwarded_for() {
+        let proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.2")), None),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(proxies.client_ip(None, Some("203.0.113.7")), None);
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{Result, anyhow};
+use maxminddb::{MaxMindDBError, Reader, geoip2};
+use std::{net::IpAddr, path::Path};
+use tracing::{debug, info};
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Location {
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+}
+
+/// Resolves client addresses against a local MaxMind-format (`.mmdb`) City database.
+#[derive(Default)]
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("enabled", &self.reader.is_some())
+            .finish()
+    }
+}
+
+impl GeoIp {
+    /// Opens the database at `GEOIP_DATABASE_PATH`. Lookups are disabled when it is not set.
+    pub fn build() -> Result<Self> {
+        match std::env::var("GEOIP_DATABASE_PATH") {
+            Ok(path) => Self::open(Path::new(&path)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| anyhow!("could not open GeoIP database {}: {e}", path.display()))?;
+        info!("GeoIP database loaded from {}", path.display());
+
+        Ok(Self {
+            reader: Some(reader),
+        })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Location {
+        let Some(reader) = self.reader.as_ref() else {
+            return Location::default();
+        };
+
+        let city: geoip2::City = match reader.lookup(ip) {
+            Ok(city) => city,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return Location::default(),
+            Err(e) => {
+                debug!("GeoIP lookup failed: {e}");
+                return Location::default();
+            }
+        };
+
+        Location {
+            country: city
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_string),
+            region: city
+                .subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| subdivision.iso_code)
+                .map(str::to_string),
+            city: city
+                .city
+                .and_then(|city| city.names)
+                .and_then(|names| names.get("en").copied())
+                .map(str::to_string),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_without_database_is_empty() {
+        let geoip = GeoIp::default();
+
+        assert_eq!(
+            geoip.lookup("203.0.113.7".parse().unwrap()),
+            Location::default()
+        );
+    }
+
+    #[test]
+    fn test_open_invalid_database_fails() {
+        let path = std::env::temp_dir().join(crate::utilities::generate_uuid_v4());
+        std::fs::write(&path, b"not a maxmind database").unwrap();
+
+        assert!(GeoIp::open(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
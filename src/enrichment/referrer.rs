@@ -0,0 +1,385 @@
+//! Referrer normalization and traffic channel classification.
+//!
+//! A referrer is reduced to its domain and matched against a list of known sources. The
+//! built-in list covers the common search engines, social networks and webmail clients; more
+//! sources can be added with a JSON file that maps domains to channels.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Direct,
+    OrganicSearch,
+    Social,
+    Email,
+    Paid,
+    Referral,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Direct => "direct",
+            Channel::OrganicSearch => "organic_search",
+            Channel::Social => "social",
+            Channel::Email => "email",
+            Channel::Paid => "paid",
+            Channel::Referral => "referral",
+        }
+    }
+}
+
+/// Built-in sources, checked in order. A pattern matches the domain itself and its
+/// subdomains; a pattern ending in `.*` matches the name under any public suffix, so
+/// `google.*` matches both `google.com` and `google.co.uk`. Webmail hosts come before the
+/// search engines that run on the same domains.
+const SOURCES: [(&str, Channel); 36] = [
+    ("mail.google.com", Channel::Email),
+    ("outlook.live.com", Channel::Email),
+    ("outlook.office.com", Channel::Email),
+    ("mail.yahoo.com", Channel::Email),
+    ("mail.proton.me", Channel::Email),
+    ("app.fastmail.com", Channel::Email),
+    ("googleadservices.com", Channel::Paid),
+    ("doubleclick.net", Channel::Paid),
+    ("google.*", Channel::OrganicSearch),
+    ("bing.com", Channel::OrganicSearch),
+    ("duckduckgo.com", Channel::OrganicSearch),
+    ("search.yahoo.com", Channel::OrganicSearch),
+    ("yandex.*", Channel::OrganicSearch),
+    ("baidu.com", Channel::OrganicSearch),
+    ("ecosia.org", Channel::OrganicSearch),
+    ("search.brave.com", Channel::OrganicSearch),
+    ("kagi.com", Channel::OrganicSearch),
+    ("startpage.com", Channel::OrganicSearch),
+    ("qwant.com", Channel::OrganicSearch),
+    ("facebook.com", Channel::Social),
+    ("fb.me", Channel::Social),
+    ("instagram.com", Channel::Social),
+    ("t.co", Channel::Social),
+    ("twitter.com", Channel::Social),
+    ("x.com", Channel::Social),
+    ("linkedin.com", Channel::Social),
+    ("lnkd.in", Channel::Social),
+    ("reddit.com", Channel::Social),
+    ("news.ycombinator.com", Channel::Social),
+    ("pinterest.*", Channel::Social),
+    ("tiktok.com", Channel::Social),
+    ("youtube.com", Channel::Social),
+    ("bsky.app", Channel::Social),
+    ("mastodon.social", Channel::Social),
+    ("threads.net", Channel::Social),
+    ("whatsapp.com", Channel::Social),
+];
+
+/// Query parameters that ad platforms append to landing page URLs.
+const CLICK_IDS: [&str; 5] = ["gclid", "gbraid", "wbraid", "dclid", "msclkid"];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Referrer {
+    pub domain: Option<String>,
+    pub channel: Option<Channel>,
+}
+
+/// Additional sources, checked before the built-in list so they can also override it.
+#[derive(Debug, Default)]
+pub struct ReferrerSources {
+    custom: Vec<(String, Channel)>,
+}
+
+impl ReferrerSources {
+    /// Loads additional sources from `REFERRER_SOURCES_PATH` when set.
+    pub fn build() -> Result<Self> {
+        match std::env::var("REFERRER_SOURCES_PATH") {
+            Ok(path) => Self::load(Path::new(&path)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Reads a JSON object mapping domain patterns to channels, e.g.
+    /// `{"news.example.com": "social"}`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("could not read referrer sources {}", path.display()))?;
+        let sources: HashMap<String, Channel> = serde_json::from_str(&contents)
+            .with_context(|| format!("could not parse referrer sources {}", path.display()))?;
+
+        let mut custom: Vec<(String, Channel)> = sources
+            .into_iter()
+            .map(|(pattern, channel)| (pattern.to_lowercase(), channel))
+            .collect();
+
+        // Longer patterns are more specific, so they win over their parent domains.
+        custom.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+        Ok(Self { custom })
+    }
+
+    /// Classifies a visit. `page_url` is the URL of the page the event was sent from and is
+    /// used to ignore navigation within the site. `path` is checked for ad click ids and a
+    /// campaign's `utm_medium` takes precedence over the referring domain.
+    pub fn classify(
+        &self,
+        referrer: Option<&str>,
+        page_url: Option<&str>,
+        path: Option<&str>,
+        utm_medium: Option<&str>,
+    ) -> Referrer {
+        let page_domain = page_url.and_then(domain);
+        let domain = referrer
+            .and_then(domain)
+            .filter(|domain| Some(domain) != page_domain.as_ref());
+
+        if path.is_some_and(has_click_id) {
+            return Referrer {
+                domain,
+                channel: Some(Channel::Paid),
+            };
+        }
+
+        let channel = match (utm_medium.and_then(medium_channel), &domain) {
+            (Some(channel), _) => channel,
+            (None, None) => Channel::Direct,
+            (None, Some(domain)) => self.lookup(domain).unwrap_or(Channel::Referral),
+        };
+
+        Referrer {
+            domain,
+            channel: Some(channel),
+        }
+    }
+
+    fn lookup(&self, domain: &str) -> Option<Channel> {
+        self.custom
+            .iter()
+            .map(|(pattern, channel)| (pattern.as_str(), *channel))
+            .chain(SOURCES)
+            .find(|(pattern, _)| matches(pattern, domain))
+            .map(|(_, channel)| channel)
+    }
+}
+
+/// Reduces a URL to its lowercase host without a leading `www.`. Returns `None` for values
+/// without a host, such as an empty `document.referrer`.
+pub fn domain(url: &str) -> Option<String> {
+    let url = url.trim();
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        // IPv6 literals keep their colons, so only strip the brackets and port
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    let host = host.trim_end_matches('.').to_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_string())
+    }
+}
+
+fn matches(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_suffix(".*") {
+        Some(name) => {
+            // the name followed by a one or two label suffix, e.g. `google.de`, `google.co.uk`
+            let labels: Vec<&str> = domain.split('.').collect();
+            labels
+                .iter()
+                .rposition(|label| *label == name)
+                .is_some_and(|index| (1..=2).contains(&(labels.len() - index - 1)))
+        }
+        None => {
+            domain == pattern
+                || domain
+                    .strip_suffix(pattern)
+                    .is_some_and(|subdomain| subdomain.ends_with('.'))
+        }
+    }
+}
+
+/// Maps the common `utm_medium` conventions to a channel. Other mediums fall back to the
+/// referring domain.
+fn medium_channel(medium: &str) -> Option<Channel> {
+    match medium.to_lowercase().as_str() {
+        "cpc" | "ppc" | "cpm" | "cpv" | "paid" | "paidsearch" | "paid_search" | "paid-search"
+        | "display" | "banner" | "paid_social" | "paid-social" | "paidsocial" => {
+            Some(Channel::Paid)
+        }
+        "email" | "e-mail" | "newsletter" => Some(Channel::Email),
+        "social" | "social-network" | "social_network" | "social-media" | "social_media" => {
+            Some(Channel::Social)
+        }
+        "organic" => Some(Channel::OrganicSearch),
+        "referral" => Some(Channel::Referral),
+        _ => None,
+    }
+}
+
+fn has_click_id(path: &str) -> bool {
+    let Some((_, query)) = path.split_once('?') else {
+        return false;
+    };
+    let query = query.split('#').next().unwrap_or_default();
+
+    query
+        .split('&')
+        .filter_map(|pair| pair.split('=').next())
+        .any(|key| CLICK_IDS.contains(&key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_normalizes_urls() {
+        assert_eq!(
+            domain("https://www.Google.com/search?q=x").as_deref(),
+            Some("google.com")
+        );
+        assert_eq!(
+            domain("http://user@news.example.com:8080/a").as_deref(),
+            Some("news.example.com")
+        );
+        assert_eq!(
+            domain("android-app://com.google.android.gm/").as_deref(),
+            Some("com.google.android.gm")
+        );
+        assert_eq!(
+            domain("http://[2001:db8::1]:80/").as_deref(),
+            Some("2001:db8::1")
+        );
+        assert_eq!(domain(""), None);
+    }
+
+    #[test]
+    fn test_classify_builtin_sources() {
+        let sources = ReferrerSources::default();
+        let channel = |referrer| sources.classify(Some(referrer), None, None, None).channel;
+
+        assert_eq!(
+            channel("https://www.google.co.uk/"),
+            Some(Channel::OrganicSearch)
+        );
+        assert_eq!(
+            channel("https://duckduckgo.com/"),
+            Some(Channel::OrganicSearch)
+        );
+        assert_eq!(
+            channel("https://mail.google.com/mail/u/0/"),
+            Some(Channel::Email)
+        );
+        assert_eq!(
+            channel("https://l.facebook.com/l.php"),
+            Some(Channel::Social)
+        );
+        assert_eq!(channel("https://t.co/abc"), Some(Channel::Social));
+        assert_eq!(
+            channel("https://blog.example.org/post"),
+            Some(Channel::Referral)
+        );
+    }
+
+    #[test]
+    fn test_classify_does_not_match_partial_labels() {
+        let sources = ReferrerSources::default();
+        let referrer = sources.classify(Some("https://notx.com/"), None, None, None);
+
+        assert_eq!(referrer.channel, Some(Channel::Referral));
+    }
+
+    #[test]
+    fn test_classify_direct_and_internal_navigation() {
+        let sources = ReferrerSources::default();
+
+        assert_eq!(
+            sources.classify(None, None, None, None),
+            Referrer {
+                domain: None,
+                channel: Some(Channel::Direct),
+            }
+        );
+        assert_eq!(
+            sources.classify(
+                Some("https://example.com/pricing"),
+                Some("https://www.example.com/signup"),
+                None,
+                None,
+            ),
+            Referrer {
+                domain: None,
+                channel: Some(Channel::Direct),
+            }
+        );
+    }
+
+    #[test]
+    fn test_classify_click_ids_as_paid() {
+        let sources = ReferrerSources::default();
+        let referrer = sources.classify(
+            Some("https://www.google.com/"),
+            None,
+            Some("/landing?gclid=abc&x=1"),
+            None,
+        );
+
+        assert_eq!(referrer.domain.as_deref(), Some("google.com"));
+        assert_eq!(referrer.channel, Some(Channel::Paid));
+    }
+
+    #[test]
+    fn test_classify_prefers_utm_medium() {
+        let sources = ReferrerSources::default();
+        let channel = |referrer, medium| sources.classify(referrer, None, None, medium).channel;
+
+        assert_eq!(
+            channel(Some("https://www.google.com/"), Some("cpc")),
+            Some(Channel::Paid)
+        );
+        assert_eq!(channel(None, Some("Newsletter")), Some(Channel::Email));
+        assert_eq!(
+            channel(Some("https://www.google.com/"), Some("unknown")),
+            Some(Channel::OrganicSearch)
+        );
+    }
+
+    #[test]
+    fn test_load_custom_sources_override_builtin() {
+        let path = std::env::temp_dir().join(crate::utilities::generate_uuid_v4());
+        fs::write(
+            &path,
+            r#"{"news.example.com": "social", "youtube.com": "referral"}"#,
+        )
+        .unwrap();
+
+        let sources = ReferrerSources::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let channel = |referrer| sources.classify(Some(referrer), None, None, None).channel;
+        assert_eq!(channel("https://news.example.com/a"), Some(Channel::Social));
+        assert_eq!(
+            channel("https://www.youtube.com/watch"),
+            Some(Channel::Referral)
+        );
+        assert_eq!(channel("https://example.com/"), Some(Channel::Referral));
+    }
+
+    #[test]
+    fn test_load_rejects_unknown_channels() {
+        let path = std::env::temp_dir().join(crate::utilities::generate_uuid_v4());
+        fs::write(&path, r#"{"example.com": "carrier-pigeon"}"#).unwrap();
+
+        let result = ReferrerSources::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! A small, dependency-free User-Agent parser.
+//!
+//! It only recognises the browser and OS families that make up nearly all real traffic and
+//! classifies the device, which is enough to answer "what share of traffic is mobile".
+//! Anything it cannot identify is left empty rather than guessed. Bots are recognised by the
+//! bot filter's lists instead, which mark their device as `bot`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceClass {
+    Desktop,
+    Mobile,
+    Tablet,
+    Bot,
+}
+
+impl DeviceClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceClass::Desktop => "desktop",
+            DeviceClass::Mobile => "mobile",
+            DeviceClass::Tablet => "tablet",
+            DeviceClass::Bot => "bot",
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct UserAgent {
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub device: Option<DeviceClass>,
+}
+
+/// Browser tokens in the order they must be checked. Many browsers include the tokens of the
+/// engines they are built on (Edge and Opera both claim to be Chrome and Safari), so the most
+/// specific token comes first.
+const BROWSERS: [(&str, &str); 9] = [
+    ("Edg/", "Edge"),
+    ("EdgiOS/", "Edge"),
+    ("OPR/", "Opera"),
+    ("SamsungBrowser/", "Samsung Internet"),
+    ("FxiOS/", "Firefox"),
+    ("Firefox/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+    ("Version/", "Safari"),
+];
+
+pub fn parse(user_agent: &str) -> UserAgent {
+    let (browser, browser_version) = parse_browser(user_agent);
+    let (os, os_version) = parse_os(user_agent);
+
+    UserAgent {
+        browser,
+        browser_version,
+        os,
+        os_version,
+        device: Some(parse_device(user_agent)),
+    }
+}
+
+fn parse_browser(user_agent: &str) -> (Option<String>, Option<String>) {
+    for (token, family) in BROWSERS {
+        // Safari reports its version in a `Version/` token, which other WebKit browsers
+        // also send, so only trust it when the UA actually claims to be Safari.
+        if family == "Safari" && !user_agent.contains("Safari/") {
+            continue;
+        }
+
+        if let Some(version) = version_after(user_agent, token) {
+            return (Some(family.to_string()), Some(version));
+        }
+    }
+
+    if user_agent.contains("Trident/") || user_agent.contains("MSIE ") {
+        let version =
+            version_after(user_agent, "MSIE ").or_else(|| version_after(user_agent, "rv:"));
+        return (Some("Internet Explorer".to_string()), version);
+    }
+
+    (None, None)
+}
+
+fn parse_os(user_agent: &str) -> (Option<String>, Option<String>) {
+    if let Some(version) = version_after(user_agent, "Windows NT ") {
+        return (Some("Windows".to_string()), Some(version));
+    }
+
+    if user_agent.contains("iPhone") || user_agent.contains("iPad") || user_agent.contains("iPod") {
+        let version = version_after(user_agent, "iPhone OS ")
+            .or_else(|| version_after(user_agent, "CPU OS "))
+            .map(|v| v.replace('_', "."));
+        return (Some("iOS".to_string()), version);
+    }
+
+    if let Some(version) = version_after(user_agent, "Mac OS X ") {
+        return (Some("macOS".to_string()), Some(version.replace('_', ".")));
+    }
+
+    if user_agent.contains("Android") {
+        return (
+            Some("Android".to_string()),
+            version_after(user_agent, "Android "),
+        );
+    }
+
+    if user_agent.contains("CrOS") {
+        return (Some("Chrome OS".to_string()), None);
+    }
+
+    if user_agent.contains("Linux") {
+        return (Some("Linux".to_string()), None);
+    }
+
+    (None, None)
+}
+
+fn parse_device(user_agent: &str) -> DeviceClass {
+    if user_agent.contains("iPad")
+        || user_agent.contains("Tablet")
+        || (user_agent.contains("Android") && !user_agent.contains("Mobile"))
+    {
+        return DeviceClass::Tablet;
+    }
+
+    if user_agent.contains("Mobi") || user_agent.contains("iPhone") || user_agent.contains("iPod") {
+        return DeviceClass::Mobile;
+    }
+
+    DeviceClass::Desktop
+}
+
+/// Returns the version number that directly follows `token`, accepting digits, dots and
+/// underscores (iOS and macOS separate version parts with underscores).
+fn version_after(user_agent: &str, token: &str) -> Option<String> {
+    let start = user_agent.find(token)? + token.len();
+    let version: String = user_agent[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == '_')
+        .collect();
+
+    if version.is_empty() {
+        None
+    } else {
+        Some(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chrome_on_windows() {
+        let ua = parse(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36",
+        );
+
+        assert_eq!(ua.browser.as_deref(), Some("Chrome"));
+        assert_eq!(ua.browser_version.as_deref(), Some("126.0.0.0"));
+        assert_eq!(ua.os.as_deref(), Some("Windows"));
+        assert_eq!(ua.os_version.as_deref(), Some("10.0"));
+        assert_eq!(ua.device, Some(DeviceClass::Desktop));
+    }
+
+    #[test]
+    fn test_parse_safari_on_iphone() {
+        let ua = parse(
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_5 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.5 Mobile/15E148 Safari/604.1",
+        );
+
+        assert_eq!(ua.browser.as_deref(), Some("Safari"));
+        assert_eq!(ua.browser_version.as_deref(), Some("17.5"));
+        assert_eq!(ua.os.as_deref(), Some("iOS"));
+        assert_eq!(ua.os_version.as_deref(), Some("17.5"));
+        assert_eq!(ua.device, Some(DeviceClass::Mobile));
+    }
+
+    #[test]
+    fn test_parse_safari_on_ipad() {
+        let ua = parse(
+            "Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.6 Mobile/15E148 Safari/604.1",
+        );
+
+        assert_eq!(ua.os.as_deref(), Some("iOS"));
+        assert_eq!(ua.os_version.as_deref(), Some("16.6"));
+        assert_eq!(ua.device, Some(DeviceClass::Tablet));
+    }
+
+    #[test]
+    fn test_parse_edge_is_not_reported_as_chrome() {
+        let ua = parse(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/125.0.0.0 Safari/537.36 Edg/125.0.2535.67",
+        );
+
+        assert_eq!(ua.browser.as_deref(), Some("Edge"));
+        assert_eq!(ua.browser_version.as_deref(), Some("125.0.2535.67"));
+    }
+
+    #[test]
+    fn test_parse_firefox_on_macos() {
+        let ua = parse(
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 14.5; rv:127.0) Gecko/20100101 Firefox/127.0",
+        );
+
+        assert_eq!(ua.browser.as_deref(), Some("Firefox"));
+        assert_eq!(ua.browser_version.as_deref(), Some("127.0"));
+        assert_eq!(ua.os.as_deref(), Some("macOS"));
+        assert_eq!(ua.os_version.as_deref(), Some("14.5"));
+        assert_eq!(ua.device, Some(DeviceClass::Desktop));
+    }
+
+    #[test]
+    fn test_parse_chrome_on_android_phone_and_tablet() {
+        let phone = parse(
+            "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.6478.71 Mobile Safari/537.36",
+        );
+        let tablet = parse(
+            "Mozilla/5.0 (Linux; Android 13; SM-X700) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.6478.71 Safari/537.36",
+        );
+
+        assert_eq!(phone.os.as_deref(), Some("Android"));
+        assert_eq!(phone.os_version.as_deref(), Some("14"));
+        assert_eq!(phone.device, Some(DeviceClass::Mobile));
+        assert_eq!(tablet.device, Some(DeviceClass::Tablet));
+    }
+
+    #[test]
+    fn test_parse_unknown_user_agent() {
+        let ua = parse("SomethingElse");
+
+        assert_eq!(ua.browser, None);
+        assert_eq!(ua.os, None);
+        assert_eq!(ua.device, Some(DeviceClass::Desktop));
+    }
+}
@@ -0,0 +1,258 @@
+//! Cookie-less visitor identifiers.
+//!
+//! A visitor is identified by hashing the app, client address and User-Agent together with a
+//! random salt. The salt is replaced at the start of every UTC day and the previous one is
+//! dropped, so identifiers cannot be linked across days or reversed into the inputs. The
+//! current salt is kept in the buffer, so identifiers stay the same across a restart during the
+//! day.
+
+use crate::storage::visitor_salt;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use libsql::Connection;
+use sha2::{Digest, Sha256};
+use std::{net::IpAddr, sync::RwLock};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub struct VisitorSalt {
+    current: RwLock<Salt>,
+}
+
+#[derive(Debug)]
+struct Salt {
+    day: NaiveDate,
+    value: [u8; 32],
+    /// Whether the buffer holds this salt yet.
+    stored: bool,
+}
+
+impl Salt {
+    fn new(day: NaiveDate) -> Self {
+        Self {
+            day,
+            value: random_salt(),
+            stored: false,
+        }
+    }
+}
+
+impl Default for VisitorSalt {
+    fn default() -> Self {
+        Self {
+            current: RwLock::new(Salt::new(Utc::now().date_naive())),
+        }
+    }
+}
+
+impl VisitorSalt {
+    pub fn visitor_id(
+        &self,
+        app_id: &str,
+        client_ip: IpAddr,
+        user_agent: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> String {
+        let salt = self.salt_for(now.date_naive());
+
+        let mut hasher = Sha256::new();
+        hasher.update(salt);
+        hasher.update(app_id.as_bytes());
+        hasher.update([0]);
+        hasher.update(client_ip.to_string().as_bytes());
+        hasher.update([0]);
+        hasher.update(user_agent.unwrap_or_default().as_bytes());
+
+        hasher.finalize()[..16]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Picks up the salt stored before a restart if it is still for the current day, and
+    /// replaces an expired one.
+    pub async fn restore(&self, connection: &Connection, now: DateTime<Utc>) -> Result<()> {
+        match visitor_salt::get(connection).await? {
+            Some((day, value)) if day == now.date_naive() => {
+                *self
+                    .current
+                    .write()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = Salt {
+                    day,
+                    value,
+                    stored: true,
+                };
+
+                Ok(())
+            }
+            _ => self.store(connection, now).await,
+        }
+    }
+
+    /// Rotates the salt if the day has changed and stores it in place of the previous one,
+    /// unless it is stored already.
+    pub async fn store(&self, connection: &Connection, now: DateTime<Utc>) -> Result<()> {
+        let day = now.date_naive();
+        let value = self.salt_for(day);
+
+        if self
+            .current
+            .read()
+            .is_ok_and(|current| current.stored && current.day == day)
+        {
+            return Ok(());
+        }
+
+        visitor_salt::replace(connection, day, &value).await?;
+
+        let mut current = self
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if current.day == day && current.value == value {
+            current.stored = true;
+        }
+
+        Ok(())
+    }
+
+    fn salt_for(&self, day: NaiveDate) -> [u8; 32] {
+        if let Ok(current) = self.current.read()
+            && current.day == day
+        {
+            return current.value;
+        }
+
+        let mut current = self
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Another request may have rotated the salt while we waited for the lock.
+        if current.day != day {
+            *current = Salt::new(day);
+        }
+
+        current.value
+    }
+}
+
+fn random_salt() -> [u8; 32] {
+    let mut salt = [0; 32];
+    salt[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+    salt[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+    salt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_visitor_id_is_stable_within_a_day() {
+        let salt = VisitorSalt::default();
+        let morning = Utc::now()
+            .date_naive()
+            .and_hms_opt(8, 0, 0)
+            .unwrap()
+            .and_utc();
+        let evening = Utc::now()
+            .date_naive()
+            .and_hms_opt(20, 0, 0)
+            .unwrap()
+            .and_utc();
+
+        let first = salt.visitor_id("blog", ip("203.0.113.7"), Some("Firefox"), morning);
+        let second = salt.visitor_id("blog", ip("203.0.113.7"), Some("Firefox"), evening);
+
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 32);
+    }
+
+    #[test]
+    fn test_visitor_id_differs_by_app_address_and_user_agent() {
+        let salt = VisitorSalt::default();
+        let now = Utc::now();
+
+        let base = salt.visitor_id("blog", ip("203.0.113.7"), Some("Firefox"), now);
+
+        assert_ne!(
+            base,
+            salt.visitor_id("shop", ip("203.0.113.7"), Some("Firefox"), now)
+        );
+        assert_ne!(
+            base,
+            salt.visitor_id("blog", ip("203.0.113.8"), Some("Firefox"), now)
+        );
+        assert_ne!(
+            base,
+            salt.visitor_id("blog", ip("203.0.113.7"), Some("Chrome"), now)
+        );
+    }
+
+    #[test]
+    fn test_visitor_id_changes_when_the_day_changes() {
+        let salt = VisitorSalt::default();
+        let today = Utc::now();
+        let tomorrow = today + chrono::TimeDelta::days(1);
+
+        let first = salt.visitor_id("blog", ip("203.0.113.7"), None, today);
+        let second = salt.visitor_id("blog", ip("203.0.113.7"), None, tomorrow);
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_previous_salt_is_discarded() {
+        let salt = VisitorSalt::default();
+        let day_one = Utc.with_ymd_and_hms(2030, 1, 1, 12, 0, 0).unwrap();
+        let day_two = Utc.with_ymd_and_hms(2030, 1, 2, 12, 0, 0).unwrap();
+
+        let first = salt.visitor_id("blog", ip("203.0.113.7"), None, day_one);
+        salt.visitor_id("blog", ip("203.0.113.7"), None, day_two);
+        let again = salt.visitor_id("blog", ip("203.0.113.7"), None, day_one);
+
+        assert_ne!(first, again);
+    }
+
+    #[tokio::test]
+    async fn test_restore_keeps_the_stored_salt_for_the_same_day() {
+        let connection = crate::storage::memory::initialize().await.unwrap();
+        let now = Utc::now();
+
+        let before = VisitorSalt::default();
+        before.store(&connection, now).await.unwrap();
+        let after = VisitorSalt::default();
+        after.restore(&connection, now).await.unwrap();
+
+        assert_eq!(
+            before.visitor_id("blog", ip("203.0.113.7"), None, now),
+            after.visitor_id("blog", ip("203.0.113.7"), None, now)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_replaces_an_expired_salt() {
+        let connection = crate::storage::memory::initialize().await.unwrap();
+        let today = Utc::now();
+        let tomorrow = today + chrono::TimeDelta::days(1);
+
+        let salt = VisitorSalt::default();
+        salt.store(&connection, today).await.unwrap();
+        let (_, expired) = visitor_salt::get(&connection).await.unwrap().unwrap();
+
+        VisitorSalt::default()
+            .restore(&connection, tomorrow)
+            .await
+            .unwrap();
+
+        let (day, stored) = visitor_salt::get(&connection).await.unwrap().unwrap();
+        assert_eq!(day, tomorrow.date_naive());
+        assert_ne!(stored, expired);
+    }
+}
@@ -1,7 +1,14 @@
 use axum::{
-    http::StatusCode,
+    Json,
+    http::{
+        HeaderValue, StatusCode,
+        header::{CONTENT_TYPE, RETRY_AFTER},
+    },
     response::{IntoResponse, Response},
 };
+use jsonschema::ValidationError;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use tracing::error;
 
 #[allow(dead_code)]
@@ -9,6 +16,110 @@ use tracing::error;
 pub enum ApplicationError {
     Unknown(anyhow::Error),
     InvalidPayload(String),
+    /// The payload does not match its JSON Schema.
+    ValidationFailed(Vec<ValidationIssue>),
+    PayloadTooLarge(String),
+    /// The request body uses a `Content-Encoding` the collector cannot decode.
+    UnsupportedMediaType(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// An event with the same id is still being stored, so the request should be retried once
+    /// it is known whether that worked.
+    Conflict(String),
+    /// Rejected by the rate limiter, with the time until the request may be retried.
+    TooManyRequests(String, Duration),
+    /// The event buffer is full and does not take new events until it has been exported, or
+    /// too many requests are waiting to be written to it.
+    ServiceUnavailable(String),
+}
+
+/// A single JSON Schema violation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidationIssue {
+    /// JSON Pointer to the offending value, e.g. `/props/plan`. Empty for the payload itself.
+    pub instance_path: String,
+    /// The schema keyword that failed, e.g. `maxLength` or `required`.
+    pub keyword: String,
+    pub message: String,
+}
+
+impl From<&ValidationError<'_>> for ValidationIssue {
+    fn from(error: &ValidationError<'_>) -> Self {
+        let schema_path = error.schema_path.as_str();
+
+        Self {
+            instance_path: error.instance_path.as_str().to_string(),
+            keyword: schema_path
+                .rsplit('/')
+                .next()
+                .unwrap_or(schema_path)
+                .to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// An RFC 7807 problem details document, sent as `application/problem+json`.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ValidationIssue>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: Option<String>) -> Self {
+        Self {
+            // The status code says everything there is to know, see RFC 7807 section 4.2
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail,
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        (
+            status,
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            )],
+            Json(self),
+        )
+            .into_response()
+    }
+}
+
+impl ApplicationError {
+    /// Why a payload is kept as a dead letter, or `None` for errors that are not caused by
+    /// the payload, such as rate limiting. Failed authorization is not kept either, so that
+    /// unauthenticated requests cannot push the dead letters of real clients out.
+    pub fn dead_letter_reason(&self) -> Option<&'static str> {
+        match self {
+            ApplicationError::InvalidPayload(_) => Some("invalid_payload"),
+            ApplicationError::ValidationFailed(_) => Some("schema_validation"),
+            _ => None,
+        }
+    }
+
+    pub fn validation_issues(&self) -> &[ValidationIssue] {
+        match self {
+            ApplicationError::ValidationFailed(errors) => errors,
+            _ => &[],
+        }
+    }
 }
 
 impl<E> From<E> for ApplicationError
@@ -20,6 +131,26 @@ where
     }
 }
 
+impl std::fmt::Display for ApplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplicationError::InvalidPayload(e) => write!(f, "{e}"),
+            ApplicationError::ValidationFailed(_) => {
+                write!(f, "The event does not match its schema")
+            }
+            ApplicationError::PayloadTooLarge(e) => write!(f, "{e}"),
+            ApplicationError::UnsupportedMediaType(e) => write!(f, "{e}"),
+            ApplicationError::Unauthorized(e) => write!(f, "{e}"),
+            ApplicationError::Forbidden(e) => write!(f, "{e}"),
+            ApplicationError::NotFound(e) => write!(f, "{e}"),
+            ApplicationError::Conflict(e) => write!(f, "{e}"),
+            ApplicationError::TooManyRequests(e, _) => write!(f, "{e}"),
+            ApplicationError::ServiceUnavailable(e) => write!(f, "{e}"),
+            ApplicationError::Unknown(e) => write!(f, "{e}"),
+        }
+    }
+}
+
 impl IntoResponse for ApplicationError {
     fn into_response(self) -> Response {
         error!("Error: {:?}", self);
@@ -27,13 +158,161 @@ impl IntoResponse for ApplicationError {
         match self {
             ApplicationError::InvalidPayload(e) => {
                 error!("Invalid payload: {}", e);
-                (StatusCode::BAD_REQUEST, e).into_response()
+                Problem::new(StatusCode::BAD_REQUEST, Some(e)).into_response()
             }
-            ApplicationError::Unknown(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
+            ApplicationError::ValidationFailed(errors) => {
+                error!("Invalid payload: {} validation errors", errors.len());
+                let detail = ApplicationError::ValidationFailed(Vec::new()).to_string();
+
+                Problem {
+                    errors,
+                    ..Problem::new(StatusCode::BAD_REQUEST, Some(detail))
+                }
+                .into_response()
+            }
+            ApplicationError::PayloadTooLarge(e) => {
+                Problem::new(StatusCode::PAYLOAD_TOO_LARGE, Some(e)).into_response()
+            }
+            ApplicationError::UnsupportedMediaType(e) => {
+                Problem::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, Some(e)).into_response()
+            }
+            ApplicationError::Unauthorized(e) => {
+                error!("Unauthorized: {}", e);
+                Problem::new(StatusCode::UNAUTHORIZED, Some(e)).into_response()
+            }
+            ApplicationError::Forbidden(e) => {
+                error!("Forbidden: {}", e);
+                Problem::new(StatusCode::FORBIDDEN, Some(e)).into_response()
+            }
+            ApplicationError::NotFound(e) => {
+                Problem::new(StatusCode::NOT_FOUND, Some(e)).into_response()
+            }
+            ApplicationError::Conflict(e) => (
+                [(RETRY_AFTER, "1")],
+                Problem::new(StatusCode::CONFLICT, Some(e)),
             )
                 .into_response(),
+            ApplicationError::TooManyRequests(e, retry_after) => {
+                // `Retry-After` only takes whole seconds, so round up to not invite an early retry
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+                (
+                    [(RETRY_AFTER, seconds.max(1).to_string())],
+                    Problem::new(StatusCode::TOO_MANY_REQUESTS, Some(e)),
+                )
+                    .into_response()
+            }
+            ApplicationError::ServiceUnavailable(e) => {
+                error!("Service unavailable: {}", e);
+                Problem::new(StatusCode::SERVICE_UNAVAILABLE, Some(e)).into_response()
+            }
+            ApplicationError::Unknown(_) => {
+                Problem::new(StatusCode::INTERNAL_SERVER_ERROR, None).into_response()
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use serde_json::{Value, json};
+
+    async fn problem(error: ApplicationError) -> (Response, Value) {
+        let response = error.into_response();
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+
+        (
+            Response::from_parts(parts, ().into()),
+            serde_json::from_slice(&body).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_invalid_payload_is_a_problem_document() {
+        let (response, body) = problem(ApplicationError::InvalidPayload(
+            "Missing Content-Type header".to_string(),
+        ))
+        .await;
+
+        assert_eq!(response.status(), 400);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        assert_eq!(
+            body,
+            json!({
+                "type": "about:blank",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "Missing Content-Type header"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_failed_lists_every_error() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "name": { "type": "string", "maxLength": 3 } },
+            "required": ["appId"]
+        });
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        let errors = validator
+            .iter_errors(&json!({ "name": "signup" }))
+            .map(|e| ValidationIssue::from(&e))
+            .collect();
+
+        let (response, body) = problem(ApplicationError::ValidationFailed(errors)).await;
+
+        assert_eq!(response.status(), 400);
+        let errors = body["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(
+            errors
+                .iter()
+                .any(|e| e["instance_path"] == "/name" && e["keyword"] == "maxLength")
+        );
+        assert!(
+            errors
+                .iter()
+                .any(|e| e["instance_path"] == "" && e["keyword"] == "required")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_too_many_requests_keeps_retry_after() {
+        let (response, body) = problem(ApplicationError::TooManyRequests(
+            "Too many requests".to_string(),
+            Duration::from_millis(1500),
+        ))
+        .await;
+
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+        assert_eq!(body["status"], 429);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_asks_for_a_retry() {
+        let (response, body) = problem(ApplicationError::Conflict(
+            "An event with this id is still being stored".to_string(),
+        ))
+        .await;
+
+        assert_eq!(response.status(), 409);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+        assert_eq!(body["status"], 409);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_error_hides_details() {
+        let (response, body) = problem(ApplicationError::Unknown(anyhow::anyhow!(
+            "connection refused"
+        )))
+        .await;
+
+        assert_eq!(response.status(), 500);
+        assert!(body.get("detail").is_none());
+    }
+}
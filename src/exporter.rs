@@ -7,6 +7,44 @@ pub mod prometheus;
 use anyhow::Result;
 use std::sync::Arc;
 
+/// The names exporters keep their checkpoints under, see `storage::checkpoints`.
+pub const POSTGRESQL: &str = "postgresql";
+pub const PARQUET: &str = "parquet";
+
+/// Exporters read the buffer this many events at a time and move their checkpoint after each
+/// chunk, so a backlog is exported in bounded memory and a failure only repeats one chunk.
+#[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+pub const CHUNK_SIZE: u64 = 10_000;
+
+/// The exporters that run in this process. Events are only pruned from the buffer once each of
+/// them has exported them, so an exporter only counts once it is configured to export anywhere.
+pub fn enabled() -> Vec<&'static str> {
+    let postgresql = cfg!(feature = "export-postgres") && std::env::var("DATABASE_URL").is_ok();
+
+    #[cfg(feature = "export-parquet")]
+    let parquet = crate::storage::google_storage::GoogleStorageClient::configured();
+    #[cfg(not(feature = "export-parquet"))]
+    let parquet = false;
+
+    [(POSTGRESQL, postgresql), (PARQUET, parquet)]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect()
+}
+
 pub trait Exporter {
     async fn publish(&mut self, source: Arc<libsql::Connection>) -> Result<usize>;
 }
+
+#[cfg(all(test, feature = "export-parquet"))]
+mod tests {
+    use super::*;
+    use crate::storage::google_storage::GoogleStorageClient;
+
+    #[test]
+    fn test_parquet_is_not_enabled_without_google_storage() {
+        // The tests run without workload identity or a bucket configured
+        assert!(GoogleStorageClient::new().is_err());
+        assert!(!enabled().contains(&PARQUET));
+    }
+}
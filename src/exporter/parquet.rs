@@ -1,48 +1,112 @@
 mod serializer;
+mod sessions;
 
 use crate::{
-    exporter::Exporter,
-    storage::{EventSerializer, memory::flush_since},
+    exporter::{CHUNK_SIZE, Exporter, PARQUET},
+    storage::{
+        EventSerializer,
+        checkpoints::{self, Checkpoint},
+        memory::{flush_after, flush_sessions_after},
+    },
 };
 
 #[cfg(feature = "export-parquet")]
 use crate::storage::google_storage::GoogleStorageClient;
 
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use serializer::{ParqetSerializer, VERSION};
+use sessions::{SESSIONS_VERSION, SessionSerializer};
 use std::{
     sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 use tracing::info;
 
-pub struct ParquetExporter {
-    #[allow(dead_code)]
-    pub last_export_at: DateTime<Utc>,
-}
+/// Uploads the events and sessions added since its checkpoint as Parquet files, a file per chunk
+/// of events.
+pub struct ParquetExporter;
 
 impl Exporter for ParquetExporter {
     async fn publish(&mut self, source: Arc<libsql::Connection>) -> anyhow::Result<usize> {
         info!("Starting parquet export");
 
-        let event_records = flush_since(source.clone(), self.last_export_at).await?;
-        let (buffer, row_count) = ParqetSerializer.to_bytes(&event_records)?;
+        let started_at = Utc::now();
+        let mut checkpoint = checkpoints::get(&source, PARQUET)
+            .await?
+            .unwrap_or_else(|| Checkpoint::start(PARQUET));
+        let mut client = None;
+        let mut row_count = 0;
+
+        loop {
+            let event_records =
+                flush_after(source.clone(), checkpoint.sequence, CHUNK_SIZE).await?;
+            let Some(last) = event_records.last() else {
+                break;
+            };
+
+            let (buffer, chunk_count) = ParqetSerializer.to_bytes(&event_records)?;
+            let filename = format!("{}/{}", VERSION, timestamp()?);
+            upload(&mut client, &filename, &buffer).await?;
+            row_count += chunk_count;
+
+            let next = Checkpoint {
+                sequence: last.sequence,
+                ..checkpoint.clone()
+            };
+            if !checkpoints::advance(&source, &checkpoint, &next).await? {
+                info!("Parquet checkpoint was moved during the export, keeping it");
+                return Ok(row_count);
+            }
+            checkpoint = next;
+
+            if (event_records.len() as u64) < CHUNK_SIZE {
+                break;
+            }
+        }
 
-        if row_count > 0 {
-            let mut client = GoogleStorageClient::new()?;
-            let now = SystemTime::now();
-            let duration = now.duration_since(UNIX_EPOCH)?;
-            let micros = duration.as_micros();
+        let updates = flush_sessions_after(&source, checkpoint.session_sequence).await?;
+        let (sessions_buffer, session_count) = SessionSerializer.to_bytes(&updates.sessions)?;
 
-            let filename = format!("{}/{}", VERSION, &micros.to_string());
+        if session_count > 0 {
+            let filename = format!("sessions/{}/{}", SESSIONS_VERSION, timestamp()?);
+            upload(&mut client, &filename, &sessions_buffer).await?;
+        }
 
-            client
-                .upload_binary_data(&filename, &buffer, Some("application/vnd.apache.parquet"))
-                .await?;
+        let next = Checkpoint {
+            session_sequence: updates.sequence,
+            exported_at: started_at,
+            ..checkpoint.clone()
+        };
+        if !checkpoints::advance(&source, &checkpoint, &next).await? {
+            info!("Parquet checkpoint was moved during the export, keeping it");
         }
 
-        info!("Parquet export completed successfully, exported {row_count} rows");
+        info!(
+            "Parquet export completed successfully, exported {row_count} rows and {session_count} sessions"
+        );
 
         Ok(row_count)
     }
 }
+
+/// The storage client is only created for the first upload, so an export with nothing to upload
+/// does not need one.
+async fn upload(
+    client: &mut Option<GoogleStorageClient>,
+    filename: &str,
+    buffer: &[u8],
+) -> anyhow::Result<()> {
+    let client = match client {
+        Some(client) => client,
+        None => client.insert(GoogleStorageClient::new()?),
+    };
+
+    client
+        .upload_binary_data(filename, buffer, Some("application/vnd.apache.parquet"))
+        .await
+}
+
+/// Files are named by the time they are uploaded at, in microseconds since the epoch.
+fn timestamp() -> anyhow::Result<u128> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros())
+}
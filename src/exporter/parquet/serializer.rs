@@ -1,19 +1,24 @@
 use crate::storage::EventSerializer;
+use crate::storage::memory::PropertyValue;
 use anyhow::Result;
 use anyhow::anyhow;
 use arrow_array::StructArray;
 use arrow_array::TimestampMillisecondArray;
-use arrow_array::{RecordBatch, StringArray};
+use arrow_array::builder::{
+    BooleanBuilder, Float64Builder, MapBuilder, MapFieldNames, StringBuilder, StructBuilder,
+};
+use arrow_array::{BooleanArray, Int64Array, RecordBatch, StringArray};
 use arrow_schema::Field;
 use arrow_schema::Fields;
 use arrow_schema::{DataType, Schema, SchemaBuilder, TimeUnit};
 use parquet::arrow::ArrowWriter;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::debug;
 use tracing::info;
 
 pub struct ParqetSerializer;
-pub static VERSION: &str = "1.1.0";
+pub static VERSION: &str = "1.12.0";
 
 impl EventSerializer for ParqetSerializer {
     fn to_bytes<'a>(
@@ -44,10 +49,35 @@ fn generate_record_batch<'a>(
     let mut event_action_values = Vec::<String>::new();
     let mut event_path_values = Vec::<Option<String>>::new();
     let mut event_app_id_values = Vec::<String>::new();
+    let mut event_name_values = Vec::<Option<String>>::new();
+    let mut event_props_builder = props_builder();
+    let mut event_utm_source_values = Vec::<Option<String>>::new();
+    let mut event_utm_medium_values = Vec::<Option<String>>::new();
+    let mut event_utm_campaign_values = Vec::<Option<String>>::new();
+    let mut event_utm_term_values = Vec::<Option<String>>::new();
+    let mut event_utm_content_values = Vec::<Option<String>>::new();
 
     let mut recorded_at_values = Vec::<i64>::new();
     let mut recorded_by_values = Vec::<Option<String>>::new();
 
+    let mut context_schema_version_values = Vec::<Option<String>>::new();
+    let mut context_browser_values = Vec::<Option<String>>::new();
+    let mut context_browser_version_values = Vec::<Option<String>>::new();
+    let mut context_os_values = Vec::<Option<String>>::new();
+    let mut context_os_version_values = Vec::<Option<String>>::new();
+    let mut context_device_values = Vec::<Option<String>>::new();
+    let mut context_country_values = Vec::<Option<String>>::new();
+    let mut context_region_values = Vec::<Option<String>>::new();
+    let mut context_city_values = Vec::<Option<String>>::new();
+    let mut context_visitor_id_values = Vec::<Option<String>>::new();
+    let mut context_session_id_values = Vec::<Option<String>>::new();
+    let mut context_referrer_domain_values = Vec::<Option<String>>::new();
+    let mut context_channel_values = Vec::<Option<String>>::new();
+    let mut context_is_bot_values = Vec::<Option<bool>>::new();
+
+    let mut buffer_id_values = Vec::<String>::new();
+    let mut sequence_values = Vec::<i64>::new();
+
     for event_record in event_records {
         id_values.push(event_record.id.clone());
 
@@ -56,9 +86,34 @@ fn generate_record_batch<'a>(
         event_action_values.push(event_record.event.action.clone());
         event_path_values.push(event_record.event.path.clone());
         event_app_id_values.push(event_record.event.app_id.clone());
+        event_name_values.push(event_record.event.name.clone());
+        append_props(&mut event_props_builder, event_record.event.props.as_ref())?;
+        event_utm_source_values.push(event_record.event.campaign.utm_source.clone());
+        event_utm_medium_values.push(event_record.event.campaign.utm_medium.clone());
+        event_utm_campaign_values.push(event_record.event.campaign.utm_campaign.clone());
+        event_utm_term_values.push(event_record.event.campaign.utm_term.clone());
+        event_utm_content_values.push(event_record.event.campaign.utm_content.clone());
 
         recorded_at_values.push(event_record.recorded_at.timestamp_millis());
         recorded_by_values.push(event_record.recorded_by.clone());
+
+        context_schema_version_values.push(event_record.context.schema_version.clone());
+        context_browser_values.push(event_record.context.browser.clone());
+        context_browser_version_values.push(event_record.context.browser_version.clone());
+        context_os_values.push(event_record.context.os.clone());
+        context_os_version_values.push(event_record.context.os_version.clone());
+        context_device_values.push(event_record.context.device.clone());
+        context_country_values.push(event_record.context.country.clone());
+        context_region_values.push(event_record.context.region.clone());
+        context_city_values.push(event_record.context.city.clone());
+        context_visitor_id_values.push(event_record.context.visitor_id.clone());
+        context_session_id_values.push(event_record.context.session_id.clone());
+        context_referrer_domain_values.push(event_record.context.referrer_domain.clone());
+        context_channel_values.push(event_record.context.channel.clone());
+        context_is_bot_values.push(event_record.context.is_bot);
+
+        buffer_id_values.push(event_record.buffer_id.clone());
+        sequence_values.push(event_record.sequence);
     }
 
     let event_values = StructArray::try_new(
@@ -69,6 +124,34 @@ fn generate_record_batch<'a>(
             Arc::new(StringArray::from(event_action_values)),
             Arc::new(StringArray::from(event_path_values)),
             Arc::new(StringArray::from(event_app_id_values)),
+            Arc::new(StringArray::from(event_name_values)),
+            Arc::new(event_props_builder.finish()),
+            Arc::new(StringArray::from(event_utm_source_values)),
+            Arc::new(StringArray::from(event_utm_medium_values)),
+            Arc::new(StringArray::from(event_utm_campaign_values)),
+            Arc::new(StringArray::from(event_utm_term_values)),
+            Arc::new(StringArray::from(event_utm_content_values)),
+        ],
+        None,
+    )?;
+
+    let context_values = StructArray::try_new(
+        context_fields(),
+        vec![
+            Arc::new(StringArray::from(context_schema_version_values)),
+            Arc::new(StringArray::from(context_browser_values)),
+            Arc::new(StringArray::from(context_browser_version_values)),
+            Arc::new(StringArray::from(context_os_values)),
+            Arc::new(StringArray::from(context_os_version_values)),
+            Arc::new(StringArray::from(context_device_values)),
+            Arc::new(StringArray::from(context_country_values)),
+            Arc::new(StringArray::from(context_region_values)),
+            Arc::new(StringArray::from(context_city_values)),
+            Arc::new(StringArray::from(context_visitor_id_values)),
+            Arc::new(StringArray::from(context_session_id_values)),
+            Arc::new(StringArray::from(context_referrer_domain_values)),
+            Arc::new(StringArray::from(context_channel_values)),
+            Arc::new(BooleanArray::from(context_is_bot_values)),
         ],
         None,
     )?;
@@ -85,6 +168,9 @@ fn generate_record_batch<'a>(
                 Arc::new(event_values),
                 Arc::new(TimestampMillisecondArray::from(recorded_at_values)),
                 Arc::new(StringArray::from(recorded_by_values)),
+                Arc::new(context_values),
+                Arc::new(StringArray::from(buffer_id_values)),
+                Arc::new(Int64Array::from(sequence_values)),
             ],
         )
         .map_err(|e| anyhow!("{:?}", e))?,
@@ -99,6 +185,99 @@ fn event_fields() -> Fields {
         Field::new("action", DataType::Utf8, false),
         Field::new("path", DataType::Utf8, true),
         Field::new("app_id", DataType::Utf8, false),
+        Field::new("name", DataType::Utf8, true),
+        Field::new_map(
+            "props",
+            "entries",
+            Field::new("keys", DataType::Utf8, false),
+            Field::new_struct("values", prop_value_fields(), true),
+            false,
+            true,
+        ),
+        Field::new("utm_source", DataType::Utf8, true),
+        Field::new("utm_medium", DataType::Utf8, true),
+        Field::new("utm_campaign", DataType::Utf8, true),
+        Field::new("utm_term", DataType::Utf8, true),
+        Field::new("utm_content", DataType::Utf8, true),
+    ])
+}
+
+/// Custom event props keep their JSON type by storing each value in exactly one of the
+/// typed children of this struct.
+fn prop_value_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("string_value", DataType::Utf8, true),
+        Field::new("number_value", DataType::Float64, true),
+        Field::new("bool_value", DataType::Boolean, true),
+    ])
+}
+
+fn props_builder() -> MapBuilder<StringBuilder, StructBuilder> {
+    MapBuilder::new(
+        Some(MapFieldNames {
+            entry: "entries".to_string(),
+            key: "keys".to_string(),
+            value: "values".to_string(),
+        }),
+        StringBuilder::new(),
+        StructBuilder::from_fields(prop_value_fields(), 0),
+    )
+}
+
+fn append_props(
+    builder: &mut MapBuilder<StringBuilder, StructBuilder>,
+    props: Option<&BTreeMap<String, PropertyValue>>,
+) -> Result<()> {
+    let Some(props) = props else {
+        builder.append(false)?;
+        return Ok(());
+    };
+
+    for (key, value) in props {
+        builder.keys().append_value(key);
+
+        let values = builder.values();
+        let (string_value, number_value, bool_value) = match value {
+            PropertyValue::String(v) => (Some(v.as_str()), None, None),
+            PropertyValue::Number(v) => (None, Some(*v), None),
+            PropertyValue::Bool(v) => (None, None, Some(*v)),
+        };
+
+        values
+            .field_builder::<StringBuilder>(0)
+            .ok_or_else(|| anyhow!("missing string_value builder"))?
+            .append_option(string_value);
+        values
+            .field_builder::<Float64Builder>(1)
+            .ok_or_else(|| anyhow!("missing number_value builder"))?
+            .append_option(number_value);
+        values
+            .field_builder::<BooleanBuilder>(2)
+            .ok_or_else(|| anyhow!("missing bool_value builder"))?
+            .append_option(bool_value);
+        values.append(true);
+    }
+
+    builder.append(true)?;
+    Ok(())
+}
+
+fn context_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("schema_version", DataType::Utf8, true),
+        Field::new("browser", DataType::Utf8, true),
+        Field::new("browser_version", DataType::Utf8, true),
+        Field::new("os", DataType::Utf8, true),
+        Field::new("os_version", DataType::Utf8, true),
+        Field::new("device", DataType::Utf8, true),
+        Field::new("country", DataType::Utf8, true),
+        Field::new("region", DataType::Utf8, true),
+        Field::new("city", DataType::Utf8, true),
+        Field::new("visitor_id", DataType::Utf8, true),
+        Field::new("session_id", DataType::Utf8, true),
+        Field::new("referrer_domain", DataType::Utf8, true),
+        Field::new("channel", DataType::Utf8, true),
+        Field::new("is_bot", DataType::Boolean, true),
     ])
 }
 
@@ -117,6 +296,10 @@ fn generate_schema() -> Arc<Schema> {
         false,
     ));
     builder.push(Field::new("recorded_by", DataType::Utf8, true));
+    builder.push(Field::new_struct("context", context_fields(), false));
+    // Numbers events per buffer in insertion order, so consumers can detect gaps
+    builder.push(Field::new("buffer_id", DataType::Utf8, false));
+    builder.push(Field::new("sequence", DataType::Int64, false));
 
     Arc::new(builder.finish())
 }
@@ -124,7 +307,9 @@ fn generate_schema() -> Arc<Schema> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::storage::memory::{Event, EventRecord};
+    use crate::enrichment::campaign::Campaign;
+    use crate::storage::memory::{Event, EventContext, EventRecord};
+    use arrow_array::{Array, MapArray};
     use chrono::{DateTime, Utc};
 
     fn create_test_event_record(id: &str, with_optional_fields: bool) -> EventRecord {
@@ -136,6 +321,8 @@ mod tests {
         };
 
         EventRecord {
+            buffer_id: "test-buffer".to_string(),
+            sequence: 1,
             id: id.to_string(),
             recorded_at,
             recorded_by: if with_optional_fields {
@@ -153,10 +340,38 @@ mod tests {
                     None
                 },
                 app_id: "my-app".to_string(),
+                name: None,
+                props: None,
+                campaign: Campaign {
+                    utm_source: with_optional_fields.then(|| "newsletter".to_string()),
+                    ..Campaign::default()
+                },
+            },
+            context: EventContext {
+                schema_version: Some("default@1.0.0".to_string()),
+                browser: with_optional_fields.then(|| "Firefox".to_string()),
+                device: Some("desktop".to_string()),
+                ..EventContext::default()
             },
         }
     }
 
+    fn create_custom_event_record(id: &str) -> EventRecord {
+        let mut record = create_test_event_record(id, true);
+        record.event.entity = "custom".to_string();
+        record.event.action = "track".to_string();
+        record.event.name = Some("purchase".to_string());
+        record.event.props = Some(BTreeMap::from([
+            (
+                "currency".to_string(),
+                PropertyValue::String("EUR".to_string()),
+            ),
+            ("amount".to_string(), PropertyValue::Number(19.99)),
+            ("first_order".to_string(), PropertyValue::Bool(true)),
+        ]));
+        record
+    }
+
     #[test]
     fn test_to_bytes_empty_records() {
         let serializer = ParqetSerializer;
@@ -222,7 +437,7 @@ mod tests {
 
         assert_eq!(count, 0);
         assert_eq!(record_batch.num_rows(), 0);
-        assert_eq!(record_batch.num_columns(), 4); // id, recorded_at, recorded_by
+        assert_eq!(record_batch.num_columns(), 7); // id, event, recorded_at, recorded_by, context, buffer_id, sequence
     }
 
     #[test]
@@ -233,14 +448,22 @@ mod tests {
 
         assert_eq!(count, 1);
         assert_eq!(record_batch.num_rows(), 1);
-        assert_eq!(record_batch.num_columns(), 4);
+        assert_eq!(record_batch.num_columns(), 7);
 
         // Verify column names
         let schema = record_batch.schema();
         let field_names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
         assert_eq!(
             field_names,
-            vec!["id", "event", "recorded_at", "recorded_by"]
+            vec![
+                "id",
+                "event",
+                "recorded_at",
+                "recorded_by",
+                "context",
+                "buffer_id",
+                "sequence"
+            ]
         );
     }
 
@@ -255,7 +478,52 @@ mod tests {
 
         assert_eq!(count, 3);
         assert_eq!(record_batch.num_rows(), 3);
-        assert_eq!(record_batch.num_columns(), 4);
+        assert_eq!(record_batch.num_columns(), 7);
+    }
+
+    #[test]
+    fn test_generate_record_batch_sequence() {
+        let mut records = vec![
+            create_test_event_record("test-id-1", true),
+            create_test_event_record("test-id-2", false),
+        ];
+        records[1].sequence = 3;
+        let (record_batch, _) = generate_record_batch(records.iter()).unwrap();
+
+        let sequence = record_batch
+            .column_by_name("sequence")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(sequence.values(), &[1, 3]);
+    }
+
+    #[test]
+    fn test_generate_record_batch_custom_event_props() {
+        let records = [
+            create_custom_event_record("test-id-1"),
+            create_test_event_record("test-id-2", true),
+        ];
+        let (record_batch, count) = generate_record_batch(records.iter()).unwrap();
+
+        assert_eq!(count, 2);
+
+        let event = record_batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<StructArray>()
+            .unwrap();
+        let props = event
+            .column_by_name("props")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<MapArray>()
+            .unwrap();
+
+        assert!(props.is_valid(0));
+        assert_eq!(props.value_length(0), 3);
+        assert!(props.is_null(1));
     }
 
     #[test]
@@ -0,0 +1,141 @@
+use crate::sessions::Session;
+use anyhow::Result;
+use arrow_array::{Int64Array, RecordBatch, StringArray, TimestampMillisecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Version of the sessions dataset, used as its upload prefix next to the events dataset.
+pub static SESSIONS_VERSION: &str = "1.0.0";
+
+pub struct SessionSerializer;
+
+impl SessionSerializer {
+    pub fn to_bytes(&self, sessions: &[Session]) -> Result<(Vec<u8>, usize)> {
+        let record_batch = generate_record_batch(sessions)?;
+
+        let mut buffer = Vec::<u8>::new();
+        let mut writer = ArrowWriter::try_new(&mut buffer, record_batch.schema(), None)?;
+
+        writer.write(&record_batch)?;
+        writer.close()?;
+
+        debug!(
+            "Sessions parquet data written, buffer size: {} bytes",
+            buffer.len()
+        );
+
+        Ok((buffer, sessions.len()))
+    }
+}
+
+fn generate_record_batch(sessions: &[Session]) -> Result<RecordBatch> {
+    let timestamp = |at: &chrono::DateTime<chrono::Utc>| at.timestamp_millis();
+
+    Ok(RecordBatch::try_new(
+        generate_schema(),
+        vec![
+            Arc::new(StringArray::from_iter_values(
+                sessions.iter().map(|s| s.id.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                sessions.iter().map(|s| s.recorded_by.as_str()),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                sessions.iter().map(|s| s.visitor_id.as_str()),
+            )),
+            Arc::new(TimestampMillisecondArray::from_iter_values(
+                sessions.iter().map(|s| timestamp(&s.started_at)),
+            )),
+            Arc::new(TimestampMillisecondArray::from_iter_values(
+                sessions.iter().map(|s| timestamp(&s.last_seen_at)),
+            )),
+            Arc::new(StringArray::from_iter(
+                sessions.iter().map(|s| s.entry_path.as_deref()),
+            )),
+            Arc::new(StringArray::from_iter(
+                sessions.iter().map(|s| s.exit_path.as_deref()),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                sessions.iter().map(|s| s.page_count),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                sessions.iter().map(|s| s.duration_seconds()),
+            )),
+        ],
+    )?)
+}
+
+fn generate_schema() -> Arc<Schema> {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("recorded_by", DataType::Utf8, false),
+        Field::new("visitor_id", DataType::Utf8, false),
+        Field::new(
+            "started_at",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            false,
+        ),
+        Field::new(
+            "last_seen_at",
+            DataType::Timestamp(TimeUnit::Millisecond, None),
+            false,
+        ),
+        Field::new("entry_path", DataType::Utf8, true),
+        Field::new("exit_path", DataType::Utf8, true),
+        Field::new("page_count", DataType::Int64, false),
+        Field::new("duration_seconds", DataType::Int64, false),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeDelta, TimeZone, Utc};
+
+    fn create_test_session(id: &str, page_count: i64) -> Session {
+        let started_at = Utc.with_ymd_and_hms(2030, 1, 1, 12, 0, 0).unwrap();
+
+        Session {
+            id: id.to_string(),
+            recorded_by: "my-app".to_string(),
+            visitor_id: "visitor".to_string(),
+            started_at,
+            last_seen_at: started_at + TimeDelta::seconds(90),
+            entry_path: (page_count > 0).then(|| "/".to_string()),
+            exit_path: (page_count > 0).then(|| "/about".to_string()),
+            page_count,
+        }
+    }
+
+    #[test]
+    fn test_generate_record_batch() {
+        let sessions = [
+            create_test_session("first", 2),
+            create_test_session("second", 0),
+        ];
+        let record_batch = generate_record_batch(&sessions).unwrap();
+
+        assert_eq!(record_batch.num_rows(), 2);
+        assert_eq!(record_batch.num_columns(), 9);
+
+        let duration = record_batch
+            .column_by_name("duration_seconds")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(duration.value(0), 90);
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let sessions = [create_test_session("first", 1)];
+        let (bytes, count) = SessionSerializer.to_bytes(&sessions).unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(&bytes[0..4], b"PAR1");
+        assert_eq!(&bytes[bytes.len() - 4..], b"PAR1");
+    }
+}
@@ -1,10 +1,140 @@
-use super::Exporter;
-use anyhow::Result;
-use chrono::{DateTime, Utc};
+use super::{CHUNK_SIZE, Exporter, POSTGRESQL};
+use crate::{
+    enrichment::campaign::Campaign,
+    sessions::Session,
+    storage::{
+        checkpoints::{self, Checkpoint},
+        memory::{EventContext, flush_sessions_after},
+    },
+};
+use anyhow::{Context, Result};
+use chrono::Utc;
 use libsql::params;
-use rust_database_common::{Client, DatabasePool};
+use rust_database_common::{Client, DatabasePool, ToSql};
 use std::sync::Arc;
-use tracing::{debug, error, info};
+use tracing::{debug, info};
+
+/// Columns written to the PostgreSQL `events` table, in the order of [`BufferedEvent::params`].
+const COLUMNS: [&str; 25] = [
+    "id",
+    "recorded_at",
+    "recorded_by",
+    "event",
+    "utm_source",
+    "utm_medium",
+    "utm_campaign",
+    "utm_term",
+    "utm_content",
+    "schema_version",
+    "browser",
+    "browser_version",
+    "os",
+    "os_version",
+    "device",
+    "country",
+    "region",
+    "city",
+    "visitor_id",
+    "session_id",
+    "referrer_domain",
+    "channel",
+    "is_bot",
+    "buffer_id",
+    "sequence",
+];
+
+/// An event read from the buffer, with its campaign and context spread over individual columns.
+#[derive(Debug)]
+struct BufferedEvent {
+    /// The buffer the event was numbered in.
+    buffer_id: String,
+    /// The event's position in the buffer.
+    sequence: i64,
+    id: String,
+    recorded_at: String,
+    recorded_by: String,
+    event: String,
+    campaign: Campaign,
+    context: EventContext,
+}
+
+impl BufferedEvent {
+    fn params(&self) -> [&(dyn ToSql + Sync); COLUMNS.len()] {
+        [
+            &self.id,
+            &self.recorded_at,
+            &self.recorded_by,
+            &self.event,
+            &self.campaign.utm_source,
+            &self.campaign.utm_medium,
+            &self.campaign.utm_campaign,
+            &self.campaign.utm_term,
+            &self.campaign.utm_content,
+            &self.context.schema_version,
+            &self.context.browser,
+            &self.context.browser_version,
+            &self.context.os,
+            &self.context.os_version,
+            &self.context.device,
+            &self.context.country,
+            &self.context.region,
+            &self.context.city,
+            &self.context.visitor_id,
+            &self.context.session_id,
+            &self.context.referrer_domain,
+            &self.context.channel,
+            &self.context.is_bot,
+            &self.buffer_id,
+            &self.sequence,
+        ]
+    }
+}
+
+/// Columns written to the PostgreSQL `sessions` table, in the order of [`SessionRow::params`].
+const SESSION_COLUMNS: [&str; 9] = [
+    "id",
+    "recorded_by",
+    "visitor_id",
+    "started_at",
+    "last_seen_at",
+    "entry_path",
+    "exit_path",
+    "page_count",
+    "duration_seconds",
+];
+
+/// A session with its timestamps formatted the way the `events` table stores them.
+struct SessionRow<'a> {
+    session: &'a Session,
+    started_at: String,
+    last_seen_at: String,
+    duration_seconds: i64,
+}
+
+impl<'a> SessionRow<'a> {
+    fn new(session: &'a Session) -> Self {
+        Self {
+            session,
+            started_at: session.started_at.to_rfc3339(),
+            last_seen_at: session.last_seen_at.to_rfc3339(),
+            duration_seconds: session.duration_seconds(),
+        }
+    }
+
+    fn params(&self) -> [&(dyn ToSql + Sync); SESSION_COLUMNS.len()] {
+        [
+            &self.session.id,
+            &self.session.recorded_by,
+            &self.session.visitor_id,
+            &self.started_at,
+            &self.last_seen_at,
+            &self.session.entry_path,
+            &self.session.exit_path,
+            &self.session.page_count,
+            &self.duration_seconds,
+        ]
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PostgresqlExporter {
@@ -33,107 +163,122 @@ impl PostgresqlExporter {
         }
     }
 
-    async fn fetch_latest_recorded_at(&self, client: &Client) -> Option<DateTime<Utc>> {
-        let latest_recorded_at: Option<String> = match client
-            .query_opt("SELECT MAX(recorded_at) FROM events", &[])
-            .await
-        {
-            Ok(opt_row) => opt_row.and_then(|row| row.get::<_, Option<String>>(0)),
-            Err(e) => {
-                error!("Failed to query latest recorded_at from postgres: {}", e);
-                None
-            }
-        };
-        latest_recorded_at
-            .as_ref()
-            .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
-            .map(|dt| dt.with_timezone(&Utc))
-    }
-
+    /// Reads up to `limit` events inserted after the one numbered `sequence`, in insertion
+    /// order. A failed read fails the export, rather than looking like there is nothing new.
     async fn fetch_new_events(
         &self,
         memory_connection: &libsql::Connection,
-        latest_recorded_at_dt: Option<&DateTime<Utc>>,
-    ) -> Vec<(String, String, String, String)> {
-        let (query, params) = if let Some(latest_dt) = latest_recorded_at_dt {
-            (
-                "SELECT id, recorded_at, recorded_by, event FROM events where recorded_at > ?"
-                    .to_string(),
-                params![latest_dt.to_rfc3339()],
-            )
-        } else {
-            (
-                "SELECT id, recorded_at, recorded_by, event FROM events".to_string(),
-                params!([]),
-            )
-        };
+        sequence: i64,
+        limit: u64,
+    ) -> Result<Vec<BufferedEvent>> {
+        let query = "SELECT sequence, events.id, recorded_at, recorded_by, event, context, \
+                     buffer.buffer_id FROM events, buffer WHERE sequence > ? \
+                     ORDER BY sequence LIMIT ?";
 
-        let mut stmt = match memory_connection.prepare(&query).await {
-            Ok(stmt) => stmt,
-            Err(e) => {
-                error!("Failed to prepare statement: {}", e);
-                return Vec::new();
-            }
-        };
-        let mut rows: libsql::Rows = match stmt.query(params).await {
-            Ok(rows) => rows,
-            Err(e) => {
-                error!("Failed to query events from memory db: {}", e);
-                return Vec::new();
-            }
-        };
+        let mut stmt = memory_connection.prepare(query).await?;
+        let mut rows = stmt.query(params![sequence, limit]).await?;
         let mut events = Vec::new();
-        loop {
-            match rows.next().await {
-                Ok(Some(row)) => {
-                    let id: String = row.get(0).unwrap_or_default();
-                    let recorded_at: String = row.get(1).unwrap_or_default();
-                    let recorded_by: String = row.get(2).unwrap_or_default();
-                    let event: String = row.get(3).unwrap_or_default();
-
-                    events.push((id, recorded_at, recorded_by, event));
-                }
-                Ok(None) => break,
-                Err(e) => {
-                    error!("Failed to read row: {}", e);
-                    break;
-                }
-            }
+        while let Some(row) = rows.next().await? {
+            let sequence: i64 = row.get(0)?;
+            let id: String = row.get(1)?;
+            let recorded_at: String = row.get(2)?;
+            let recorded_by: String = row.get(3)?;
+            let event: String = row.get(4)?;
+            let context: String = row.get(5)?;
+            let buffer_id: String = row.get(6)?;
+            let context = serde_json::from_str(&context)
+                .with_context(|| format!("could not parse the context of event {id}"))?;
+
+            // the campaign is part of the stored payload, other fields are ignored
+            let campaign = serde_json::from_str(&event).unwrap_or_default();
+
+            events.push(BufferedEvent {
+                buffer_id,
+                sequence,
+                id,
+                recorded_at,
+                recorded_by,
+                event,
+                campaign,
+                context,
+            });
         }
-        events
+
+        Ok(events)
     }
 
-    async fn batch_insert_events(
-        &self,
-        client: &Client,
-        events: &[(String, String, String, String)],
-    ) {
+    async fn batch_insert_events(&self, client: &Client, events: &[BufferedEvent]) -> Result<()> {
         let batch_size = 100;
         for chunk in events.chunks(batch_size) {
             let mut values = Vec::new();
-            let mut params: Vec<&(dyn rust_database_common::ToSql + Sync)> = Vec::new();
-            for (i, (id, recorded_at, recorded_by, event)) in chunk.iter().enumerate() {
-                let base = i * 4;
-                values.push(format!(
-                    "(${}, ${}, ${}, ${})",
-                    base + 1,
-                    base + 2,
-                    base + 3,
-                    base + 4
-                ));
-                params.push(id);
-                params.push(recorded_at);
-                params.push(recorded_by);
-                params.push(event);
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+            for (i, event) in chunk.iter().enumerate() {
+                let base = i * COLUMNS.len();
+                let placeholders: Vec<String> = (1..=COLUMNS.len())
+                    .map(|offset| format!("${}", base + offset))
+                    .collect();
+                values.push(format!("({})", placeholders.join(", ")));
+                params.extend(event.params());
             }
             let query = format!(
-                "INSERT INTO events (id, recorded_at, recorded_by, event) VALUES {} ON CONFLICT (id) DO NOTHING",
+                "INSERT INTO events ({}) VALUES {} ON CONFLICT (id) DO NOTHING",
+                COLUMNS.join(", "),
                 values.join(", ")
             );
-            if let Err(e) = client.execute(query.as_str(), &params).await {
-                error!("Failed to batch insert events into postgres: {}", e);
+            client.execute(query.as_str(), &params).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sessions keep changing while they are active, so rows are upserted and only replaced
+    /// by a more recent state.
+    async fn batch_upsert_sessions(&self, client: &Client, sessions: &[Session]) -> Result<()> {
+        let batch_size = 100;
+        for chunk in sessions.chunks(batch_size) {
+            let rows: Vec<SessionRow> = chunk.iter().map(SessionRow::new).collect();
+            let mut values = Vec::new();
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+            for (i, row) in rows.iter().enumerate() {
+                let base = i * SESSION_COLUMNS.len();
+                let placeholders: Vec<String> = (1..=SESSION_COLUMNS.len())
+                    .map(|offset| format!("${}", base + offset))
+                    .collect();
+                values.push(format!("({})", placeholders.join(", ")));
+                params.extend(row.params());
             }
+            let query = format!(
+                "INSERT INTO sessions ({}) VALUES {} ON CONFLICT (id) DO UPDATE SET \
+                 last_seen_at = EXCLUDED.last_seen_at, entry_path = EXCLUDED.entry_path, \
+                 exit_path = EXCLUDED.exit_path, page_count = EXCLUDED.page_count, \
+                 duration_seconds = EXCLUDED.duration_seconds \
+                 WHERE EXCLUDED.last_seen_at::timestamptz >= sessions.last_seen_at::timestamptz",
+                SESSION_COLUMNS.join(", "),
+                values.join(", ")
+            );
+            client.execute(query.as_str(), &params).await?;
         }
+
+        Ok(())
+    }
+
+    /// Returns the session sequence the export got to.
+    async fn publish_sessions(
+        &self,
+        client: &Client,
+        memory_connection: &libsql::Connection,
+        sequence: i64,
+    ) -> Result<i64> {
+        let updates = flush_sessions_after(memory_connection, sequence).await?;
+
+        if updates.sessions.is_empty() {
+            return Ok(updates.sequence);
+        }
+
+        self.batch_upsert_sessions(client, &updates.sessions).await?;
+        info!("Flushed {} sessions to PostgreSQL", updates.sessions.len());
+
+        Ok(updates.sequence)
     }
 }
 
@@ -151,20 +296,54 @@ impl Exporter for PostgresqlExporter {
             .get_client()
             .await?;
 
-        let latest_recorded_at_dt = self.fetch_latest_recorded_at(&client).await;
-        debug!("Latest recorded_at: {:?}", latest_recorded_at_dt);
+        let started_at = Utc::now();
+        let mut checkpoint = checkpoints::get(&memory_connection, POSTGRESQL)
+            .await?
+            .unwrap_or_else(|| Checkpoint::start(POSTGRESQL));
+        debug!("Exporting from checkpoint {:?}", checkpoint);
 
-        let events = self
-            .fetch_new_events(&memory_connection, latest_recorded_at_dt.as_ref())
-            .await;
+        let session_sequence = self
+            .publish_sessions(&client, &memory_connection, checkpoint.session_sequence)
+            .await?;
 
-        if events.is_empty() {
-            return Ok(0);
+        let mut exported = 0;
+        loop {
+            let events = self
+                .fetch_new_events(&memory_connection, checkpoint.sequence, CHUNK_SIZE)
+                .await?;
+            let Some(last) = events.last() else {
+                break;
+            };
+
+            self.batch_insert_events(&client, &events).await?;
+            info!("Flushed {} events to PostgreSQL", events.len());
+            exported += events.len();
+
+            let next = Checkpoint {
+                sequence: last.sequence,
+                ..checkpoint.clone()
+            };
+            if !checkpoints::advance(&memory_connection, &checkpoint, &next).await? {
+                info!("PostgreSQL checkpoint was moved during the export, keeping it");
+                return Ok(exported);
+            }
+            checkpoint = next;
+
+            if (events.len() as u64) < CHUNK_SIZE {
+                break;
+            }
         }
 
-        self.batch_insert_events(&client, &events).await;
-        info!("Flushed {} events to PostgreSQL", events.len());
-        Ok(events.len())
+        let next = Checkpoint {
+            session_sequence,
+            exported_at: started_at,
+            ..checkpoint.clone()
+        };
+        if !checkpoints::advance(&memory_connection, &checkpoint, &next).await? {
+            info!("PostgreSQL checkpoint was moved during the export, keeping it");
+        }
+
+        Ok(exported)
     }
 }
 
@@ -247,7 +426,7 @@ mod tests {
         // Check events in Postgres
         let rows = client
             .query(
-                "SELECT id, recorded_at, event FROM events WHERE recorded_by = $1 ORDER BY event",
+                "SELECT id, recorded_at, event, sequence, buffer_id FROM events WHERE recorded_by = $1 ORDER BY event",
                 &[&recorded_by],
             )
             .await
@@ -255,6 +434,11 @@ mod tests {
         assert_eq!(rows.len(), 2);
         assert_eq!(rows[0].get::<_, String>(2), "event1");
         assert_eq!(rows[1].get::<_, String>(2), "event2");
+        assert_eq!(rows[0].get::<_, Option<i64>>(3), Some(1));
+        assert_eq!(rows[1].get::<_, Option<i64>>(3), Some(2));
+        let buffer_id = rows[0].get::<_, Option<String>>(4);
+        assert!(buffer_id.is_some());
+        assert_eq!(rows[1].get::<_, Option<String>>(4), buffer_id);
     }
 
     #[tokio::test]
@@ -264,4 +448,29 @@ mod tests {
         let count = exporter.publish(memory_conn.clone()).await.unwrap();
         assert_eq!(count, 0);
     }
+
+    #[tokio::test]
+    async fn test_fetch_new_events_fails_on_unreadable_events() {
+        let memory_conn = setup_memory_db().await;
+        let exporter = PostgresqlExporter {
+            database_pool: None,
+            enabled: false,
+        };
+        memory_conn
+            .execute(
+                "INSERT INTO events (id, recorded_at, recorded_by, event, context) \
+                 VALUES ('broken', '2030-01-01T00:00:00Z', 'test-app', '{}', 'not json')",
+                (),
+            )
+            .await
+            .unwrap();
+
+        // A failed read must not look like there is nothing to export
+        assert!(
+            exporter
+                .fetch_new_events(&memory_conn, 0, CHUNK_SIZE)
+                .await
+                .is_err()
+        );
+    }
 }
@@ -1,48 +1,186 @@
 use super::Exporter;
+use crate::storage::memory::PendingEvent;
 use anyhow::Result;
-use libsql::params;
 use prometheus_client::{
     encoding::{EncodeLabelSet, text::encode},
-    metrics::{counter::Counter, family::Family},
-    registry::Registry,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::{Registry, Unit},
 };
 use serde::Deserialize;
-use std::sync::Arc;
+use std::{borrow::Cow, sync::Arc};
 
+/// Labels of stored events. The `instance_id` is added when the metrics are scraped. Custom
+/// event names are left out, since clients choose them freely and every new one would add a
+/// time series.
 #[derive(Debug, Deserialize, EncodeLabelSet, Clone, Hash, Eq, PartialEq)]
-struct Event {
+pub struct Event {
     entity: String,
     action: String,
     #[serde(rename = "appId")]
     app_id: String,
-    instance_id: Option<String>,
     path: Option<String>,
 }
 
+/// Labels of requests rejected by the rate limiter. `limit` is `ip` or `app`, and the
+/// `app_id` is only known for the latter.
+#[derive(Debug, EncodeLabelSet, Clone, Hash, Eq, PartialEq)]
+pub struct Throttled {
+    pub limit: String,
+    pub app_id: Option<String>,
+}
+
+/// Labels of events recognised as bots, by `reason`: `crawler`, `headless` or `datacenter`.
+#[derive(Debug, EncodeLabelSet, Clone, Hash, Eq, PartialEq)]
+pub struct Filtered {
+    pub reason: String,
+    pub app_id: String,
+}
+
+/// Labels of payloads kept as dead letters, by the `reason` they were rejected for.
+#[derive(Debug, EncodeLabelSet, Clone, Hash, Eq, PartialEq)]
+pub struct DeadLettered {
+    pub reason: String,
+}
+
+/// Labels of request bodies rejected for their size, by the `route` whose limit they exceeded:
+/// `event` or `batch`.
+#[derive(Debug, EncodeLabelSet, Clone, Hash, Eq, PartialEq)]
+pub struct BodyRejected {
+    pub route: String,
+}
+
+/// Labels of events that did not fit into the buffer, by the `policy` that applied: `reject`
+/// or `drop_oldest`.
+#[derive(Debug, EncodeLabelSet, Clone, Hash, Eq, PartialEq)]
+pub struct Overflowed {
+    pub policy: String,
+}
+
+/// Metrics recorded while serving requests and maintaining the buffer. Events are counted as
+/// they are stored, so a scrape does not depend on how long the buffer keeps them.
+#[derive(Debug)]
+pub struct Metrics {
+    pub events: Family<Event, Counter>,
+    pub throttled_requests: Family<Throttled, Counter>,
+    pub filtered_events: Family<Filtered, Counter>,
+    pub dead_letters: Family<DeadLettered, Counter>,
+    pub rejected_body_size: Family<BodyRejected, Histogram, fn() -> Histogram>,
+    pub buffered_events: Gauge,
+    pub buffered_bytes: Gauge,
+    pub pruned_events: Counter,
+    pub overflowed_events: Family<Overflowed, Counter>,
+    pub rejected_writes: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            events: Family::default(),
+            throttled_requests: Family::default(),
+            filtered_events: Family::default(),
+            dead_letters: Family::default(),
+            // 1 KiB to 16 MiB
+            rejected_body_size: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(1024.0, 2.0, 15))
+            }),
+            buffered_events: Gauge::default(),
+            buffered_bytes: Gauge::default(),
+            pruned_events: Counter::default(),
+            overflowed_events: Family::default(),
+            rejected_writes: Counter::default(),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts stored events. Events tagged as bots would otherwise inflate the counts, e.g. of
+    /// crawlers following anchors.
+    pub fn count_stored<'a>(&self, events: impl IntoIterator<Item = &'a PendingEvent>) {
+        for event in events {
+            if event.context.is_bot == Some(true) {
+                continue;
+            }
+
+            if let Ok(labels) = serde_json::from_str::<Event>(&event.event) {
+                self.events.get_or_create(&labels).inc();
+            }
+        }
+    }
+
+    pub(crate) fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "throttled_requests",
+            "Requests rejected by the rate limiter",
+            self.throttled_requests.clone(),
+        );
+        registry.register(
+            "filtered_events",
+            "Events recognised as bots",
+            self.filtered_events.clone(),
+        );
+        registry.register(
+            "dead_letters",
+            "Rejected payloads kept as dead letters",
+            self.dead_letters.clone(),
+        );
+        registry.register_with_unit(
+            "rejected_body_size",
+            "Sizes of request bodies rejected for exceeding their route's limit",
+            Unit::Bytes,
+            self.rejected_body_size.clone(),
+        );
+        registry.register(
+            "buffered_events",
+            "Events in the buffer",
+            self.buffered_events.clone(),
+        );
+        registry.register_with_unit(
+            "buffered",
+            "Size of the payloads and context of the events in the buffer",
+            Unit::Bytes,
+            self.buffered_bytes.clone(),
+        );
+        registry.register(
+            "pruned_events",
+            "Events removed from the buffer after every exporter exported them",
+            self.pruned_events.clone(),
+        );
+        registry.register(
+            "overflowed_events",
+            "Events rejected or dropped because the buffer was full",
+            self.overflowed_events.clone(),
+        );
+        registry.register(
+            "rejected_writes",
+            "Requests rejected because the write queue was full",
+            self.rejected_writes.clone(),
+        );
+    }
+}
+
 pub struct PrometheusExporter<'a> {
     pub buffer: &'a mut String,
     pub instance_id: String,
+    pub metrics: Arc<Metrics>,
 }
 
 impl Exporter for PrometheusExporter<'_> {
-    async fn publish(&mut self, connection: Arc<libsql::Connection>) -> Result<usize> {
+    async fn publish(&mut self, _connection: Arc<libsql::Connection>) -> Result<usize> {
         let mut registry = Registry::default();
-        let counter = Family::<Event, Counter>::default();
-
-        registry.register("events", "analytics", counter.clone());
 
-        let mut results = connection
-            .clone()
-            .query("select event from events", params![])
-            .await?;
+        registry
+            .sub_registry_with_label((
+                Cow::Borrowed("instance_id"),
+                Cow::Owned(self.instance_id.clone()),
+            ))
+            .register("events", "analytics", self.metrics.events.clone());
+        self.metrics.register(&mut registry);
 
-        while let Some(row) = results.next().await? {
-            let event: String = row.get(0)?;
-            if let Ok(mut event) = serde_json::from_str::<Event>(&event) {
-                event.instance_id = Some(self.instance_id.clone());
-                counter.get_or_create(&event).inc();
-            }
-        }
         encode(self.buffer, &registry)?;
         Ok(1)
     }
@@ -51,41 +189,57 @@ impl Exporter for PrometheusExporter<'_> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{storage::memory::initialize, utilities::generate_uuid_v4};
+    use crate::{
+        storage::memory::{EventContext, initialize},
+        utilities::generate_uuid_v4,
+    };
     use chrono::Utc;
-    use libsql::{Connection, params};
-    use std::sync::Arc;
 
-    async fn setup_db_with_events(events: Vec<&str>) -> Arc<Connection> {
-        let connection = initialize().await.unwrap();
-
-        for event in events {
-            connection
-                .execute(
-                    "INSERT INTO events (id, event, recorded_at, recorded_by) VALUES (?1, ?2, ?3, ?4)",
-                    params![generate_uuid_v4(), event, Utc::now().to_rfc3339(), generate_uuid_v4()],
-                )
-                .await
-                .unwrap();
+    fn pending_event(event: &str, context: EventContext) -> PendingEvent {
+        PendingEvent {
+            id: generate_uuid_v4(),
+            recorded_at: Utc::now(),
+            recorded_by: "test-app".to_string(),
+            event: event.to_string(),
+            context,
+            visit: None,
+            session: None,
         }
-        Arc::new(connection)
+    }
+
+    fn metrics_with_events(events: Vec<&str>) -> Arc<Metrics> {
+        let metrics = Metrics::default();
+        let events: Vec<PendingEvent> = events
+            .into_iter()
+            .map(|event| pending_event(event, EventContext::default()))
+            .collect();
+        metrics.count_stored(&events);
+
+        Arc::new(metrics)
+    }
+
+    async fn scrape(metrics: Arc<Metrics>, instance_id: &str) -> String {
+        // The counts are kept in the metrics, so the buffer can stay empty
+        let connection = Arc::new(initialize().await.unwrap());
+        let mut buffer = String::new();
+        let mut exporter = PrometheusExporter {
+            buffer: &mut buffer,
+            instance_id: instance_id.to_string(),
+            metrics,
+        };
+        exporter.publish(connection).await.unwrap();
+
+        buffer
     }
 
     #[tokio::test]
     async fn test_publish_counts_events() {
-        let events = vec![
+        let metrics = metrics_with_events(vec![
             r#"{"entity":"signup","action":"page_view","path":"/","appId":"test-app"}"#,
             r#"{"entity":"signup","action":"page_view","path":"/","appId":"test-app"}"#,
             r#"{"entity":"login","action":"click","path":"/login","appId":"test-app"}"#,
-        ];
-        let conn = setup_db_with_events(events).await;
-        let instance_id = "test-app".to_string();
-        let mut buffer = String::new();
-        let mut exporter = PrometheusExporter {
-            buffer: &mut buffer,
-            instance_id,
-        };
-        exporter.publish(conn).await.unwrap();
+        ]);
+        let buffer = scrape(metrics, "test-app").await;
 
         // Should contain entity, action, path, and app_id as labels
         assert!(buffer.contains("entity=\"signup\""));
@@ -119,36 +273,79 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_publish_handles_empty_table() {
-        let conn = setup_db_with_events(vec![]).await;
-        let instance_id = "empty-app".to_string();
+    async fn test_publish_counts_custom_events_without_their_names() {
+        let metrics = metrics_with_events(vec![
+            r#"{"entity":"custom","action":"track","name":"signup","props":{"plan":"pro"},"appId":"test-app"}"#,
+            r#"{"entity":"custom","action":"track","name":"purchase","appId":"test-app"}"#,
+        ]);
+        let buffer = scrape(metrics, "test-app").await;
+
+        // Both names are counted in one series
+        assert!(!buffer.contains("name="));
+        let custom = buffer
+            .lines()
+            .filter(|l| l.contains("entity=\"custom\""))
+            .collect::<Vec<_>>();
+        assert_eq!(custom.len(), 1);
+        assert!(custom[0].ends_with(" 2"));
+    }
+
+    #[tokio::test]
+    async fn test_publish_handles_no_events() {
+        let buffer = scrape(Arc::default(), "empty-app").await;
 
-        let mut buffer = String::new();
-        let mut exporter = PrometheusExporter {
-            buffer: &mut buffer,
-            instance_id,
-        };
-        exporter.publish(conn).await.unwrap();
         // Should still output valid Prometheus format, but no event lines
         assert!(buffer.contains("# TYPE events counter"));
         assert!(!buffer.contains("entity="));
     }
 
+    #[tokio::test]
+    async fn test_publish_skips_events_tagged_as_bots() {
+        let metrics = Metrics::default();
+        for (path, is_bot) in [("/human", false), ("/crawled", true)] {
+            let event = format!(
+                r#"{{"entity":"page","action":"view","path":"{path}","appId":"test-app"}}"#
+            );
+            metrics.count_stored(&[pending_event(
+                &event,
+                EventContext {
+                    is_bot: Some(is_bot),
+                    ..EventContext::default()
+                },
+            )]);
+        }
+
+        let buffer = scrape(Arc::new(metrics), "test-app").await;
+
+        assert!(buffer.contains("path=\"/human\""));
+        assert!(!buffer.contains("path=\"/crawled\""));
+    }
+
+    #[tokio::test]
+    async fn test_publish_includes_throttled_requests() {
+        let metrics = Arc::new(Metrics::default());
+        metrics
+            .throttled_requests
+            .get_or_create(&Throttled {
+                limit: "app".to_string(),
+                app_id: Some("test-app".to_string()),
+            })
+            .inc();
+
+        let buffer = scrape(metrics, "test-app").await;
+
+        assert!(buffer.contains("throttled_requests_total{limit=\"app\",app_id=\"test-app\"} 1"));
+    }
+
     #[tokio::test]
     async fn test_publish_ignores_invalid_json() {
-        let events = vec![
+        let metrics = metrics_with_events(vec![
             r#"{"entity":"signup", "action": "click", "appId": "bad-json"}"#,
             r#"not a json"#,
             r#"{"entity":"signup", "action": "click", "appId": "bad-json"}"#,
-        ];
-        let conn = setup_db_with_events(events).await;
-        let instance_id = "bad-json".to_string();
-        let mut buffer = String::new();
-        let mut exporter = PrometheusExporter {
-            buffer: &mut buffer,
-            instance_id,
-        };
-        exporter.publish(conn).await.unwrap();
+        ]);
+        let buffer = scrape(metrics, "bad-json").await;
+
         // Only two valid events should be counted
         let signup_count = buffer
             .lines()
@@ -0,0 +1,261 @@
+//! Deduplication of client-supplied event ids.
+//!
+//! Clients that retry, such as beacons and offline queues, can send an `eventId` or an
+//! `Idempotency-Key` header. Ids are only unique within an app, so the event's primary key is
+//! derived from the app and the client's id, and an event whose key was seen again within the
+//! dedup window is acknowledged without storing it twice. Until the first event is committed
+//! its id only counts as in flight, and a retry is asked to come back rather than acknowledged,
+//! since the first attempt may still fail. Ids are tracked per replica; the PostgreSQL export
+//! also skips ids it already has.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{collections::HashMap, sync::Mutex};
+use uuid::Uuid;
+
+use crate::utilities::get_environment_variable_with_default;
+
+const DEFAULT_WINDOW_SECONDS: i64 = 60 * 60;
+
+/// The UUIDv5 namespace that app ids are hashed into, each of which is in turn the namespace
+/// of that app's event ids.
+const EVENT_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6f3a_9c1e_52d4_4b8e_9a07_c2e1_d85f_3b60);
+
+/// Derives the id of an event from the app that recorded it and the client's id, so two apps
+/// that happen to send the same `eventId` neither dedup nor overwrite each other's events.
+pub fn event_id(app_id: &str, client_id: &str) -> String {
+    let app = Uuid::new_v5(&EVENT_ID_NAMESPACE, app_id.as_bytes());
+    Uuid::new_v5(&app, client_id.as_bytes()).to_string()
+}
+
+/// What claiming an id found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Claim {
+    /// The id is claimed for a new event, until it is committed or released.
+    Claimed,
+    /// An event with the id was stored within the window.
+    Duplicate,
+    /// An event with the id is still being stored, and may yet fail.
+    InFlight,
+}
+
+#[derive(Debug)]
+pub struct EventIds {
+    window: TimeDelta,
+    state: Mutex<EventIdsState>,
+}
+
+#[derive(Debug, Default)]
+struct EventIdsState {
+    seen: HashMap<String, Seen>,
+    last_sweep_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct Seen {
+    at: DateTime<Utc>,
+    /// Whether the event with this id was stored.
+    committed: bool,
+}
+
+impl Default for EventIds {
+    fn default() -> Self {
+        Self::new(TimeDelta::seconds(DEFAULT_WINDOW_SECONDS))
+    }
+}
+
+impl EventIds {
+    pub fn new(window: TimeDelta) -> Self {
+        Self {
+            window,
+            state: Mutex::new(EventIdsState::default()),
+        }
+    }
+
+    /// Reads the dedup window from `IDEMPOTENCY_WINDOW_SECONDS`.
+    pub fn build() -> Self {
+        let window = get_environment_variable_with_default(
+            "IDEMPOTENCY_WINDOW_SECONDS",
+            DEFAULT_WINDOW_SECONDS.to_string(),
+        )
+        .parse::<i64>()
+        .unwrap_or(DEFAULT_WINDOW_SECONDS);
+
+        Self::new(TimeDelta::seconds(window))
+    }
+
+    /// Claims an id for a new event, unless an event with the same id was claimed within the
+    /// window.
+    pub fn claim(&self, id: &str, at: DateTime<Utc>) -> Claim {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        self.sweep(&mut state, at);
+
+        match state.seen.get(id) {
+            Some(seen) if at - seen.at <= self.window && seen.committed => Claim::Duplicate,
+            Some(seen) if at - seen.at <= self.window => Claim::InFlight,
+            _ => {
+                state.seen.insert(
+                    id.to_string(),
+                    Seen {
+                        at,
+                        committed: false,
+                    },
+                );
+                Claim::Claimed
+            }
+        }
+    }
+
+    /// Tracks the ids of events on their way to the buffer, see [`Pending`].
+    pub fn pending(&self, ids: Vec<String>) -> Pending<'_> {
+        Pending {
+            event_ids: self,
+            ids,
+        }
+    }
+
+    /// Marks claimed ids as stored, so later events with the same id are duplicates. Ids that
+    /// were never claimed, such as generated ones, are left alone.
+    fn commit<'a>(&self, ids: impl IntoIterator<Item = &'a str>) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        for id in ids {
+            if let Some(seen) = state.seen.get_mut(id) {
+                seen.committed = true;
+            }
+        }
+    }
+
+    /// Releases ids whose events could not be stored, so a retry is not taken for a duplicate.
+    pub fn release<'a>(&self, ids: impl IntoIterator<Item = &'a str>) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        for id in ids {
+            state.seen.remove(id);
+        }
+    }
+
+    /// Forgets ids older than the window, at most once per window.
+    fn sweep(&self, state: &mut EventIdsState, now: DateTime<Utc>) {
+        if state
+            .last_sweep_at
+            .is_some_and(|last_sweep_at| now - last_sweep_at < self.window)
+        {
+            return;
+        }
+
+        state.seen.retain(|_, seen| now - seen.at <= self.window);
+        state.last_sweep_at = Some(now);
+    }
+}
+
+/// The ids of events that are being stored. They are committed once the events are, and are
+/// otherwise released when dropped, also when the request is cancelled while it waits for the
+/// writer, so a retry is neither taken for a duplicate nor turned away for good.
+pub struct Pending<'a> {
+    event_ids: &'a EventIds,
+    ids: Vec<String>,
+}
+
+impl Pending<'_> {
+    pub fn commit(mut self) {
+        let ids = std::mem::take(&mut self.ids);
+        self.event_ids.commit(ids.iter().map(String::as_str));
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.event_ids.release(self.ids.iter().map(String::as_str));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_rejects_ids_seen_within_window() {
+        let ids = EventIds::new(TimeDelta::minutes(10));
+        let now = Utc::now();
+
+        assert_eq!(ids.claim("event-1", now), Claim::Claimed);
+        ids.pending(vec!["event-1".to_string()]).commit();
+        assert_eq!(
+            ids.claim("event-1", now + TimeDelta::minutes(5)),
+            Claim::Duplicate
+        );
+        assert_eq!(
+            ids.claim("event-2", now + TimeDelta::minutes(5)),
+            Claim::Claimed
+        );
+    }
+
+    #[test]
+    fn test_claim_reports_ids_in_flight_until_committed() {
+        let ids = EventIds::default();
+        let now = Utc::now();
+
+        assert_eq!(ids.claim("event-1", now), Claim::Claimed);
+        let pending = ids.pending(vec!["event-1".to_string()]);
+
+        // The first attempt may still fail, so the retry is not a duplicate yet
+        assert_eq!(ids.claim("event-1", now), Claim::InFlight);
+
+        pending.commit();
+        assert_eq!(ids.claim("event-1", now), Claim::Duplicate);
+    }
+
+    #[test]
+    fn test_claim_accepts_ids_again_after_window() {
+        let ids = EventIds::new(TimeDelta::minutes(10));
+        let now = Utc::now();
+
+        assert_eq!(ids.claim("event-1", now), Claim::Claimed);
+        assert_eq!(
+            ids.claim("event-1", now + TimeDelta::minutes(11)),
+            Claim::Claimed
+        );
+    }
+
+    #[test]
+    fn test_dropping_pending_ids_allows_retry() {
+        let ids = EventIds::default();
+        let now = Utc::now();
+
+        assert_eq!(ids.claim("event-1", now), Claim::Claimed);
+        drop(ids.pending(vec!["event-1".to_string()]));
+        assert_eq!(ids.claim("event-1", now), Claim::Claimed);
+    }
+
+    #[test]
+    fn test_event_id_is_scoped_to_the_app() {
+        assert_eq!(event_id("blog", "event-1"), event_id("blog", "event-1"));
+        assert_ne!(event_id("blog", "event-1"), event_id("shop", "event-1"));
+        assert_ne!(event_id("blog", "event-1"), event_id("blog", "event-2"));
+        // The app id and the client's id cannot be shifted into one another
+        assert_ne!(event_id("blog", "-event"), event_id("blog-", "event"));
+    }
+
+    #[test]
+    fn test_sweep_forgets_expired_ids() {
+        let ids = EventIds::new(TimeDelta::minutes(10));
+        let now = Utc::now();
+
+        ids.claim("old", now);
+        ids.claim("new", now + TimeDelta::minutes(11));
+
+        let state = ids.state.lock().unwrap();
+        assert_eq!(state.seen.len(), 1);
+        assert!(state.seen.contains_key("new"));
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{
+    AppState,
+    bots::{BotFilterMode, BotReason},
+    enrichment::{RequestContext, campaign, user_agent::DeviceClass},
+    errors::{ApplicationError, ValidationIssue},
+    exporter::prometheus::Filtered,
+    idempotency::{self, Claim},
+    schemas::MAX_EVENT_ID_LENGTH,
+    sessions::{PageView, Visit},
+    storage::memory::{EventContext, PendingEvent},
+    utilities::generate_uuid_v4,
+};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// The valid items of a batch are stored with a single multi-row insert that binds every
+/// column of every item, so batches are kept well below SQLite's limit of 32766 parameters.
+pub const MAX_BATCH_ITEMS: usize = 1_000;
+
+/// What happens to a valid event.
+#[derive(Debug)]
+pub enum Prepared {
+    Event(Box<PendingEvent>),
+    /// The event's id was already seen within the dedup window.
+    Duplicate(String),
+    /// An event with the same id is still being stored, and may yet fail.
+    InFlight(String),
+    /// A bot event that is dropped rather than stored.
+    Filtered,
+}
+
+/// The answer to an event whose id an earlier request is still storing. The client retries it
+/// shortly, and is then told whether the earlier request stored it.
+pub fn in_flight() -> ApplicationError {
+    ApplicationError::Conflict("An event with this id is still being stored".to_string())
+}
+
+/// Validates a single event payload and prepares it for insertion. The payload's `eventId`,
+/// or else the request's idempotency key, determines the event id together with the app,
+/// instead of a random one.
+pub fn prepare_event(
+    state: &AppState,
+    request: &RequestContext,
+    payload: &Value,
+    idempotency_key: Option<&str>,
+) -> Result<Prepared, ApplicationError> {
+    // Requests are authorized before their payload is validated, so only rejected payloads of
+    // authorized clients are kept as dead letters
+    let app_id = payload.get("appId").and_then(|v| v.as_str());
+    state.apps.authorize(app_id.unwrap_or_default(), request)?;
+
+    let schema = state.schemas.validator_for(app_id);
+
+    let errors: Vec<ValidationIssue> = schema
+        .validator
+        .iter_errors(payload)
+        .map(|e| ValidationIssue::from(&e))
+        .collect();
+
+    if !errors.is_empty() {
+        return Err(ApplicationError::ValidationFailed(errors));
+    }
+
+    let recorded_by = app_id.ok_or_else(|| {
+        ApplicationError::InvalidPayload("Missing 'recorded_by' field".to_string())
+    })?;
+
+    let bot = state
+        .bots
+        .detect(request.user_agent.as_deref(), request.client_ip);
+
+    if let Some(reason) = bot {
+        state
+            .metrics
+            .filtered_events
+            .get_or_create(&Filtered {
+                reason: reason.as_str().to_string(),
+                app_id: recorded_by.to_string(),
+            })
+            .inc();
+
+        if state.bots.mode == BotFilterMode::Drop {
+            return Ok(Prepared::Filtered);
+        }
+    }
+
+    let recorded_at = Utc::now();
+
+    let client_id = payload
+        .get("eventId")
+        .and_then(|v| v.as_str())
+        .or(idempotency_key);
+
+    let id = match client_id {
+        Some(id) if id.is_empty() || id.len() > MAX_EVENT_ID_LENGTH => {
+            return Err(ApplicationError::InvalidPayload(format!(
+                "Event ids must be between 1 and {MAX_EVENT_ID_LENGTH} characters long"
+            )));
+        }
+        Some(id) => {
+            let id = idempotency::event_id(recorded_by, id);
+            match state.event_ids.claim(&id, recorded_at) {
+                Claim::Claimed => id,
+                Claim::Duplicate => return Ok(Prepared::Duplicate(id)),
+                Claim::InFlight => return Ok(Prepared::InFlight(id)),
+            }
+        }
+        None => generate_uuid_v4(),
+    };
+
+    let mut context = EventContext {
+        schema_version: Some(schema.version.clone()),
+        is_bot: (state.bots.mode != BotFilterMode::Off).then_some(bot.is_some()),
+        ..EventContext::default()
+    };
+    state.enricher.enrich(
+        &mut context,
+        request,
+        recorded_by,
+        state.apps.get(recorded_by),
+        payload,
+    );
+
+    // The device class goes by the bot filter's User-Agent lists, so both agree on what is a bot
+    if matches!(bot, Some(BotReason::Crawler | BotReason::Headless)) {
+        context.device = Some(DeviceClass::Bot.as_str().to_string());
+    }
+
+    let payload = &extract_campaign(payload);
+
+    // Sessions are keyed by the visitor, so events without a visitor id stay unsessionized, and
+    // bots are left out so they do not skew bounce rate and session length. The writer assigns
+    // the session when it stores the event.
+    let visit = context
+        .visitor_id
+        .clone()
+        .filter(|_| bot.is_none())
+        .map(|visitor_id| Visit {
+            visitor_id,
+            page_view: (payload.get("entity").and_then(|v| v.as_str()) == Some("page")).then(
+                || PageView {
+                    path: payload
+                        .get("path")
+                        .and_then(|v| v.as_str())
+                        .map(str::to_string),
+                },
+            ),
+        });
+
+    Ok(Prepared::Event(Box::new(PendingEvent {
+        id,
+        recorded_at,
+        recorded_by: recorded_by.to_string(),
+        event: payload.to_string(),
+        context,
+        visit,
+        session: None,
+    })))
+}
+
+/// Page views may report a full URL or query string as their `path`. The UTM parameters are
+/// moved out of it into top-level `utm_*` fields, which clients cannot send themselves, and
+/// only the pathname is kept.
+fn extract_campaign(payload: &Value) -> Value {
+    let mut payload = payload.clone();
+
+    if payload.get("entity").and_then(|v| v.as_str()) != Some("page") {
+        return payload;
+    }
+
+    let Some(url) = payload.get("path").and_then(|v| v.as_str()) else {
+        return payload;
+    };
+
+    let (path, campaign) = campaign::extract(url);
+
+    if let Some(fields) = payload.as_object_mut() {
+        if path.is_empty() {
+            fields.remove("path");
+        } else {
+            fields.insert("path".to_string(), Value::String(path));
+        }
+
+        if let Ok(Value::Object(campaign)) = serde_json::to_value(campaign) {
+            fields.extend(campaign);
+        }
+    }
+
+    payload
+}
+
+/// Builds an event payload from the query parameters of a tracking pixel request. Parameters
+/// named `props[<name>]` become the event's `props`, and every value is a string. A repeated
+/// parameter keeps its last value. The `key` parameter is the app's key rather than a field.
+pub fn event_from_query(params: Vec<(String, String)>) -> Value {
+    let mut event = Map::new();
+    let mut props = Map::new();
+
+    for (name, value) in params.into_iter().filter(|(name, _)| name != "key") {
+        match name
+            .strip_prefix("props[")
+            .and_then(|name| name.strip_suffix(']'))
+        {
+            Some(prop) => props.insert(prop.to_string(), Value::String(value)),
+            None => event.insert(name, Value::String(value)),
+        };
+    }
+
+    if !props.is_empty() {
+        event.insert("props".to_string(), Value::Object(props));
+    }
+
+    Value::Object(event)
+}
+
+/// A line of a newline-delimited batch that is not valid JSON.
+#[derive(Debug)]
+pub struct InvalidItem {
+    pub raw: String,
+    pub error: String,
+}
+
+/// Splits a batch body into individual items. A body starting with `[` is treated as a JSON
+/// array, anything else as newline-delimited JSON. Lines that fail to parse are kept as errors
+/// so they can be reported against their position in the batch.
+pub fn split_batch(body: &str) -> Result<Vec<Result<Value, InvalidItem>>, ApplicationError> {
+    let trimmed = body.trim_start();
+
+    if trimmed.starts_with('[') {
+        let items: Vec<Value> = serde_json::from_str(trimmed)
+            .map_err(|e| ApplicationError::InvalidPayload(e.to_string()))?;
+
+        return Ok(items.into_iter().map(Ok).collect());
+    }
+
+    Ok(trimmed
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line).map_err(|e| InvalidItem {
+                raw: line.to_string(),
+                error: e.to_string(),
+            })
+        })
+        .collect())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase", tag = "status")]
+pub enum ItemResult {
+    Accepted {
+        index: usize,
+        id: String,
+    },
+    Rejected {
+        index: usize,
+        error: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        errors: Vec<ValidationIssue>,
+    },
+    /// Recognised as a bot and dropped.
+    Filtered {
+        index: usize,
+    },
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct BatchReport {
+    pub accepted: usize,
+    pub rejected: usize,
+    pub filtered: usize,
+    pub results: Vec<ItemResult>,
+}
+
+impl BatchReport {
+    pub fn accept(&mut self, index: usize, id: String) {
+        self.accepted += 1;
+        self.results.push(ItemResult::Accepted { index, id });
+    }
+
+    /// Rejects an item, listing every violation if it does not match its schema.
+    pub fn reject(&mut self, index: usize, error: String, errors: Vec<ValidationIssue>) {
+        self.rejected += 1;
+        self.results.push(ItemResult::Rejected {
+            index,
+            error,
+            errors,
+        });
+    }
+
+    pub fn filter(&mut self, index: usize) {
+        self.filtered += 1;
+        self.results.push(ItemResult::Filtered { index });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_split_batch_json_array() {
+        let items = split_batch(r#"[{"a": 1}, {"b": 2}]"#).unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(|item| item.is_ok()));
+    }
+
+    #[test]
+    fn test_split_batch_ndjson_skips_blank_lines() {
+        let items = split_batch("{\"a\": 1}\n\n{\"b\": 2}\n").unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(|item| item.is_ok()));
+    }
+
+    #[test]
+    fn test_split_batch_ndjson_keeps_invalid_lines_as_errors() {
+        let items = split_batch("{\"a\": 1}\nnot json\n{\"b\": 2}").unwrap();
+        assert_eq!(items.len(), 3);
+        assert!(items[0].is_ok());
+        assert_eq!(items[1].as_ref().unwrap_err().raw, "not json");
+        assert!(items[2].is_ok());
+    }
+
+    #[test]
+    fn test_split_batch_invalid_array_is_an_error() {
+        assert!(split_batch(r#"[{"a": 1},"#).is_err());
+    }
+
+    #[test]
+    fn test_event_from_query() {
+        let event = event_from_query(vec![
+            ("entity".to_string(), "page".to_string()),
+            ("action".to_string(), "view".to_string()),
+            ("appId".to_string(), "blog".to_string()),
+            ("props[feed]".to_string(), "rss".to_string()),
+            ("props[plan]".to_string(), "pro".to_string()),
+            ("key".to_string(), "pk_blog".to_string()),
+        ]);
+
+        assert_eq!(
+            event,
+            json!({
+                "entity": "page",
+                "action": "view",
+                "appId": "blog",
+                "props": { "feed": "rss", "plan": "pro" }
+            })
+        );
+    }
+
+    #[test]
+    fn test_extract_campaign_from_page_view_path() {
+        let payload = extract_campaign(&json!({
+            "entity": "page",
+            "action": "view",
+            "path": "https://example.com/pricing?utm_source=newsletter&plan=pro",
+            "appId": "test-app"
+        }));
+
+        assert_eq!(payload["path"], "/pricing");
+        assert_eq!(payload["utm_source"], "newsletter");
+        assert!(payload.get("utm_medium").is_none());
+    }
+
+    #[test]
+    fn test_extract_campaign_ignores_other_entities() {
+        let payload = json!({
+            "entity": "anchor",
+            "action": "click",
+            "path": "https://example.com/?utm_source=newsletter",
+            "appId": "test-app"
+        });
+
+        assert_eq!(extract_campaign(&payload), payload);
+    }
+
+    #[test]
+    fn test_batch_report_serialization() {
+        let mut report = BatchReport::default();
+        report.accept(0, "abc".to_string());
+        report.reject(1, "bad".to_string(), Vec::new());
+        report.filter(2);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["accepted"], 1);
+        assert_eq!(json["rejected"], 1);
+        assert_eq!(json["filtered"], 1);
+        assert_eq!(json["results"][0]["status"], "accepted");
+        assert_eq!(json["results"][0]["id"], "abc");
+        assert_eq!(json["results"][1]["status"], "rejected");
+        assert_eq!(json["results"][1]["index"], 1);
+        assert!(json["results"][1].get("errors").is_none());
+        assert_eq!(json["results"][2]["status"], "filtered");
+    }
+
+    #[test]
+    fn test_batch_report_lists_validation_errors() {
+        let mut report = BatchReport::default();
+        report.reject(
+            0,
+            "The event does not match its schema".to_string(),
+            vec![ValidationIssue {
+                instance_path: "/name".to_string(),
+                keyword: "minLength".to_string(),
+                message: "\"\" is shorter than 1 character".to_string(),
+            }],
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["results"][0]["errors"][0]["instance_path"], "/name");
+        assert_eq!(json["results"][0]["errors"][0]["keyword"], "minLength");
+    }
+}
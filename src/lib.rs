@@ -0,0 +1,7 @@
+//! The parts of the collector that other services link against, such as a backend that signs
+//! the links it puts into pages and emails with [`sign`].
+
+pub mod errors;
+pub mod redirects;
+
+pub use redirects::sign;
@@ -1,37 +1,38 @@
-mod errors;
+mod admin;
+mod apps;
+mod bots;
+mod enrichment;
 mod exporter;
+mod idempotency;
+mod ingest;
 mod middleware;
+mod rate_limit;
 mod responses;
 mod schemas;
+mod sessions;
 mod storage;
 mod utilities;
 
-#[cfg(feature = "export-parquet")]
-use anyhow::Result;
-
+use analytics_collector::{errors, redirects};
 use axum::{
     Router,
     http::StatusCode,
-    middleware::from_fn,
+    middleware::{from_fn, from_fn_with_state},
     routing::{get, post},
 };
 
-#[cfg(feature = "export-parquet")]
-use chrono::{DateTime, TimeDelta, Utc};
-
 #[cfg(any(feature = "export-postgres", feature = "export-parquet"))]
 use exporter::Exporter;
 
 use libsql::Connection;
-use middleware::{validate_body_length, validate_content_type};
-
-#[cfg(feature = "export-parquet")]
-use std::ops::Deref;
+use middleware::{
+    cors, decompress_body, rate_limit_apps, rate_limit_ip, validate_body_length,
+    validate_content_type,
+};
 
-use responses::{get_metrics, post_event};
+use responses::{get_metrics, get_pixel, get_redirect, post_batch, post_event};
 use rust_web_common::telemetry::TelemetryBuilder;
-use std::sync::Arc;
-use storage::memory::initialize;
+use std::{net::SocketAddr, sync::Arc};
 
 #[cfg(feature = "export-postgres")]
 use tracing::error;
@@ -39,11 +40,8 @@ use tracing::error;
 #[cfg(any(feature = "export-postgres", feature = "export-parquet"))]
 use tokio::time::{Duration, interval};
 
-#[cfg(feature = "export-parquet")]
-use tokio::sync::RwLock;
-
 use tokio::spawn;
-use tokio::{select, signal::unix::SignalKind};
+use tokio::{select, signal::unix::SignalKind, sync::watch, task::JoinHandle};
 use tower::ServiceBuilder;
 use tower_http::trace::TraceLayer;
 
@@ -63,52 +61,159 @@ use exporter::parquet::ParquetExporter;
 #[derive(Clone, Debug)]
 pub struct AppState {
     pub connection: Arc<libsql::Connection>,
-    pub validator: Arc<jsonschema::Validator>,
+    pub apps: Arc<apps::AppRegistry>,
+    pub bots: Arc<bots::BotFilter>,
+    pub schemas: Arc<schemas::SchemaRegistry>,
+    pub enricher: Arc<enrichment::Enricher>,
+    pub event_ids: Arc<idempotency::EventIds>,
+    pub rate_limiter: Arc<rate_limit::RateLimiter>,
+    pub metrics: Arc<exporter::prometheus::Metrics>,
+    pub dead_letters: Arc<storage::dead_letters::DeadLetters>,
+    pub body_limits: middleware::BodyLimits,
+    pub retention: Arc<storage::retention::Retention>,
+    pub writer: storage::writer::Writer,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// An in-memory state with the embedded schema, no rate limits and default settings.
+    pub async fn for_tests() -> Self {
+        let unlimited = rate_limit::Quota {
+            per_second: 0.0,
+            burst: 0.0,
+        };
+
+        let buffer = storage::memory::Buffer::in_memory().await.unwrap();
+        let connection = Arc::new(buffer.connection);
+        let retention = Arc::new(storage::retention::Retention::default());
+        let metrics = Arc::new(exporter::prometheus::Metrics::default());
+
+        Self {
+            connection,
+            apps: Arc::default(),
+            bots: Arc::default(),
+            schemas: Arc::new(schemas::SchemaRegistry::embedded().unwrap()),
+            enricher: Arc::default(),
+            event_ids: Arc::default(),
+            rate_limiter: Arc::new(rate_limit::RateLimiter {
+                ip: rate_limit::TokenBuckets::new(unlimited),
+                app: rate_limit::TokenBuckets::new(unlimited),
+            }),
+            metrics: metrics.clone(),
+            dead_letters: Arc::default(),
+            body_limits: middleware::BodyLimits::default(),
+            retention: retention.clone(),
+            writer: storage::writer::Writer::spawn(
+                buffer.writer,
+                retention,
+                Arc::default(),
+                metrics,
+                storage::writer::Batching::default(),
+            )
+            .0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InternalState {
+    /// The ingestion state, shared so dead letters are replayed exactly like new events.
+    pub app: AppState,
+    pub instance_id: String,
+    /// Bearer token for the admin endpoints, which are only served when it is set.
+    pub admin_token: Option<Arc<str>>,
 }
 
 #[tokio::main]
 async fn main() {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+
+    if arguments.first().map(String::as_str) == Some("sign-redirect") {
+        let signed = redirects::sign_from_arguments(&arguments[1..]);
+        std::process::exit(if signed { 0 } else { 1 });
+    }
+
     let _telemetry_providers = TelemetryBuilder::new("analytics-collector".to_string())
         .build()
         .expect("failed to initialize telemetry");
 
-    let memory_database = initialize().await.expect("failed to initialize database");
-    let memory_database = Arc::new(memory_database);
+    let buffer = storage::memory::Buffer::build()
+        .await
+        .expect("failed to initialize database");
+    let memory_database = Arc::new(buffer.connection);
 
     #[cfg(feature = "export-postgres")]
-    let periodic_postgres_export_handler =
+    let mut periodic_postgres_export_handler =
         spawn(periodic_postgres_export_handler(memory_database.clone()));
 
     #[cfg(not(feature = "export-postgres"))]
-    let periodic_postgres_export_handler = spawn(async {});
+    let mut periodic_postgres_export_handler = spawn(std::future::pending::<()>());
 
     #[cfg(feature = "export-parquet")]
-    let periodic_parquet_export_handler =
+    let mut periodic_parquet_export_handler =
         spawn(periodic_parquet_export_handler(memory_database.clone()));
 
     #[cfg(not(feature = "export-parquet"))]
-    let periodic_parquet_export_handler = spawn(async {});
+    let mut periodic_parquet_export_handler = spawn(std::future::pending::<()>());
+
+    let (state, writer_handler) = app_state(memory_database.clone(), buffer.writer);
+    state
+        .retention
+        .refresh(&state.connection, &state.metrics)
+        .await
+        .expect("failed to measure the buffer");
+    state
+        .enricher
+        .visitors
+        .restore(&state.connection, chrono::Utc::now())
+        .await
+        .expect("failed to restore the visitor salt");
+
+    let mut signal = tokio::signal::unix::signal(SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
 
-    let internal_endpoint_handler = spawn(internal_endpoint_handler(memory_database.clone()));
-    let external_endpoint_handler = spawn(external_endpoint_handler(memory_database.clone()));
-    let shutdown_handler = spawn(shutdown_handler(memory_database.clone()));
+    let (shutdown, stopping) = watch::channel(());
+    let mut periodic_prune_handler = spawn(periodic_prune_handler(state.clone()));
+    let mut internal_endpoint_handler =
+        spawn(internal_endpoint_handler(state.clone(), stopping.clone()));
+    let mut external_endpoint_handler = spawn(external_endpoint_handler(state, stopping));
 
     select! {
-        _ = periodic_postgres_export_handler => {}
-        _ = periodic_parquet_export_handler => {}
-        _ = internal_endpoint_handler => {}
-        _ = external_endpoint_handler => {}
-        _ = shutdown_handler => {}
+        _ = &mut periodic_postgres_export_handler => {}
+        _ = &mut periodic_parquet_export_handler => {}
+        _ = &mut periodic_prune_handler => {}
+        _ = &mut internal_endpoint_handler => {}
+        _ = &mut external_endpoint_handler => {}
+        _ = signal.recv() => {}
+    }
+
+    // Both servers stop accepting connections and answer the requests they already read
+    let _ = shutdown.send(());
+    let _ = external_endpoint_handler.await;
+    let _ = internal_endpoint_handler.await;
+
+    // The periodic tasks are stopped, so the last export does not race them and the prune
+    // handler lets go of its writer
+    for handler in [
+        periodic_prune_handler,
+        periodic_postgres_export_handler,
+        periodic_parquet_export_handler,
+    ] {
+        handler.abort();
+        let _ = handler.await;
+    }
+
+    // With every writer dropped, the writer task commits what is still queued and finishes
+    if let Err(e) = writer_handler.await {
+        tracing::error!("the buffer writer failed: {e}");
     }
+
+    shutdown_handler(memory_database).await;
 }
 
+/// Exports everything the buffer still holds, once no more events are written to it.
 #[instrument(name = "shutdown-handler")]
 async fn shutdown_handler(connection: Arc<libsql::Connection>) {
-    let mut signal = tokio::signal::unix::signal(SignalKind::terminate())
-        .expect("failed to install SIGTERM handler");
-
-    signal.recv().await;
-
     #[cfg(feature = "export-postgres")]
     let mut postgres_exporter = PostgresqlExporter::build()
         .await
@@ -125,11 +230,7 @@ async fn shutdown_handler(connection: Arc<libsql::Connection>) {
         });
 
     #[cfg(feature = "export-parquet")]
-    let mut parquet_exporter = ParquetExporter {
-        last_export_at: Utc::now()
-            .checked_sub_signed(TimeDelta::minutes(1))
-            .unwrap(),
-    };
+    let mut parquet_exporter = ParquetExporter;
 
     #[cfg(feature = "export-parquet")]
     parquet_exporter
@@ -142,22 +243,75 @@ async fn shutdown_handler(connection: Arc<libsql::Connection>) {
         });
 }
 
-async fn external_endpoint_handler(connection: Arc<Connection>) {
+/// Builds the state and starts the buffer writer, whose task finishes once every clone of the
+/// state is dropped.
+fn app_state(connection: Arc<Connection>, writer: Connection) -> (AppState, JoinHandle<()>) {
+    let retention = Arc::new(
+        storage::retention::Retention::build(exporter::enabled())
+            .expect("failed to configure buffer retention"),
+    );
+    let metrics = Arc::new(exporter::prometheus::Metrics::default());
+    let (writer, writer_handler) = storage::writer::Writer::spawn(
+        writer,
+        retention.clone(),
+        Arc::new(sessions::SessionTracker::build()),
+        metrics.clone(),
+        storage::writer::Batching::build(),
+    );
+
     let state = AppState {
         connection,
-        validator: Arc::new(
-            schemas::event_validator().expect("failed to create JSON schema validator"),
+        apps: Arc::new(apps::AppRegistry::build().expect("failed to load app registry")),
+        bots: Arc::new(bots::BotFilter::build().expect("failed to load bot filter")),
+        schemas: Arc::new(
+            schemas::SchemaRegistry::build().expect("failed to load JSON schema registry"),
         ),
+        enricher: Arc::new(
+            enrichment::Enricher::build().expect("failed to initialize event enrichment"),
+        ),
+        event_ids: Arc::new(idempotency::EventIds::build()),
+        rate_limiter: Arc::new(rate_limit::RateLimiter::build()),
+        metrics,
+        dead_letters: Arc::new(storage::dead_letters::DeadLetters::build()),
+        body_limits: middleware::BodyLimits::build(),
+        retention,
+        writer,
     };
+
+    (state, writer_handler)
+}
+
+async fn external_endpoint_handler(state: AppState, stopping: watch::Receiver<()>) {
+    // Tracking pixels and click redirects are requested by image tags and links, which send
+    // neither a body nor a preflight request, so only the rate limit applies to them
+    let tracking = Router::new()
+        .route("/pixel.gif", get(get_pixel))
+        .route("/p.gif", get(get_pixel))
+        .route("/r", get(get_redirect))
+        .layer(
+            ServiceBuilder::new()
+                .layer(from_fn_with_state(state.clone(), rate_limit_ip))
+                .layer(from_fn_with_state(state.clone(), rate_limit_apps))
+                .layer(TraceLayer::new_for_http()),
+        );
+
     let app = Router::new()
         .route("/", post(post_event))
+        .route("/batch", post(post_batch))
         .route("/{any}", post(post_event))
         .layer(
             ServiceBuilder::new()
+                // CORS preflight requests are answered before any other validation
+                .layer(cors(state.apps.clone()))
                 .layer(from_fn(validate_content_type))
-                .layer(from_fn(validate_body_length))
+                // Clients over their limit are turned away before their bodies are read
+                .layer(from_fn_with_state(state.clone(), rate_limit_ip))
+                .layer(from_fn_with_state(state.clone(), decompress_body))
+                .layer(from_fn_with_state(state.clone(), validate_body_length))
+                .layer(from_fn_with_state(state.clone(), rate_limit_apps))
                 .layer(TraceLayer::new_for_http()),
         )
+        .merge(tracking)
         .with_state(state)
         // putting the healthcheck route at the end to avoid it being processed by the middleware and logging
         .route("/healthcheck", get(StatusCode::OK));
@@ -169,19 +323,34 @@ async fn external_endpoint_handler(connection: Arc<Connection>) {
         .await
         .unwrap();
 
-    axum::serve(listener, app)
-        .await
-        .expect("failed to start server")
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(stopped(stopping))
+    .await
+    .expect("failed to start server")
 }
 
-async fn internal_endpoint_handler(connection: Arc<Connection>) {
+async fn internal_endpoint_handler(app: AppState, stopping: watch::Receiver<()>) {
     // This server is dedicated to serving Prometheus metrics for observability purposes.
     // It uses a separate port (($PORT || 8000) + 1) to isolate metrics traffic from application traffic.
-    let instance_id = generate_uuid_v4();
-    let app = Router::new()
-        .route("/metrics", get(get_metrics))
-        .with_state((connection, instance_id))
-        .layer(TraceLayer::new_for_http());
+    let state = InternalState {
+        app,
+        instance_id: generate_uuid_v4(),
+        admin_token: std::env::var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+            .map(Arc::from),
+    };
+
+    let mut router = Router::new().route("/metrics", get(get_metrics));
+
+    if state.admin_token.is_some() {
+        router = router.nest("/admin", admin::router(state.clone()));
+    }
+
+    let app = router.with_state(state).layer(TraceLayer::new_for_http());
 
     let port = get_environment_variable_with_default("PORT", "8000".to_string());
     let port = port.parse::<u16>().unwrap_or(8000) + 1;
@@ -191,10 +360,41 @@ async fn internal_endpoint_handler(connection: Arc<Connection>) {
         .unwrap();
 
     axum::serve(listener, app)
+        .with_graceful_shutdown(stopped(stopping))
         .await
         .expect("failed to start server")
 }
 
+/// Resolves once the process is shutting down.
+async fn stopped(mut stopping: watch::Receiver<()>) {
+    let _ = stopping.changed().await;
+}
+
+async fn periodic_prune_handler(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = state
+            .retention
+            .prune(&state.connection, &state.metrics)
+            .await
+        {
+            tracing::error!("failed to prune the buffer: {e}");
+        }
+
+        if let Err(e) = state
+            .enricher
+            .visitors
+            .store(&state.connection, chrono::Utc::now())
+            .await
+        {
+            tracing::error!("failed to store the visitor salt: {e}");
+        }
+    }
+}
+
 #[cfg(feature = "export-postgres")]
 async fn periodic_postgres_export_handler(memory_connection: Arc<libsql::Connection>) {
     let mut postgres_exporter = PostgresqlExporter::build()
@@ -217,43 +417,19 @@ async fn periodic_postgres_export_handler(memory_connection: Arc<libsql::Connect
 }
 
 #[cfg(feature = "export-parquet")]
-async fn periodic_parquet_export_handler(connection: Arc<libsql::Connection>) -> Result<()> {
+async fn periodic_parquet_export_handler(connection: Arc<libsql::Connection>) {
     let mut interval = interval(Duration::from_secs(30)); // flush every 30 seconds
-    let last_export_at = Arc::new(RwLock::new(Utc::now()));
-    let export_closure =
-        async |connection: Arc<libsql::Connection>, last_export_at: Arc<RwLock<DateTime<Utc>>>| {
-            let last_export_at_copy = last_export_at.clone();
-            let last_export_at_copy = last_export_at_copy.read().await;
-            let last_export_at_copy = last_export_at_copy.deref().to_owned();
-
-            let mut exporter = exporter::parquet::ParquetExporter {
-                last_export_at: last_export_at_copy,
-            };
-
-            exporter.publish(connection.clone()).await
-        };
 
     loop {
         interval.tick().await;
 
-        let exported_started = Utc::now();
-
-        let handle = spawn(export_closure(connection.clone(), last_export_at.clone()));
-
-        match handle.await {
-            Err(err) => {
-                tracing::error!("error {}", err);
-                continue;
-            }
-            Ok(result) => {
-                if let Err(err) = result {
-                    tracing::error!("error {}", err);
-                    continue;
-                }
-            }
+        // Each export runs in a task of its own, so a panic while writing a file ends only that
+        // export, and the next one starts over from the checkpoint
+        let connection = connection.clone();
+        match tokio::spawn(async move { ParquetExporter.publish(connection).await }).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::error!("failed to flush events to Parquet: {e}"),
+            Err(e) => tracing::error!("exporting to Parquet panicked: {e}"),
         }
-
-        let mut guard = last_export_at.write().await;
-        *guard = exported_started;
     }
 }
@@ -1,56 +1,405 @@
-use crate::errors::ApplicationError;
-use anyhow::{Result, anyhow};
+use crate::{
+    AppState, InternalState,
+    apps::AppRegistry,
+    enrichment::RequestContext,
+    errors::ApplicationError,
+    exporter::prometheus::{BodyRejected, Metrics, Throttled},
+    ingest::{event_from_query, split_batch},
+    utilities::get_environment_variable_with_default,
+};
+use anyhow::Result;
 use axum::{
-    body::HttpBody,
-    extract::Request,
-    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+    body::{Body, to_bytes},
+    extract::{Query, Request, State},
+    http::{
+        HeaderMap, HeaderName, Method,
+        header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    },
     middleware::Next,
     response::Response,
 };
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_stream::StreamExt;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Answers CORS preflight requests for the origins of all registered apps. Preflight requests
+/// carry no body, so whether an origin may send events for a particular app is only checked
+/// when the event is ingested.
+pub fn cors(apps: Arc<AppRegistry>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| apps.allows_origin(origin))
+        }))
+        .allow_methods([Method::POST])
+        .allow_headers([
+            CONTENT_TYPE,
+            CONTENT_ENCODING,
+            HeaderName::from_static("x-api-key"),
+            HeaderName::from_static("idempotency-key"),
+        ])
+        .max_age(Duration::from_secs(60 * 60 * 24))
+}
+
+const DEFAULT_EVENT_BODY_LIMIT: usize = 1024;
+const DEFAULT_BATCH_BODY_LIMIT: usize = 1024 * 1024;
+const DEFAULT_DECOMPRESSED_BODY_LIMIT: usize = 4 * 1024 * 1024;
+
+/// Maximum request body sizes in bytes, per route.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimits {
+    pub event: usize,
+    pub batch: usize,
+    /// Hard cap on what a compressed body may expand to, checked while decompressing and
+    /// before the route limits apply.
+    pub decompressed: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            event: DEFAULT_EVENT_BODY_LIMIT,
+            batch: DEFAULT_BATCH_BODY_LIMIT,
+            decompressed: DEFAULT_DECOMPRESSED_BODY_LIMIT,
+        }
+    }
+}
+
+impl BodyLimits {
+    /// Reads the limits from `MAX_EVENT_BODY_BYTES`, `MAX_BATCH_BODY_BYTES` and
+    /// `MAX_DECOMPRESSED_BODY_BYTES`.
+    pub fn build() -> Self {
+        let limit = |key: &str, default: usize| {
+            get_environment_variable_with_default(key, default.to_string())
+                .parse::<usize>()
+                .unwrap_or(default)
+        };
+
+        Self {
+            event: limit("MAX_EVENT_BODY_BYTES", DEFAULT_EVENT_BODY_LIMIT),
+            batch: limit("MAX_BATCH_BODY_BYTES", DEFAULT_BATCH_BODY_LIMIT),
+            decompressed: limit(
+                "MAX_DECOMPRESSED_BODY_BYTES",
+                DEFAULT_DECOMPRESSED_BODY_LIMIT,
+            ),
+        }
+    }
+
+    /// The limit for a request path, with its route name for the metrics.
+    fn for_path(&self, path: &str) -> (&'static str, usize) {
+        match path {
+            "/batch" => ("batch", self.batch),
+            _ => ("event", self.event),
+        }
+    }
+}
+
+/// Decodes `gzip`, `deflate` and `br` request bodies, so the body limits and the handlers see
+/// the decompressed JSON. The compressed body is read only up to the limit of its route, and
+/// decompression stops once the output passes the decompressed cap, so a small body that
+/// expands enormously is rejected without being expanded in full. Decompression runs on the
+/// blocking pool so that it does not hold up other requests.
+pub async fn decompress_body(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApplicationError> {
+    let Some(encoding) = request.headers().get(CONTENT_ENCODING) else {
+        return Ok(next.run(request).await);
+    };
+
+    let encoding = encoding
+        .to_str()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let limit = state.body_limits.decompressed;
+    let (mut parts, body) = request.into_parts();
+    let (_, compressed_limit) = state.body_limits.for_path(parts.uri.path());
+
+    parts.headers.remove(CONTENT_ENCODING);
+
+    if encoding == "identity" {
+        return Ok(next.run(Request::from_parts(parts, body)).await);
+    }
+
+    let compressed = to_bytes(body, compressed_limit)
+        .await
+        .map_err(|_| ApplicationError::PayloadTooLarge("Request body too large".to_string()))?;
+    let decompressed =
+        tokio::task::spawn_blocking(move || decompress(&encoding, &compressed, limit))
+            .await
+            .map_err(|e| ApplicationError::Unknown(e.into()))??;
+
+    // The route limits are checked against the decompressed length
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, decompressed.len().into());
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(decompressed)))
+        .await)
+}
+
+fn decompress(
+    encoding: &str,
+    compressed: &[u8],
+    limit: usize,
+) -> Result<Vec<u8>, ApplicationError> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(compressed)),
+        // HTTP's `deflate` is the zlib format, see RFC 9110 section 8.4.1.2
+        "deflate" => Box::new(ZlibDecoder::new(compressed)),
+        "br" => Box::new(brotli::Decompressor::new(compressed, 4096)),
+        _ => {
+            return Err(ApplicationError::UnsupportedMediaType(format!(
+                "Unsupported Content-Encoding: {encoding}"
+            )));
+        }
+    };
+
+    // Reading one byte past the limit tells a body at the limit from one over it
+    let mut decompressed = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| {
+            ApplicationError::InvalidPayload(format!("Failed to decompress request body: {e}"))
+        })?;
+
+    if decompressed.len() > limit {
+        return Err(ApplicationError::PayloadTooLarge(
+            "Decompressed request body too large".to_string(),
+        ));
+    }
+
+    Ok(decompressed)
+}
 
+/// Reads the request body up to the limit of its route, counting the bytes actually received
+/// rather than trusting `Content-Length`, which chunked requests do not send. A body declared or
+/// found to be over the limit is rejected without reading the rest, and its size is recorded:
+/// the declared length if there is one, otherwise the bytes received until the limit was passed.
 pub async fn validate_body_length(
+    State(state): State<AppState>,
     request: Request,
     next: Next,
 ) -> Result<Response, ApplicationError> {
+    let (route, limit) = state.body_limits.for_path(request.uri().path());
     let (parts, body) = request.into_parts();
-    let size_hint = body.size_hint();
-    if size_hint.lower() > 1024 {
-        return Ok(Response::builder()
-            .status(StatusCode::PAYLOAD_TOO_LARGE)
-            .body("Request body too large".into())
-            .map_err(|e| anyhow!("could not create response: {}", e))?);
+
+    let too_large = |size: usize| {
+        state
+            .metrics
+            .rejected_body_size
+            .get_or_create(&BodyRejected {
+                route: route.to_string(),
+            })
+            .observe(size as f64);
+
+        ApplicationError::PayloadTooLarge("Request body too large".to_string())
+    };
+
+    let declared_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if let Some(length) = declared_length
+        && length > limit
+    {
+        return Err(too_large(length));
+    }
+
+    let mut stream = body.into_data_stream();
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            ApplicationError::InvalidPayload(format!("Failed to read request body: {e}"))
+        })?;
+
+        if buffer.len() + chunk.len() > limit {
+            return Err(too_large(buffer.len() + chunk.len()));
+        }
+
+        buffer.extend_from_slice(&chunk);
     }
 
-    Ok(next.run(Request::from_parts(parts, body)).await)
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(buffer)))
+        .await)
 }
 
-pub async fn validate_content_type(
-    headers: HeaderMap,
+/// Lets a request through to the admin endpoints only if it carries the admin token as
+/// `Authorization: Bearer <token>`.
+pub async fn require_admin_token(
+    State(state): State<InternalState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApplicationError> {
+    let supplied = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match (supplied, state.admin_token.as_deref()) {
+        (Some(supplied), Some(token))
+            if constant_time_eq(supplied.as_bytes(), token.as_bytes()) =>
+        {
+            Ok(next.run(request).await)
+        }
+        _ => Err(ApplicationError::Unauthorized(
+            "Missing or invalid admin token".to_string(),
+        )),
+    }
+}
+
+/// Compares tokens without returning early, so the comparison takes as long for a wrong first
+/// byte as for a wrong last one.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (x, y)| difference | (x ^ y))
+            == 0
+}
+
+/// Throttles requests per client IP, taking a token from the client's bucket for every request.
+/// It runs before the body is read or decompressed, so a client over its limit cannot make the
+/// server do either.
+pub async fn rate_limit_ip(
+    State(state): State<AppState>,
+    request_context: RequestContext,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApplicationError> {
+    if let Some(client_ip) = request_context.client_ip
+        && let Err(retry_after) = state
+            .rate_limiter
+            .ip
+            .acquire(client_ip, 1.0, Instant::now())
+    {
+        return Err(throttle(&state.metrics, "ip", None, retry_after));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Throttles events per `appId`. Every event in a request's body takes a token from the bucket
+/// of its app, so a batch counts as many events as it contains. Only registered apps the
+/// request is authorized for have a bucket, so requests without a valid key can neither drain
+/// an app's bucket nor create buckets and metric labels for made-up apps; the handler rejects
+/// their events. Bodies that do not parse are left for the handler to reject, and bodies are
+/// buffered up to the limit of their route. A tracking pixel's event is read from its query
+/// string.
+pub async fn rate_limit_apps(
+    State(state): State<AppState>,
+    request_context: RequestContext,
     request: Request,
     next: Next,
 ) -> Result<Response, ApplicationError> {
-    let build_error_response = |status, msg: &str| {
-        let message = msg.to_string();
+    let now = Instant::now();
+
+    if !state.rate_limiter.app.is_enabled() {
+        return Ok(next.run(request).await);
+    }
 
-        Response::builder()
-            .status(status)
-            .body(message.into())
-            .map_err(|e| anyhow!("could not create response: {}", e))
+    let (parts, body) = request.into_parts();
+    let (_, limit) = state.body_limits.for_path(parts.uri.path());
+    let Ok(bytes) = to_bytes(body, limit).await else {
+        return Err(ApplicationError::PayloadTooLarge(
+            "Request body too large".to_string(),
+        ));
+    };
+
+    let body = String::from_utf8_lossy(&bytes);
+    let events: Vec<Value> = if parts.uri.path() == "/batch" {
+        split_batch(&body)
+            .map(|items| items.into_iter().filter_map(Result::ok).collect())
+            .unwrap_or_default()
+    } else if parts.method == Method::GET {
+        // Tracking pixels carry their event in the query string
+        Query::try_from_uri(&parts.uri)
+            .map(|Query(params)| event_from_query(params))
+            .into_iter()
+            .collect()
+    } else {
+        serde_json::from_str::<Value>(&body).into_iter().collect()
     };
 
-    let content_type = match headers.get(CONTENT_TYPE) {
-        Some(ct) => ct,
-        None => {
-            return Ok(build_error_response(
-                StatusCode::BAD_REQUEST,
-                "Missing Content-Type header",
-            )?);
+    for (app_id, count) in events_per_app(&events) {
+        if state.apps.get(&app_id).is_none()
+            || state.apps.authorize(&app_id, &request_context).is_err()
+        {
+            continue;
+        }
+
+        if let Err(retry_after) = state.rate_limiter.app.acquire(app_id.clone(), count, now) {
+            return Err(throttle(&state.metrics, "app", Some(app_id), retry_after));
         }
+    }
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+fn events_per_app(events: &[Value]) -> HashMap<String, f64> {
+    let mut counts = HashMap::new();
+
+    for app_id in events
+        .iter()
+        .filter_map(|event| event.get("appId").and_then(|v| v.as_str()))
+    {
+        *counts.entry(app_id.to_string()).or_default() += 1.0;
+    }
+
+    counts
+}
+
+fn throttle(
+    metrics: &Metrics,
+    limit: &str,
+    app_id: Option<String>,
+    retry_after: Duration,
+) -> ApplicationError {
+    let message = match &app_id {
+        Some(app_id) => format!("Too many events for appId '{app_id}'"),
+        None => "Too many requests".to_string(),
     };
 
-    let content_type = content_type
+    metrics
+        .throttled_requests
+        .get_or_create(&Throttled {
+            limit: limit.to_string(),
+            app_id,
+        })
+        .inc();
+
+    ApplicationError::TooManyRequests(message, retry_after)
+}
+
+pub async fn validate_content_type(
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApplicationError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .ok_or_else(|| ApplicationError::InvalidPayload("Missing Content-Type header".to_string()))?
         .to_str()
-        .map_err(|e| anyhow!("could not convert Content-Type header to string: {}", e))?;
+        .map_err(|_| ApplicationError::InvalidPayload("Invalid Content-Type header".to_string()))?;
 
     if !["application/json", "text/plain"]
         .iter()
@@ -58,10 +407,9 @@ pub async fn validate_content_type(
     {
         tracing::error!("Invalid Content-Type header: {}", content_type);
 
-        return Ok(build_error_response(
-            StatusCode::BAD_REQUEST,
-            "Invalid Content-Type header",
-        )?);
+        return Err(ApplicationError::InvalidPayload(
+            "Invalid Content-Type header".to_string(),
+        ));
     }
 
     Ok(next.run(request).await)
@@ -70,6 +418,7 @@ pub async fn validate_content_type(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rate_limit::{Quota, RateLimiter, TokenBuckets};
     use axum::{Router, body::Body, http::Request, middleware::from_fn, routing::get};
     use tower::ServiceExt;
 
@@ -89,6 +438,23 @@ mod tests {
         assert_eq!(response.status(), 400);
     }
 
+    #[tokio::test]
+    async fn test_missing_content_type_header_is_a_problem_document() {
+        let app = Router::new()
+            .route("/", get("OK"))
+            .layer(from_fn(validate_content_type));
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["detail"], "Missing Content-Type header");
+    }
+
     #[tokio::test]
     async fn test_content_type_header_invalid_type_returns_400() {
         let app = Router::new()
@@ -140,21 +506,552 @@ mod tests {
         assert_eq!(response.status(), 400);
     }
 
+    fn cors_app() -> Router {
+        let apps = AppRegistry::from_apps(vec![crate::apps::App {
+            id: "blog".to_string(),
+            key: "pk_blog".to_string(),
+            origins: vec!["https://example.com".to_string()],
+            redirect_secret: None,
+        }])
+        .unwrap();
+
+        Router::new().route("/", axum::routing::post("OK")).layer(
+            tower::ServiceBuilder::new()
+                .layer(cors(Arc::new(apps)))
+                .layer(from_fn(validate_content_type)),
+        )
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .method("OPTIONS")
+            .header("origin", origin)
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "content-type")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_from_registered_origin() {
+        let response = cors_app()
+            .oneshot(preflight("https://example.com"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://example.com"
+        );
+    }
+
     #[tokio::test]
-    async fn test_body_too_large_returns_413() {
+    async fn test_cors_preflight_from_unknown_origin_is_not_allowed() {
+        let response = cors_app()
+            .oneshot(preflight("https://evil.example"))
+            .await
+            .unwrap();
+
+        assert!(
+            response
+                .headers()
+                .get("access-control-allow-origin")
+                .is_none()
+        );
+    }
+
+    async fn test_state(rate_limiter: RateLimiter, body_limits: BodyLimits) -> AppState {
+        AppState {
+            rate_limiter: Arc::new(rate_limiter),
+            body_limits,
+            ..AppState::for_tests().await
+        }
+    }
+
+    async fn rate_limited_app(
+        rate_limiter: RateLimiter,
+        body_limits: BodyLimits,
+    ) -> (Router, Arc<Metrics>) {
+        let app = |id: &str| crate::apps::App {
+            id: id.to_string(),
+            key: "pk_test".to_string(),
+            origins: Vec::new(),
+            redirect_secret: None,
+        };
+        let state = AppState {
+            apps: Arc::new(AppRegistry::from_apps(vec![app("blog"), app("shop")]).unwrap()),
+            ..test_state(rate_limiter, body_limits).await
+        };
+        let metrics = state.metrics.clone();
+
         let app = Router::new()
-            .route("/", get("OK"))
-            .layer(from_fn(validate_body_length));
+            .route("/", axum::routing::post("OK"))
+            .route("/batch", axum::routing::post("OK"))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit_apps,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit_ip,
+            ))
+            .with_state(state);
+
+        (app, metrics)
+    }
+
+    async fn body_limited_app(body_limits: BodyLimits) -> (Router, Arc<Metrics>) {
+        let unlimited = RateLimiter {
+            ip: TokenBuckets::new(quota(0.0, 0.0)),
+            app: TokenBuckets::new(quota(0.0, 0.0)),
+        };
+        let state = test_state(unlimited, body_limits).await;
+        let metrics = state.metrics.clone();
+
+        let app = Router::new()
+            .route("/", axum::routing::post(|body: String| async move { body }))
+            .route(
+                "/batch",
+                axum::routing::post(|body: String| async move { body }),
+            )
+            .route(
+                "/{any}",
+                axum::routing::post(|body: String| async move { body }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                validate_body_length,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                decompress_body,
+            ))
+            .with_state(state);
+
+        (app, metrics)
+    }
+
+    fn encoded(metrics: &Metrics) -> String {
+        let mut registry = prometheus_client::registry::Registry::default();
+        metrics.register(&mut registry);
+
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &registry).unwrap();
+        buffer
+    }
+
+    fn event_request(uri: &str, body: &str) -> Request<Body> {
+        let mut request = Request::builder()
+            .uri(uri)
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(axum::extract::ConnectInfo(std::net::SocketAddr::from((
+                [203, 0, 113, 7],
+                443,
+            ))));
+        request
+    }
+
+    fn keyed(mut request: Request<Body>) -> Request<Body> {
+        request
+            .headers_mut()
+            .insert("x-api-key", "pk_test".parse().unwrap());
+        request
+    }
+
+    fn quota(per_second: f64, burst: f64) -> Quota {
+        Quota { per_second, burst }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_client_ip_returns_429_with_retry_after() {
+        let (app, metrics) = rate_limited_app(
+            RateLimiter {
+                ip: TokenBuckets::new(quota(1.0, 2.0)),
+                app: TokenBuckets::new(quota(0.0, 0.0)),
+            },
+            BodyLimits::default(),
+        )
+        .await;
+        let body = r#"{"appId": "blog"}"#;
+
+        for _ in 0..2 {
+            let response = app.clone().oneshot(event_request("/", body)).await.unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        let response = app.oneshot(event_request("/", body)).await.unwrap();
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()["retry-after"], "1");
+        assert_eq!(
+            metrics
+                .throttled_requests
+                .get_or_create(&Throttled {
+                    limit: "ip".to_string(),
+                    app_id: None,
+                })
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_counts_batch_events_per_app() {
+        let (app, metrics) = rate_limited_app(
+            RateLimiter {
+                ip: TokenBuckets::new(quota(0.0, 0.0)),
+                app: TokenBuckets::new(quota(1.0, 3.0)),
+            },
+            BodyLimits::default(),
+        )
+        .await;
+        let batch = "{\"appId\": \"blog\"}\n{\"appId\": \"blog\"}\n{\"appId\": \"shop\"}";
+
+        let response = app
+            .clone()
+            .oneshot(keyed(event_request("/batch", batch)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = app
+            .clone()
+            .oneshot(keyed(event_request("/batch", batch)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()["retry-after"], "1");
 
-        // Create a body larger than 1MB
-        let large_body = vec![b'a'; 1024 * 1024 + 1];
+        let response = app
+            .oneshot(keyed(event_request("/", r#"{"appId": "shop"}"#)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            metrics
+                .throttled_requests
+                .get_or_create(&Throttled {
+                    limit: "app".to_string(),
+                    app_id: Some("blog".to_string()),
+                })
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_skips_unauthorized_and_unknown_apps() {
+        let (app, metrics) = rate_limited_app(
+            RateLimiter {
+                ip: TokenBuckets::new(quota(0.0, 0.0)),
+                app: TokenBuckets::new(quota(1.0, 1.0)),
+            },
+            BodyLimits::default(),
+        )
+        .await;
+
+        // Neither a request without the key nor one for an unknown app uses up the bucket
+        for request in [
+            event_request("/", r#"{"appId": "blog"}"#),
+            event_request("/", r#"{"appId": "blog"}"#),
+            keyed(event_request("/", r#"{"appId": "unknown"}"#)),
+            keyed(event_request("/", r#"{"appId": "unknown"}"#)),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        let response = app
+            .oneshot(keyed(event_request("/", r#"{"appId": "blog"}"#)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(!encoded(&metrics).contains("unknown"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_reads_bodies_up_to_the_route_limit() {
+        let (app, metrics) = rate_limited_app(
+            RateLimiter {
+                ip: TokenBuckets::new(quota(0.0, 0.0)),
+                app: TokenBuckets::new(quota(1.0, 1.0)),
+            },
+            BodyLimits {
+                batch: 4 * 1024 * 1024,
+                ..BodyLimits::default()
+            },
+        )
+        .await;
+        let item = format!(r#"{{"appId": "blog", "name": "{}"}}"#, "x".repeat(1024));
+        let batch = vec![item.as_str(); 2 * 1024].join("\n");
+
+        // The first batch is counted rather than rejected as too large, so the second is throttled
+        for status in [200, 429] {
+            let response = app
+                .clone()
+                .oneshot(keyed(event_request("/batch", &batch)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+        assert_eq!(
+            metrics
+                .throttled_requests
+                .get_or_create(&Throttled {
+                    limit: "app".to_string(),
+                    app_id: Some("blog".to_string()),
+                })
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_over_declared_length_returns_413() {
+        let (app, metrics) = body_limited_app(BodyLimits::default()).await;
+
+        // Reading the body would fail the request with a 400, so it must be rejected unread
+        let chunks = tokio_stream::iter([Err::<Vec<u8>, _>(std::io::Error::other("read"))]);
         let request = Request::builder()
             .uri("/")
-            .method("GET")
-            .body(Body::from(large_body))
+            .method("POST")
+            .header(CONTENT_LENGTH, 2048)
+            .body(Body::from_stream(chunks))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 413);
+        let encoded = encoded(&metrics);
+        assert!(encoded.contains(r#"rejected_body_size_bytes_sum{route="event"} 2048.0"#));
+        assert!(encoded.contains(r#"rejected_body_size_bytes_count{route="event"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_single_chunk_body_over_limit_without_length_returns_413() {
+        let (app, metrics) = body_limited_app(BodyLimits::default()).await;
+
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .body(Body::from(vec![b'a'; 1025]))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
         assert_eq!(response.status(), 413);
+        let encoded = encoded(&metrics);
+        assert!(encoded.contains(r#"rejected_body_size_bytes_sum{route="event"} 1025.0"#));
+        assert!(encoded.contains(r#"rejected_body_size_bytes_count{route="event"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body_over_limit_returns_413() {
+        let (app, metrics) = body_limited_app(BodyLimits::default()).await;
+
+        // A streamed body has no `Content-Length`, so only the bytes received reveal its size
+        let chunks = (0..4).map(|_| Ok::<_, std::io::Error>(vec![b'a'; 512]));
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .body(Body::from_stream(tokio_stream::iter(chunks)))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 413);
+        assert!(
+            encoded(&metrics).contains(r#"rejected_body_size_bytes_sum{route="event"} 1536.0"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_limit_depends_on_route() {
+        let (app, metrics) = body_limited_app(BodyLimits {
+            event: 16,
+            batch: 64,
+            ..BodyLimits::default()
+        })
+        .await;
+        let body = r#"{"appId": "blog", "entity": "page"}"#;
+
+        let response = app
+            .clone()
+            .oneshot(event_request("/batch", body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let echoed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(echoed, body.as_bytes());
+
+        let response = app.oneshot(event_request("/", body)).await.unwrap();
+        assert_eq!(response.status(), 413);
+        assert!(!encoded(&metrics).contains(r#"route="batch""#));
+    }
+
+    fn compress(encoding: &str, body: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+
+        match encoding {
+            "gzip" => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+            "deflate" => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+            "br" => {
+                let mut compressed = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                encoder.write_all(body).unwrap();
+                drop(encoder);
+                compressed
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn compressed_request(uri: &str, encoding: &str, body: &[u8]) -> Request<Body> {
+        let body = compress(encoding, body);
+
+        Request::builder()
+            .uri(uri)
+            .method("POST")
+            .header(CONTENT_ENCODING, encoding)
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_compressed_bodies_are_decompressed() {
+        let (app, _) = body_limited_app(BodyLimits::default()).await;
+        let body = r#"{"appId": "blog", "entity": "page", "action": "view"}"#.repeat(20);
+
+        for encoding in ["gzip", "deflate", "br"] {
+            let response = app
+                .clone()
+                .oneshot(compressed_request("/batch", encoding, body.as_bytes()))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200, "{encoding}");
+
+            let echoed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(echoed, body.as_bytes(), "{encoding}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_limit_applies_to_decompressed_size() {
+        let (app, metrics) = body_limited_app(BodyLimits::default()).await;
+        let body = vec![b'a'; 4096];
+
+        let response = app
+            .oneshot(compressed_request("/", "gzip", &body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 413);
+        assert!(
+            encoded(&metrics).contains(r#"rejected_body_size_bytes_sum{route="event"} 4096.0"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decompression_stops_at_cap() {
+        let (app, _) = body_limited_app(BodyLimits {
+            decompressed: 64 * 1024,
+            ..BodyLimits::default()
+        })
+        .await;
+        // A megabyte of zeros compresses to about a kilobyte
+        let body = vec![0; 1024 * 1024];
+
+        let response = app
+            .oneshot(compressed_request("/batch", "gzip", &body))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 413);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_body_over_route_limit_is_not_read_in_full() {
+        let (app, _) = body_limited_app(BodyLimits::default()).await;
+        let polled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        // Not valid gzip, so reading all of it would end in a 400 rather than a 413
+        let chunks = {
+            let polled = polled.clone();
+            tokio_stream::iter(0..8).map(move |_| {
+                polled.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok::<_, std::io::Error>(vec![b'a'; 512])
+            })
+        };
+        let request = Request::builder()
+            .uri("/event")
+            .method("POST")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from_stream(chunks))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 413);
+        assert!(polled.load(std::sync::atomic::Ordering::SeqCst) < 8);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_client_ip_comes_before_decompression() {
+        let state = test_state(
+            RateLimiter {
+                ip: TokenBuckets::new(quota(1.0, 1.0)),
+                app: TokenBuckets::new(quota(0.0, 0.0)),
+            },
+            BodyLimits::default(),
+        )
+        .await;
+        let app = Router::new()
+            .route("/", axum::routing::post("OK"))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                decompress_body,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit_ip,
+            ))
+            .with_state(state);
+
+        // The second corrupt body is throttled before anything tries to decompress it
+        for status in [400, 429] {
+            let mut request = event_request("/", "not gzip");
+            request
+                .headers_mut()
+                .insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_content_encoding_returns_415() {
+        let (app, _) = body_limited_app(BodyLimits::default()).await;
+
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .header(CONTENT_ENCODING, "zstd")
+            .body(Body::from("{}"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 415);
     }
 }
@@ -0,0 +1,234 @@
+//! Token bucket rate limiting per client IP and per `appId`.
+//!
+//! Every key gets a bucket that holds up to `burst` tokens and refills at `per_second` tokens
+//! per second. A request takes one token from its client's bucket and one token per event
+//! from its app's bucket. Buckets only live in memory, so each replica enforces its own limits.
+
+use crate::utilities::get_environment_variable_with_default;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How often buckets that have refilled since their last use are dropped.
+const IDLE_BUCKET_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Quota {
+    /// Reads `<prefix>_PER_SECOND` and `<prefix>_BURST`. A rate of zero disables the limit.
+    fn from_environment(prefix: &str, default: Quota) -> Self {
+        let read = |name: &str, default: f64| {
+            get_environment_variable_with_default(&format!("{prefix}_{name}"), default.to_string())
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite() && *value >= 0.0)
+                .unwrap_or(default)
+        };
+
+        Self {
+            per_second: read("PER_SECOND", default.per_second),
+            burst: read("BURST", default.burst),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct TokenBuckets<K> {
+    quota: Quota,
+    state: Mutex<BucketState<K>>,
+}
+
+#[derive(Debug)]
+struct BucketState<K> {
+    buckets: HashMap<K, Bucket>,
+    last_sweep_at: Option<Instant>,
+}
+
+impl<K: Eq + Hash> TokenBuckets<K> {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            state: Mutex::new(BucketState {
+                buckets: HashMap::new(),
+                last_sweep_at: None,
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.quota.per_second > 0.0
+    }
+
+    /// Takes `tokens` from the key's bucket, or returns how long until enough tokens are
+    /// available. Requests for more tokens than the burst wait for a full bucket.
+    pub fn acquire(&self, key: K, tokens: f64, now: Instant) -> Result<(), Duration> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        self.sweep(&mut state, now);
+
+        let bucket = state.buckets.entry(key).or_insert(Bucket {
+            tokens: self.quota.burst,
+            updated_at: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.quota.per_second).min(self.quota.burst);
+        bucket.updated_at = now;
+
+        let needed = tokens.min(self.quota.burst);
+        if bucket.tokens >= needed {
+            bucket.tokens -= needed;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64(
+            (needed - bucket.tokens) / self.quota.per_second,
+        ))
+    }
+
+    fn sweep(&self, state: &mut BucketState<K>, now: Instant) {
+        if state.last_sweep_at.is_some_and(|last_sweep_at| {
+            now.duration_since(last_sweep_at) < IDLE_BUCKET_SWEEP_INTERVAL
+        }) {
+            return;
+        }
+
+        let time_to_fill = Duration::from_secs_f64(self.quota.burst / self.quota.per_second);
+        state
+            .buckets
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated_at) < time_to_fill);
+        state.last_sweep_at = Some(now);
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    pub ip: TokenBuckets<IpAddr>,
+    pub app: TokenBuckets<String>,
+}
+
+const DEFAULT_IP_QUOTA: Quota = Quota {
+    per_second: 20.0,
+    burst: 100.0,
+};
+
+const DEFAULT_APP_QUOTA: Quota = Quota {
+    per_second: 500.0,
+    burst: 2000.0,
+};
+
+impl RateLimiter {
+    /// Reads `RATE_LIMIT_IP_PER_SECOND`, `RATE_LIMIT_IP_BURST`, `RATE_LIMIT_APP_PER_SECOND` and
+    /// `RATE_LIMIT_APP_BURST`.
+    pub fn build() -> Self {
+        Self {
+            ip: TokenBuckets::new(Quota::from_environment("RATE_LIMIT_IP", DEFAULT_IP_QUOTA)),
+            app: TokenBuckets::new(Quota::from_environment("RATE_LIMIT_APP", DEFAULT_APP_QUOTA)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buckets(per_second: f64, burst: f64) -> TokenBuckets<&'static str> {
+        TokenBuckets::new(Quota { per_second, burst })
+    }
+
+    #[test]
+    fn test_acquire_allows_burst_then_throttles() {
+        let buckets = buckets(1.0, 3.0);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(buckets.acquire("client", 1.0, now).is_ok());
+        }
+
+        let retry_after = buckets.acquire("client", 1.0, now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_acquire_refills_over_time() {
+        let buckets = buckets(2.0, 2.0);
+        let now = Instant::now();
+
+        assert!(buckets.acquire("client", 2.0, now).is_ok());
+        assert!(buckets.acquire("client", 1.0, now).is_err());
+        assert!(
+            buckets
+                .acquire("client", 1.0, now + Duration::from_millis(500))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_acquire_keeps_keys_separate() {
+        let buckets = buckets(1.0, 1.0);
+        let now = Instant::now();
+
+        assert!(buckets.acquire("first", 1.0, now).is_ok());
+        assert!(buckets.acquire("second", 1.0, now).is_ok());
+        assert!(buckets.acquire("first", 1.0, now).is_err());
+    }
+
+    #[test]
+    fn test_acquire_more_than_burst_waits_for_full_bucket() {
+        let buckets = buckets(10.0, 5.0);
+        let now = Instant::now();
+
+        assert!(buckets.acquire("app", 50.0, now).is_ok());
+        assert_eq!(
+            buckets.acquire("app", 50.0, now).unwrap_err(),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn test_disabled_buckets_never_throttle() {
+        let buckets = buckets(0.0, 0.0);
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert!(buckets.acquire("client", 1.0, now).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_sweep_drops_refilled_buckets() {
+        let buckets = buckets(1.0, 1.0);
+        let now = Instant::now();
+
+        buckets.acquire("idle", 1.0, now).unwrap();
+        buckets
+            .acquire("active", 1.0, now + IDLE_BUCKET_SWEEP_INTERVAL)
+            .unwrap();
+
+        let state = buckets.state.lock().unwrap();
+        assert_eq!(state.buckets.len(), 1);
+        assert!(state.buckets.contains_key("active"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Signed redirects for counting clicks on outbound and email links.
+//!
+//! A link to `/r` carries its destination and an HMAC-SHA256 signature of the app id, entity
+//! and destination, keyed with the app's `redirect_secret`. Only links signed by the app's own
+//! backend are followed, so the endpoint cannot be used as an open redirect. Backends sign their
+//! links with [`sign`].
+
+use crate::errors::ApplicationError;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The entities a redirect may record a click for.
+const ENTITIES: [&str; 2] = ["anchor", "email"];
+
+/// The query parameters of a redirect link.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Redirect {
+    #[serde(rename = "appId")]
+    pub app_id: String,
+    #[serde(default = "default_entity")]
+    pub entity: String,
+    /// The destination, an absolute `http` or `https` URL.
+    pub url: String,
+    /// Hex-encoded signature.
+    #[serde(default)]
+    pub sig: String,
+}
+
+fn default_entity() -> String {
+    "anchor".to_string()
+}
+
+impl Redirect {
+    /// Signs a redirect to `url`, for a backend to put into a page or an email.
+    pub fn signed(
+        secret: &str,
+        app_id: &str,
+        entity: &str,
+        url: &str,
+    ) -> Result<Self, ApplicationError> {
+        let mut redirect = Self {
+            app_id: app_id.to_string(),
+            entity: entity.to_string(),
+            url: url.to_string(),
+            sig: String::new(),
+        };
+        redirect.validate()?;
+        redirect.sig = encode_hex(&redirect.mac(secret).finalize().into_bytes());
+
+        Ok(redirect)
+    }
+
+    pub fn validate(&self) -> Result<(), ApplicationError> {
+        if !ENTITIES.contains(&self.entity.as_str()) {
+            return Err(ApplicationError::InvalidPayload(format!(
+                "Redirects can only record clicks on {}",
+                ENTITIES.join(" or ")
+            )));
+        }
+
+        match Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+            _ => Err(ApplicationError::InvalidPayload(
+                "Redirects must point to an absolute http or https URL".to_string(),
+            )),
+        }
+    }
+
+    /// Checks the signature in constant time.
+    pub fn verify(&self, secret: &str) -> bool {
+        decode_hex(&self.sig).is_some_and(|sig| self.mac(secret).verify_slice(&sig).is_ok())
+    }
+
+    fn mac(&self, secret: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+
+        // The entity cannot contain a newline, so the fields cannot be shifted into each other
+        for field in [&self.app_id, &self.entity, &self.url] {
+            mac.update(field.as_bytes());
+            mac.update(b"\n");
+        }
+
+        mac
+    }
+
+    /// The path and query of the link, to be appended to the collector's external URL.
+    pub fn path(&self) -> String {
+        format!(
+            "/r?appId={}&entity={}&url={}&sig={}",
+            urlencoding::encode(&self.app_id),
+            urlencoding::encode(&self.entity),
+            urlencoding::encode(&self.url),
+            self.sig
+        )
+    }
+
+    /// The click event recorded for the redirect, with the destination's host and path as its
+    /// `path`. The query and fragment are left out, since they may carry personal data such as
+    /// tokens or email addresses.
+    pub fn event(&self) -> Value {
+        let path = Url::parse(&self.url)
+            .map(|url| format!("{}{}", url.host_str().unwrap_or_default(), url.path()))
+            .unwrap_or_default();
+
+        json!({
+            "entity": self.entity,
+            "action": "click",
+            "path": path,
+            "appId": self.app_id,
+        })
+    }
+}
+
+/// Returns the path and query of a link to `url` that records a click on `entity` for the app,
+/// signed with its `redirect_secret`, to be appended to the collector's external URL.
+pub fn sign(
+    secret: &str,
+    app_id: &str,
+    entity: &str,
+    url: &str,
+) -> Result<String, ApplicationError> {
+    Redirect::signed(secret, app_id, entity, url).map(|redirect| redirect.path())
+}
+
+/// Prints a signed redirect path for `sign-redirect <appId> <entity> <url>`, reading the app's
+/// secret from `REDIRECT_SECRET`, and returns whether it succeeded.
+pub fn sign_from_arguments(arguments: &[String]) -> bool {
+    let [app_id, entity, url] = arguments else {
+        eprintln!("usage: analytics-collector sign-redirect <appId> <anchor|email> <url>");
+        return false;
+    };
+
+    let Ok(secret) = std::env::var("REDIRECT_SECRET") else {
+        eprintln!("REDIRECT_SECRET must be set to the app's redirect secret");
+        return false;
+    };
+
+    match sign(&secret, app_id, entity, url) {
+        Ok(path) => {
+            println!("{path}");
+            true
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            false
+        }
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Query, http::Uri};
+
+    const SECRET: &str = "s3cret";
+
+    #[test]
+    fn test_signed_redirect_round_trips_through_its_path() {
+        let redirect = Redirect::signed(
+            SECRET,
+            "blog",
+            "email",
+            "https://example.com/pricing?plan=pro&utm_source=newsletter",
+        )
+        .unwrap();
+
+        let uri: Uri = redirect.path().parse().unwrap();
+        let Query(parsed) = Query::<Redirect>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(parsed, redirect);
+        assert!(parsed.verify(SECRET));
+        assert!(!parsed.verify("other secret"));
+    }
+
+    #[test]
+    fn test_tampered_redirects_do_not_verify() {
+        let redirect = Redirect::signed(SECRET, "blog", "anchor", "https://example.com/").unwrap();
+
+        let other_url = Redirect {
+            url: "https://attacker.example/".to_string(),
+            ..redirect.clone()
+        };
+        let other_entity = Redirect {
+            entity: "email".to_string(),
+            ..redirect.clone()
+        };
+        let unsigned = Redirect {
+            sig: String::new(),
+            ..redirect.clone()
+        };
+        let malformed = Redirect {
+            sig: "zz".to_string(),
+            ..redirect
+        };
+
+        for redirect in [other_url, other_entity, unsigned, malformed] {
+            assert!(!redirect.verify(SECRET), "{redirect:?}");
+        }
+    }
+
+    #[test]
+    fn test_click_event_records_the_destinations_host_and_path() {
+        let redirect = Redirect::signed(
+            SECRET,
+            "blog",
+            "email",
+            "https://example.com/pricing?email=someone%40example.com#plans",
+        )
+        .unwrap();
+
+        assert_eq!(redirect.event()["path"], "example.com/pricing");
+        assert_eq!(redirect.event()["entity"], "email");
+    }
+
+    #[test]
+    fn test_sign_returns_the_signed_path() {
+        let path = sign(SECRET, "blog", "anchor", "https://example.com/").unwrap();
+        let Query(redirect) = Query::<Redirect>::try_from_uri(&path.parse().unwrap()).unwrap();
+
+        assert!(redirect.verify(SECRET));
+    }
+
+    #[test]
+    fn test_only_web_destinations_and_click_entities_are_signed() {
+        assert!(Redirect::signed(SECRET, "blog", "anchor", "javascript:alert(1)").is_err());
+        assert!(Redirect::signed(SECRET, "blog", "anchor", "/relative").is_err());
+        assert!(Redirect::signed(SECRET, "blog", "page", "https://example.com/").is_err());
+    }
+}
@@ -1,58 +1,578 @@
 use crate::{
-    AppState,
+    AppState, InternalState,
+    enrichment::RequestContext,
     errors::ApplicationError,
-    exporter::{self, Exporter},
-    utilities::generate_uuid_v4,
+    exporter::{self, Exporter, prometheus::DeadLettered},
+    ingest::{
+        BatchReport, MAX_BATCH_ITEMS, Prepared, event_from_query, in_flight, prepare_event,
+        split_batch,
+    },
+    redirects::Redirect,
+    storage::{dead_letters::DeadLetter, memory::PendingEvent},
 };
-use axum::{extract::State, http::StatusCode, response::IntoResponse};
-use chrono::Utc;
-use libsql::{Connection, params};
-use std::sync::Arc;
-use tracing::{Instrument, info_span};
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::{
+        StatusCode, Uri,
+        header::{CACHE_CONTROL, CONTENT_TYPE, EXPIRES, LOCATION, PRAGMA},
+    },
+    response::IntoResponse,
+};
+use serde_json::Value;
+use tracing::{Instrument, error, info_span};
 
 pub async fn post_event(
     State(state): State<AppState>,
+    request: RequestContext,
     payload: String,
 ) -> Result<impl IntoResponse, ApplicationError> {
-    let json_payload = serde_json::from_str(&payload)
-        .map_err(|e| ApplicationError::InvalidPayload(e.to_string()))?;
-
-    state
-        .validator
-        .validate(&json_payload)
-        .map_err(|e| ApplicationError::InvalidPayload(e.to_string()))?;
-
-    let recorded_by = json_payload
-        .get("appId")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| {
-            ApplicationError::InvalidPayload("Missing 'recorded_by' field".to_string())
-        })?;
-
-    state
-        .connection
-        .execute(
-            "INSERT INTO events (id, recorded_at, recorded_by, event) VALUES (?1, ?2, ?3, json(?4))",
-            params!(
-                generate_uuid_v4(),
-                Utc::now().to_rfc3339(),
-                recorded_by,
-                payload
+    ingest_event(&state, &request, payload).await?;
+
+    Ok((StatusCode::ACCEPTED, String::new()))
+}
+
+/// A transparent 1x1 GIF.
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// Records an event sent as the query parameters of an image request, for pages that cannot
+/// run JavaScript. The image is returned whatever happens to the event, since nothing would
+/// show an error, and must never be cached or later views would not be requested.
+pub async fn get_pixel(
+    State(state): State<AppState>,
+    request: RequestContext,
+    uri: Uri,
+) -> impl IntoResponse {
+    let params = Query::<Vec<(String, String)>>::try_from_uri(&uri)
+        .map(|Query(params)| params)
+        .unwrap_or_default();
+
+    if let Err(e) = ingest_event(&state, &request, event_from_query(params).to_string()).await {
+        error!("failed to ingest pixel event: {e}");
+    }
+
+    (
+        [
+            (CONTENT_TYPE, "image/gif"),
+            (
+                CACHE_CONTROL,
+                "no-cache, no-store, must-revalidate, private",
             ),
-        )
-        .instrument(info_span!("insert_event"))
+            (PRAGMA, "no-cache"),
+            (EXPIRES, "0"),
+        ],
+        PIXEL,
+    )
+}
+
+/// Records a click on a signed outbound or email link and redirects to its destination. Links
+/// that are not signed by the app are rejected, so that only the app's own destinations are
+/// redirected to.
+pub async fn get_redirect(
+    State(state): State<AppState>,
+    request: RequestContext,
+    uri: Uri,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let Query(redirect) = Query::<Redirect>::try_from_uri(&uri)
+        .map_err(|e| ApplicationError::InvalidPayload(e.body_text()))?;
+
+    redirect.validate()?;
+    let app = state.apps.verify_redirect(&redirect)?;
+
+    // The signature authenticates the link in place of the key, and the page the link was
+    // clicked on, such as a webmail client, is not one of the app's origins.
+    let request = RequestContext {
+        api_key: Some(app.key.clone()),
+        origin: None,
+        referer: None,
+        ..request
+    };
+
+    if let Err(e) = ingest_event(&state, &request, redirect.event().to_string()).await {
+        error!("failed to ingest redirect click: {e}");
+    }
+
+    Ok((
+        StatusCode::FOUND,
+        [
+            (LOCATION, redirect.url),
+            (CACHE_CONTROL, "no-store".to_string()),
+        ],
+    ))
+}
+
+/// Validates and stores a single event, keeping it as a dead letter if it is rejected.
+async fn ingest_event(
+    state: &AppState,
+    request: &RequestContext,
+    payload: String,
+) -> Result<(), ApplicationError> {
+    let prepared = serde_json::from_str(&payload)
+        .map_err(|e| ApplicationError::InvalidPayload(e.to_string()))
+        .and_then(|json_payload: Value| {
+            prepare_event(
+                state,
+                request,
+                &json_payload,
+                request.idempotency_key.as_deref(),
+            )
+        });
+
+    let prepared = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            if let Some(letter) = dead_letter(state, &e, payload, request) {
+                store_dead_letters(state, vec![letter]).await;
+            }
+            return Err(e);
+        }
+    };
+
+    // Duplicates and dropped bot events are acknowledged like any other, so clients stop
+    // retrying and crawlers see no difference.
+    match prepared {
+        Prepared::Event(event) => {
+            store_events(state, vec![*event])
+                .instrument(info_span!("insert_event"))
+                .await?;
+        }
+        Prepared::InFlight(_) => return Err(in_flight()),
+        Prepared::Duplicate(_) | Prepared::Filtered => {}
+    }
+
+    Ok(())
+}
+
+/// Hands events to the buffer's writer. Their ids only count as duplicates once the events are
+/// committed, and are released if they are not stored so the client's retry is accepted.
+pub async fn store_events(
+    state: &AppState,
+    events: Vec<PendingEvent>,
+) -> Result<u64, ApplicationError> {
+    let pending = state
+        .event_ids
+        .pending(events.iter().map(|event| event.id.clone()).collect());
+
+    let stored = state.writer.write(events).await?;
+    pending.commit();
+
+    Ok(stored)
+}
+
+/// Keeps a rejected payload as a dead letter. Payloads that cannot even be parsed are kept only
+/// if the request carries a registered app's key, since there is no `appId` to authorize.
+fn dead_letter(
+    state: &AppState,
+    error: &ApplicationError,
+    payload: String,
+    request: &RequestContext,
+) -> Option<DeadLetter> {
+    if !state.apps.authenticates(request) {
+        return None;
+    }
+
+    DeadLetter::from_error(error, payload, request)
+}
+
+/// Keeps rejected payloads for inspection and replay. Losing them only costs the ability to
+/// replay, so a failure is logged rather than failing the request.
+async fn store_dead_letters(state: &AppState, letters: Vec<DeadLetter>) {
+    for letter in &letters {
+        state
+            .metrics
+            .dead_letters
+            .get_or_create(&DeadLettered {
+                reason: letter.reason.clone(),
+            })
+            .inc();
+    }
+
+    if let Err(e) = state
+        .dead_letters
+        .insert(&state.connection, &letters)
+        .instrument(info_span!("insert_dead_letters", count = letters.len()))
+        .await
+    {
+        error!("failed to store {} dead letters: {e}", letters.len());
+    }
+}
+
+pub async fn post_batch(
+    State(state): State<AppState>,
+    request: RequestContext,
+    payload: String,
+) -> Result<impl IntoResponse, ApplicationError> {
+    let items = match split_batch(&payload) {
+        Ok(items) => items,
+        Err(e) => {
+            if let Some(letter) = dead_letter(&state, &e, payload, &request) {
+                store_dead_letters(&state, vec![letter]).await;
+            }
+            return Err(e);
+        }
+    };
+
+    if items.is_empty() {
+        return Err(ApplicationError::InvalidPayload(
+            "Batch contains no events".to_string(),
+        ));
+    }
+
+    if items.len() > MAX_BATCH_ITEMS {
+        return Err(ApplicationError::PayloadTooLarge(format!(
+            "Batches can contain at most {MAX_BATCH_ITEMS} events"
+        )));
+    }
+
+    let mut report = BatchReport::default();
+    let mut events = Vec::new();
+    let mut letters = Vec::new();
+
+    for (index, item) in items.into_iter().enumerate() {
+        // A retried batch repeats its idempotency key, so each item's position extends it.
+        let idempotency_key = request
+            .idempotency_key
+            .as_ref()
+            .map(|key| format!("{key}:{index}"));
+
+        let (error, raw) = match item {
+            Ok(value) => {
+                match prepare_event(&state, &request, &value, idempotency_key.as_deref()) {
+                    Ok(Prepared::Event(event)) => {
+                        report.accept(index, event.id.clone());
+                        events.push(*event);
+                        continue;
+                    }
+                    Ok(Prepared::Duplicate(id)) => {
+                        report.accept(index, id);
+                        continue;
+                    }
+                    // The same id earlier in this batch is stored along with it
+                    Ok(Prepared::InFlight(id)) if events.iter().any(|event| event.id == id) => {
+                        report.accept(index, id);
+                        continue;
+                    }
+                    Ok(Prepared::InFlight(_)) => {
+                        // The whole batch is retried, so the ids it claimed are released
+                        state
+                            .event_ids
+                            .release(events.iter().map(|event| event.id.as_str()));
+                        return Err(in_flight());
+                    }
+                    Ok(Prepared::Filtered) => {
+                        report.filter(index);
+                        continue;
+                    }
+                    Err(e) => (e, value.to_string()),
+                }
+            }
+            Err(item) => (ApplicationError::InvalidPayload(item.error), item.raw),
+        };
+
+        report.reject(index, error.to_string(), error.validation_issues().to_vec());
+
+        // The item is replayed on its own, so it keeps its own idempotency key.
+        if let Some(mut letter) = dead_letter(&state, &error, raw, &request) {
+            letter.headers.idempotency_key = idempotency_key;
+            letters.push(letter);
+        }
+    }
+
+    store_dead_letters(&state, letters).await;
+
+    let count = events.len();
+    store_events(&state, events)
+        .instrument(info_span!("insert_events", count))
         .await?;
 
-    Ok((StatusCode::ACCEPTED, String::new()))
+    Ok((StatusCode::ACCEPTED, Json(report)))
 }
 
 pub async fn get_metrics(
-    State((connection, instance_id)): State<(Arc<Connection>, String)>,
+    State(state): State<InternalState>,
 ) -> Result<impl IntoResponse, ApplicationError> {
     let mut exporter = exporter::prometheus::PrometheusExporter {
         buffer: &mut String::new(),
-        instance_id,
+        instance_id: state.instance_id,
+        metrics: state.app.metrics,
     };
-    exporter.publish(connection).await?;
+    exporter.publish(state.app.connection).await?;
     Ok((StatusCode::OK, exporter.buffer.clone()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apps::{App, AppRegistry};
+    use axum::{Router, body::Body, body::to_bytes, http::Request, routing::get};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    async fn count(state: &AppState, table: &str) -> i64 {
+        let mut rows = state
+            .connection
+            .query(&format!("SELECT count(*) FROM {table}"), ())
+            .await
+            .unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    async fn request_pixel(state: &AppState, uri: &str) -> axum::response::Response {
+        Router::new()
+            .route("/pixel.gif", get(get_pixel))
+            .with_state(state.clone())
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pixel_stores_event_from_query() {
+        let state = AppState::for_tests().await;
+
+        let response = request_pixel(
+            &state,
+            "/pixel.gif?entity=custom&action=track&name=subscribe&appId=blog&path=%2Ffeed&props%5Bsource%5D=rss",
+        )
+        .await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/gif");
+        assert_eq!(
+            response.headers()[CACHE_CONTROL],
+            "no-cache, no-store, must-revalidate, private"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, PIXEL);
+
+        let mut rows = state
+            .connection
+            .query("SELECT event FROM events", ())
+            .await
+            .unwrap();
+        let event: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        let event: Value = serde_json::from_str(&event).unwrap();
+        assert_eq!(event["path"], "/feed");
+        assert_eq!(event["props"]["source"], "rss");
+    }
+
+    #[tokio::test]
+    async fn test_event_ids_are_deduplicated_per_app() {
+        let state = AppState::for_tests().await;
+
+        for app_id in ["blog", "blog", "shop"] {
+            let uri = format!("/pixel.gif?entity=page&action=view&appId={app_id}&eventId=event-1");
+            assert_eq!(request_pixel(&state, &uri).await.status(), 200);
+        }
+
+        let mut rows = state
+            .connection
+            .query("SELECT id, recorded_by FROM events ORDER BY sequence", ())
+            .await
+            .unwrap();
+        let mut events = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            events.push((row.get::<String>(0).unwrap(), row.get::<String>(1).unwrap()));
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                (
+                    crate::idempotency::event_id("blog", "event-1"),
+                    "blog".to_string()
+                ),
+                (
+                    crate::idempotency::event_id("shop", "event-1"),
+                    "shop".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_of_events_in_flight_are_asked_to_come_back() {
+        let state = AppState::for_tests().await;
+        let post = |state: &AppState| {
+            Router::new()
+                .route("/", axum::routing::post(post_event))
+                .with_state(state.clone())
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/")
+                        .body(Body::from(
+                            r#"{"entity":"page","action":"view","appId":"blog","eventId":"event-1"}"#,
+                        ))
+                        .unwrap(),
+                )
+        };
+
+        // An earlier request claimed the id and is still storing its event
+        let id = crate::idempotency::event_id("blog", "event-1");
+        state.event_ids.claim(&id, chrono::Utc::now());
+        let pending = state.event_ids.pending(vec![id]);
+
+        let response = post(&state).await.unwrap();
+        assert_eq!(response.status(), 409);
+        assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], "1");
+
+        // The earlier request failed, so the retry is stored
+        drop(pending);
+        assert_eq!(post(&state).await.unwrap().status(), 202);
+        assert_eq!(count(&state, "events").await, 1);
+
+        // Once it is stored, further retries are duplicates
+        assert_eq!(post(&state).await.unwrap().status(), 202);
+        assert_eq!(count(&state, "events").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bot_filter_decides_device_class() {
+        let state = AppState::for_tests().await;
+
+        for user_agent in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "curl/8.6.0",
+        ] {
+            let response = Router::new()
+                .route("/pixel.gif", get(get_pixel))
+                .with_state(state.clone())
+                .oneshot(
+                    Request::builder()
+                        .uri("/pixel.gif?entity=page&action=view&appId=blog")
+                        .header("user-agent", user_agent)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        let mut rows = state
+            .connection
+            .query("SELECT context FROM events ORDER BY sequence", ())
+            .await
+            .unwrap();
+        let mut contexts = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            contexts.push(serde_json::from_str::<Value>(&row.get::<String>(0).unwrap()).unwrap());
+        }
+
+        // HTTP libraries are used by server-side clients, so neither path counts them as bots
+        assert_eq!(contexts[0]["device"], "bot");
+        assert_eq!(contexts[0]["is_bot"], true);
+        assert_eq!(contexts[1]["device"], "desktop");
+        assert_eq!(contexts[1]["is_bot"], false);
+    }
+
+    async fn request_redirect(state: &AppState, path: &str) -> axum::response::Response {
+        Router::new()
+            .route("/r", get(get_redirect))
+            .with_state(state.clone())
+            .oneshot(Request::builder().uri(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_signed_redirect_records_click() {
+        let state = AppState {
+            apps: Arc::new(
+                AppRegistry::from_apps(vec![App {
+                    id: "blog".to_string(),
+                    key: "pk_blog".to_string(),
+                    origins: vec!["https://example.com".to_string()],
+                    redirect_secret: Some("s3cret".to_string()),
+                }])
+                .unwrap(),
+            ),
+            ..AppState::for_tests().await
+        };
+        let destination = "https://example.org/pricing?plan=pro";
+        let redirect = Redirect::signed("s3cret", "blog", "email", destination).unwrap();
+
+        let response = request_redirect(&state, &redirect.path()).await;
+        assert_eq!(response.status(), 302);
+        assert_eq!(response.headers()[LOCATION], destination);
+        assert_eq!(count(&state, "events").await, 1);
+
+        let tampered = redirect.path().replace("example.org", "attacker.example");
+        let response = request_redirect(&state, &tampered).await;
+        assert_eq!(response.status(), 403);
+        assert!(response.headers().get(LOCATION).is_none());
+
+        let response =
+            request_redirect(&state, "/r?appId=blog&url=https%3A%2F%2Fexample.org").await;
+        assert_eq!(response.status(), 403);
+        assert_eq!(count(&state, "events").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_over_item_limit_returns_413() {
+        let state = AppState::for_tests().await;
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+        let body = vec![event; MAX_BATCH_ITEMS + 1].join("\n");
+
+        let response = Router::new()
+            .route("/batch", axum::routing::post(post_batch))
+            .with_state(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/batch")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 413);
+        assert_eq!(count(&state, "events").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_only_authorized_requests_are_dead_lettered() {
+        let state = AppState {
+            apps: Arc::new(
+                AppRegistry::from_apps(vec![App {
+                    id: "blog".to_string(),
+                    key: "pk_blog".to_string(),
+                    origins: Vec::new(),
+                    redirect_secret: None,
+                }])
+                .unwrap(),
+            ),
+            ..AppState::for_tests().await
+        };
+
+        for uri in [
+            "/pixel.gif?entity=page&appId=blog",
+            "/pixel.gif?entity=page&appId=blog&key=pk_shop",
+            "/pixel.gif?entity=page&appId=shop&key=pk_blog",
+        ] {
+            assert_eq!(request_pixel(&state, uri).await.status(), 200);
+        }
+        assert_eq!(count(&state, "dead_letters").await, 0);
+
+        request_pixel(&state, "/pixel.gif?entity=page&appId=blog&key=pk_blog").await;
+        assert_eq!(count(&state, "dead_letters").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_pixel_returns_image_for_invalid_event() {
+        let state = AppState::for_tests().await;
+
+        let response = request_pixel(&state, "/pixel.gif?entity=page&appId=blog").await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/gif");
+        assert_eq!(count(&state, "events").await, 0);
+        assert_eq!(count(&state, "dead_letters").await, 1);
+    }
+}
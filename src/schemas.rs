@@ -1,19 +1,175 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use jsonschema::Validator;
+use serde_json::json;
+use std::{collections::HashMap, fs, path::Path};
+use tracing::info;
 
 const SCHEMA_DEFINITION: &str = include_str!("schema.json");
+const DEFAULT_SCHEMA_NAME: &str = "default";
+
+/// Fields every exporter reads from the stored event, so every schema must require them.
+const CORE_FIELDS: [&str; 3] = ["entity", "action", "appId"];
+
+/// Client-supplied event ids are kept short, like the random ids they stand in for. The
+/// embedded schema's `eventId` takes its `maxLength` from here, and ids sent as an
+/// `Idempotency-Key` header are checked against it as well.
+pub const MAX_EVENT_ID_LENGTH: usize = 128;
+
+/// The embedded schema, with the limits that are shared with the rest of the code filled in.
+fn embedded_schema() -> Result<serde_json::Value> {
+    let mut schema: serde_json::Value = serde_json::from_str(SCHEMA_DEFINITION)?;
+    schema["properties"]["eventId"]["maxLength"] = json!(MAX_EVENT_ID_LENGTH);
+
+    Ok(schema)
+}
 
 pub fn event_validator() -> Result<Validator> {
-    let schema: serde_json::Value = serde_json::from_str(SCHEMA_DEFINITION)?;
+    let schema = embedded_schema()?;
 
     jsonschema::validator_for(&schema)
         .map_err(|e| anyhow::anyhow!("could not create JSON schema validator: {}", e))
 }
 
+/// A compiled schema together with the version recorded on every event it validates.
+#[derive(Debug)]
+pub struct VersionedValidator {
+    pub version: String,
+    pub validator: Validator,
+}
+
+impl VersionedValidator {
+    fn compile(name: &str, schema: &serde_json::Value) -> Result<Self> {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| anyhow!("could not create JSON schema validator for {name}: {e}"))?;
+
+        Ok(Self {
+            version: schema_version(name, schema),
+            validator,
+        })
+    }
+}
+
+/// Rejects schemas that do not require the core fields as strings. A per-app schema is free
+/// to narrow them, e.g. to a `const`, but not to make them optional or change their type.
+fn require_core_fields(name: &str, schema: &serde_json::Value) -> Result<()> {
+    let required = schema
+        .get("required")
+        .and_then(|v| v.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    for field in CORE_FIELDS {
+        if !required.iter().any(|v| v.as_str() == Some(field)) {
+            return Err(anyhow!("schema {name} must require the `{field}` field"));
+        }
+
+        let property = schema.get("properties").and_then(|p| p.get(field));
+        let is_string = property.and_then(|p| p.get("type")) == Some(&json!("string"))
+            || property
+                .and_then(|p| p.get("const"))
+                .is_some_and(|v| v.is_string());
+
+        if !is_string {
+            return Err(anyhow!(
+                "schema {name} must declare `{field}` as a string property"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The version is the last segment of a URN `$id`, e.g.
+/// `urn:analytics-collector:schema:blog:2.1.0`, and is prefixed with the schema name, e.g.
+/// `blog@2.1.0`.
+fn schema_version(name: &str, schema: &serde_json::Value) -> String {
+    let version = schema
+        .get("$id")
+        .and_then(|v| v.as_str())
+        .and_then(|id| id.strip_prefix("urn:"))
+        .and_then(|id| id.rsplit_once(':'))
+        .map(|(_, version)| version)
+        .filter(|version| !version.is_empty())
+        .unwrap_or("unversioned");
+
+    format!("{name}@{version}")
+}
+
+/// Holds one validator per `appId` plus a default used for every other app.
+#[derive(Debug)]
+pub struct SchemaRegistry {
+    default: VersionedValidator,
+    apps: HashMap<String, VersionedValidator>,
+}
+
+impl SchemaRegistry {
+    /// Loads schemas from `SCHEMA_DIRECTORY` when set, otherwise uses the embedded schema.
+    pub fn build() -> Result<Self> {
+        match std::env::var("SCHEMA_DIRECTORY") {
+            Ok(directory) => Self::load(Path::new(&directory)),
+            Err(_) => Self::embedded(),
+        }
+    }
+
+    pub fn embedded() -> Result<Self> {
+        let schema = embedded_schema()?;
+
+        Ok(Self {
+            default: VersionedValidator {
+                version: schema_version(DEFAULT_SCHEMA_NAME, &schema),
+                validator: event_validator()?,
+            },
+            apps: HashMap::new(),
+        })
+    }
+
+    /// Every `<appId>.json` file in the directory becomes the schema for that app. A
+    /// `default.json` file replaces the embedded schema for apps without their own file.
+    pub fn load(directory: &Path) -> Result<Self> {
+        let mut registry = Self::embedded()?;
+
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+
+            let schema: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)
+                .map_err(|e| anyhow!("could not parse schema {}: {e}", path.display()))?;
+            require_core_fields(name, &schema)?;
+            let validator = VersionedValidator::compile(name, &schema)?;
+
+            info!(
+                "loaded schema {} from {}",
+                validator.version,
+                path.display()
+            );
+
+            if name == DEFAULT_SCHEMA_NAME {
+                registry.default = validator;
+            } else {
+                registry.apps.insert(name.to_string(), validator);
+            }
+        }
+
+        Ok(registry)
+    }
+
+    pub fn validator_for(&self, app_id: Option<&str>) -> &VersionedValidator {
+        app_id
+            .and_then(|app_id| self.apps.get(app_id))
+            .unwrap_or(&self.default)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::json;
 
     #[test]
     fn test_event_validator_valid_payload() {
@@ -89,6 +245,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_event_validator_email_clicks() {
+        let validator = event_validator().expect("validator should be created");
+        let click = json!({
+            "entity": "email",
+            "action": "click",
+            "path": "https://example.com/pricing",
+            "appId": "test-app"
+        });
+        assert!(validator.validate(&click).is_ok());
+
+        let view = json!({
+            "entity": "email",
+            "action": "view",
+            "appId": "test-app"
+        });
+        assert!(validator.validate(&view).is_err());
+    }
+
     #[test]
     fn test_event_validator_additional_property() {
         let validator = event_validator().expect("validator should be created");
@@ -322,4 +497,265 @@ mod tests {
             "Payload with valid string appId should be valid"
         );
     }
+
+    #[test]
+    fn test_event_validator_custom_event_valid() {
+        let validator = event_validator().expect("validator should be created");
+        let payload = json!({
+            "entity": "custom",
+            "action": "track",
+            "name": "signup",
+            "props": {
+                "plan": "pro",
+                "seats": 3,
+                "trial": false
+            },
+            "appId": "test-app"
+        });
+        let result = validator.validate(&payload);
+        assert!(result.is_ok(), "Custom event with props should be valid");
+    }
+
+    #[test]
+    fn test_event_validator_custom_event_missing_name() {
+        let validator = event_validator().expect("validator should be created");
+        let payload = json!({
+            "entity": "custom",
+            "action": "track",
+            "appId": "test-app"
+        });
+        let result = validator.validate(&payload);
+        assert!(
+            result.is_err(),
+            "Custom event without name should be invalid"
+        );
+    }
+
+    #[test]
+    fn test_event_validator_custom_event_wrong_action() {
+        let validator = event_validator().expect("validator should be created");
+        let payload = json!({
+            "entity": "custom",
+            "action": "view",
+            "name": "signup",
+            "appId": "test-app"
+        });
+        let result = validator.validate(&payload);
+        assert!(
+            result.is_err(),
+            "entity=custom with action=view should be invalid"
+        );
+    }
+
+    #[test]
+    fn test_event_validator_custom_event_nested_props() {
+        let validator = event_validator().expect("validator should be created");
+        let payload = json!({
+            "entity": "custom",
+            "action": "track",
+            "name": "purchase",
+            "props": {
+                "items": ["a", "b"]
+            },
+            "appId": "test-app"
+        });
+        let result = validator.validate(&payload);
+        assert!(
+            result.is_err(),
+            "Custom event props with non-scalar values should be invalid"
+        );
+    }
+
+    #[test]
+    fn test_event_validator_name_and_props_only_for_custom_events() {
+        let validator = event_validator().expect("validator should be created");
+
+        for (entity, action) in [("page", "view"), ("anchor", "click"), ("email", "click")] {
+            for extra in [json!({"name": "signup"}), json!({"props": {"plan": "pro"}})] {
+                let mut payload = json!({
+                    "entity": entity,
+                    "action": action,
+                    "appId": "test-app"
+                });
+                payload
+                    .as_object_mut()
+                    .unwrap()
+                    .extend(extra.as_object().unwrap().clone());
+
+                assert!(
+                    validator.validate(&payload).is_err(),
+                    "{entity} event with {extra} should be invalid"
+                );
+            }
+        }
+    }
+
+    fn core_schema() -> serde_json::Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "entity": { "type": "string" },
+                "action": { "type": "string" },
+                "appId": { "type": "string" }
+            },
+            "required": ["entity", "action", "appId"]
+        })
+    }
+
+    fn schema_directory(files: &[(&str, serde_json::Value)]) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(crate::utilities::generate_uuid_v4());
+        fs::create_dir_all(&directory).unwrap();
+
+        for (name, schema) in files {
+            fs::write(directory.join(name), schema.to_string()).unwrap();
+        }
+
+        directory
+    }
+
+    #[test]
+    fn test_event_validator_referrer() {
+        let validator = event_validator().expect("validator should be created");
+        let payload = json!({
+            "entity": "page",
+            "action": "view",
+            "referrer": "https://www.google.com/",
+            "appId": "test-app"
+        });
+        assert!(validator.validate(&payload).is_ok());
+
+        let payload = json!({
+            "entity": "page",
+            "action": "view",
+            "referrer": "x".repeat(2049),
+            "appId": "test-app"
+        });
+        assert!(
+            validator.validate(&payload).is_err(),
+            "Payload with an oversized referrer should be invalid"
+        );
+    }
+
+    #[test]
+    fn test_event_validator_event_id() {
+        let validator = event_validator().expect("validator should be created");
+        let payload = json!({
+            "entity": "page",
+            "action": "view",
+            "eventId": "8f14e45f-ceea-467f-a0e6-8f6b3e1d2c4a",
+            "appId": "test-app"
+        });
+        assert!(validator.validate(&payload).is_ok());
+
+        let payload = json!({
+            "entity": "page",
+            "action": "view",
+            "eventId": "",
+            "appId": "test-app"
+        });
+        assert!(
+            validator.validate(&payload).is_err(),
+            "Payload with an empty eventId should be invalid"
+        );
+
+        let payload = json!({
+            "entity": "page",
+            "action": "view",
+            "eventId": "a".repeat(MAX_EVENT_ID_LENGTH + 1),
+            "appId": "test-app"
+        });
+        assert!(
+            validator.validate(&payload).is_err(),
+            "Payload with an eventId over the maximum length should be invalid"
+        );
+    }
+
+    #[test]
+    fn test_schema_registry_embedded_uses_default_for_every_app() {
+        let registry = SchemaRegistry::embedded().unwrap();
+
+        assert_eq!(
+            registry.validator_for(Some("any-app")).version,
+            "default@1.3.0"
+        );
+        assert_eq!(registry.validator_for(None).version, "default@1.3.0");
+    }
+
+    #[test]
+    fn test_schema_registry_load_picks_schema_by_app_id() {
+        let directory = schema_directory(&[(
+            "blog.json",
+            json!({
+                "$id": "urn:analytics-collector:schema:blog:2",
+                "type": "object",
+                "properties": {
+                    "entity": { "const": "article" },
+                    "action": { "type": "string" },
+                    "appId": { "type": "string" }
+                },
+                "required": ["entity", "action", "appId"]
+            }),
+        )]);
+
+        let registry = SchemaRegistry::load(&directory).unwrap();
+        let blog = registry.validator_for(Some("blog"));
+
+        assert_eq!(blog.version, "blog@2");
+        assert!(
+            blog.validator
+                .validate(&json!({"entity": "article", "action": "read", "appId": "blog"}))
+                .is_ok()
+        );
+        assert_eq!(
+            registry.validator_for(Some("shop")).version,
+            "default@1.3.0"
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_schema_registry_load_replaces_default_and_ignores_other_files() {
+        let directory = schema_directory(&[
+            ("default.json", core_schema()),
+            ("notes.txt", json!("not a schema")),
+        ]);
+
+        let registry = SchemaRegistry::load(&directory).unwrap();
+
+        assert_eq!(
+            registry.validator_for(Some("shop")).version,
+            "default@unversioned"
+        );
+        assert!(registry.apps.is_empty());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_schema_registry_load_rejects_schema_without_core_fields() {
+        let mut optional = core_schema();
+        optional["required"] = json!(["entity", "appId"]);
+        let mut loosened = core_schema();
+        loosened["properties"]["action"] = json!({});
+
+        for schema in [optional, loosened] {
+            let directory = schema_directory(&[("blog.json", schema)]);
+
+            assert!(SchemaRegistry::load(&directory).is_err());
+
+            fs::remove_dir_all(directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_schema_registry_load_rejects_invalid_schema() {
+        let mut broken = core_schema();
+        broken["type"] = json!(12);
+        let directory = schema_directory(&[("broken.json", broken)]);
+
+        assert!(SchemaRegistry::load(&directory).is_err());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
 }
@@ -0,0 +1,338 @@
+//! Server-side sessionization.
+//!
+//! Events from the same visitor belong to one session until the visitor has been inactive
+//! for longer than the session timeout. Active sessions are tracked in memory so the buffer
+//! writer can assign a session id to each event it stores; every update is also written to the
+//! `sessions` table so sessions can be exported next to the raw events. The writer stages the
+//! updates of a transaction and only applies them to the tracker once it is committed, so events
+//! that were never stored do not count towards a session.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::utilities::{generate_uuid_v4, get_environment_variable_with_default};
+
+const DEFAULT_TIMEOUT_SECONDS: i64 = 30 * 60;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub recorded_by: String,
+    pub visitor_id: String,
+    pub started_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub entry_path: Option<String>,
+    pub exit_path: Option<String>,
+    pub page_count: i64,
+}
+
+impl Session {
+    pub fn duration_seconds(&self) -> i64 {
+        (self.last_seen_at - self.started_at).num_seconds()
+    }
+}
+
+/// A page view within a session. The path is optional in the event schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageView {
+    pub path: Option<String>,
+}
+
+/// The visitor an event belongs to, for events that can be sessionized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Visit {
+    pub visitor_id: String,
+    pub page_view: Option<PageView>,
+}
+
+type Key = (String, String);
+
+/// Session updates that are not committed yet. Like a savepoint, the updates staged since a
+/// point can be rolled back when the events they belong to fail to be stored.
+#[derive(Debug, Default)]
+pub struct Staged {
+    sessions: HashMap<Key, Session>,
+    /// The state each update replaced, in the order they were staged.
+    undo: Vec<(Key, Option<Session>)>,
+}
+
+impl Staged {
+    /// Marks the updates staged so far, to roll back to later.
+    pub fn savepoint(&self) -> usize {
+        self.undo.len()
+    }
+
+    /// Undoes every update staged since the savepoint.
+    pub fn rollback_to(&mut self, savepoint: usize) {
+        while self.undo.len() > savepoint {
+            let Some((key, previous)) = self.undo.pop() else {
+                break;
+            };
+
+            match previous {
+                Some(session) => self.sessions.insert(key, session),
+                None => self.sessions.remove(&key),
+            };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionTracker {
+    timeout: TimeDelta,
+    state: Mutex<TrackerState>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    active: HashMap<Key, Session>,
+    last_sweep_at: Option<DateTime<Utc>>,
+}
+
+impl Default for SessionTracker {
+    fn default() -> Self {
+        Self::new(TimeDelta::seconds(DEFAULT_TIMEOUT_SECONDS))
+    }
+}
+
+impl SessionTracker {
+    pub fn new(timeout: TimeDelta) -> Self {
+        Self {
+            timeout,
+            state: Mutex::new(TrackerState::default()),
+        }
+    }
+
+    /// Reads the inactivity timeout from `SESSION_TIMEOUT_SECONDS`.
+    pub fn build() -> Self {
+        let timeout = get_environment_variable_with_default(
+            "SESSION_TIMEOUT_SECONDS",
+            DEFAULT_TIMEOUT_SECONDS.to_string(),
+        )
+        .parse::<i64>()
+        .unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+
+        Self::new(TimeDelta::seconds(timeout))
+    }
+
+    /// Stages activity for a visitor on top of the committed sessions and the updates staged
+    /// before, and returns the updated session. Page views advance the page count and the entry
+    /// and exit pages; other events only keep the session alive.
+    pub fn track(
+        &self,
+        staged: &mut Staged,
+        recorded_by: &str,
+        visit: &Visit,
+        at: DateTime<Utc>,
+    ) -> Session {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        self.sweep(&mut state, at);
+
+        let key = (recorded_by.to_string(), visit.visitor_id.clone());
+        let previous = staged
+            .sessions
+            .get(&key)
+            .or_else(|| state.active.get(&key))
+            .cloned();
+
+        let mut session = match &previous {
+            Some(session) if at - session.last_seen_at <= self.timeout => session.clone(),
+            _ => Self::start(recorded_by, &visit.visitor_id, at),
+        };
+
+        session.last_seen_at = session.last_seen_at.max(at);
+
+        if let Some(PageView { path }) = &visit.page_view {
+            session.page_count += 1;
+
+            if let Some(path) = path {
+                session.exit_path = Some(path.clone());
+                session.entry_path.get_or_insert_with(|| path.clone());
+            }
+        }
+
+        let replaced = staged.sessions.insert(key.clone(), session.clone());
+        staged.undo.push((key, replaced));
+
+        session
+    }
+
+    /// Applies committed updates.
+    pub fn apply(&self, staged: Staged) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        state.active.extend(staged.sessions);
+    }
+
+    fn start(recorded_by: &str, visitor_id: &str, at: DateTime<Utc>) -> Session {
+        Session {
+            id: generate_uuid_v4(),
+            recorded_by: recorded_by.to_string(),
+            visitor_id: visitor_id.to_string(),
+            started_at: at,
+            last_seen_at: at,
+            entry_path: None,
+            exit_path: None,
+            page_count: 0,
+        }
+    }
+
+    /// Drops sessions that have timed out, at most once per timeout period.
+    fn sweep(&self, state: &mut TrackerState, now: DateTime<Utc>) {
+        if state
+            .last_sweep_at
+            .is_some_and(|last_sweep_at| now - last_sweep_at < self.timeout)
+        {
+            return;
+        }
+
+        state
+            .active
+            .retain(|_, session| now - session.last_seen_at <= self.timeout);
+        state.last_sweep_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn page(visitor_id: &str, path: &str) -> Visit {
+        Visit {
+            visitor_id: visitor_id.to_string(),
+            page_view: Some(PageView {
+                path: Some(path.to_string()),
+            }),
+        }
+    }
+
+    fn other(visitor_id: &str) -> Visit {
+        Visit {
+            visitor_id: visitor_id.to_string(),
+            page_view: None,
+        }
+    }
+
+    /// Tracks a single committed event.
+    fn track(
+        tracker: &SessionTracker,
+        recorded_by: &str,
+        visit: Visit,
+        at: DateTime<Utc>,
+    ) -> Session {
+        let mut staged = Staged::default();
+        let session = tracker.track(&mut staged, recorded_by, &visit, at);
+        tracker.apply(staged);
+        session
+    }
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2030, 1, 1, 12, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_track_groups_events_within_timeout() {
+        let tracker = SessionTracker::new(TimeDelta::minutes(30));
+
+        let first = track(&tracker, "blog", page("visitor", "/"), at(0));
+        track(&tracker, "blog", other("visitor"), at(5));
+        let last = track(&tracker, "blog", page("visitor", "/about"), at(10));
+
+        assert_eq!(first.id, last.id);
+        assert_eq!(last.page_count, 2);
+        assert_eq!(last.entry_path.as_deref(), Some("/"));
+        assert_eq!(last.exit_path.as_deref(), Some("/about"));
+        assert_eq!(last.duration_seconds(), 600);
+    }
+
+    #[test]
+    fn test_track_starts_new_session_after_inactivity() {
+        let tracker = SessionTracker::new(TimeDelta::minutes(30));
+
+        let first = track(&tracker, "blog", page("visitor", "/"), at(0));
+        let second = track(&tracker, "blog", page("visitor", "/pricing"), at(45));
+
+        assert_ne!(first.id, second.id);
+        assert_eq!(second.page_count, 1);
+        assert_eq!(second.entry_path.as_deref(), Some("/pricing"));
+        assert_eq!(second.duration_seconds(), 0);
+    }
+
+    #[test]
+    fn test_track_separates_visitors_and_apps() {
+        let tracker = SessionTracker::new(TimeDelta::minutes(30));
+
+        let first = track(&tracker, "blog", page("visitor", "/"), at(0));
+
+        assert_ne!(
+            first.id,
+            track(&tracker, "blog", page("other", "/"), at(1)).id
+        );
+        assert_ne!(
+            first.id,
+            track(&tracker, "shop", page("visitor", "/"), at(1)).id
+        );
+    }
+
+    #[test]
+    fn test_track_leaves_tracker_alone_until_applied() {
+        let tracker = SessionTracker::new(TimeDelta::minutes(30));
+        let mut staged = Staged::default();
+
+        let first = tracker.track(&mut staged, "blog", &page("visitor", "/"), at(0));
+        let second = tracker.track(&mut staged, "blog", &page("visitor", "/about"), at(1));
+        assert_eq!(first.id, second.id);
+        assert_eq!(second.page_count, 2);
+        assert!(tracker.state.lock().unwrap().active.is_empty());
+
+        tracker.apply(staged);
+        let third = track(&tracker, "blog", page("visitor", "/pricing"), at(2));
+        assert_eq!(third.id, first.id);
+        assert_eq!(third.page_count, 3);
+    }
+
+    #[test]
+    fn test_rollback_to_discards_updates_since_savepoint() {
+        let tracker = SessionTracker::new(TimeDelta::minutes(30));
+        let mut staged = Staged::default();
+
+        let kept = tracker.track(&mut staged, "blog", &page("visitor", "/"), at(0));
+        let savepoint = staged.savepoint();
+        tracker.track(&mut staged, "blog", &page("visitor", "/about"), at(1));
+        tracker.track(&mut staged, "blog", &page("other", "/"), at(1));
+        staged.rollback_to(savepoint);
+        tracker.apply(staged);
+
+        let state = tracker.state.lock().unwrap();
+        assert_eq!(state.active.len(), 1);
+        assert_eq!(
+            state.active[&("blog".to_string(), "visitor".to_string())],
+            kept
+        );
+    }
+
+    #[test]
+    fn test_sweep_drops_expired_sessions() {
+        let tracker = SessionTracker::new(TimeDelta::minutes(30));
+
+        track(&tracker, "blog", page("first", "/"), at(0));
+        track(&tracker, "blog", page("second", "/"), at(45));
+
+        let state = tracker.state.lock().unwrap();
+        assert_eq!(state.active.len(), 1);
+        assert!(
+            state
+                .active
+                .contains_key(&("blog".to_string(), "second".to_string()))
+        );
+    }
+}
@@ -1,6 +1,11 @@
+pub mod checkpoints;
+pub mod dead_letters;
 #[cfg(feature = "export-parquet")]
 pub mod google_storage;
 pub mod memory;
+pub mod retention;
+pub mod visitor_salt;
+pub mod writer;
 
 #[cfg(feature = "export-parquet")]
 use anyhow::Result;
@@ -8,13 +13,75 @@ use anyhow::Result;
 #[cfg(feature = "export-parquet")]
 use memory::EventRecord;
 
-pub const SCHEMA: &str = r#"
+/// Migrations of the buffer, applied in order. A file-backed buffer records how many it has
+/// applied in `PRAGMA user_version`, so append new migrations rather than editing old ones.
+pub const MIGRATIONS: [&str; 1] = [SCHEMA];
+
+/// Events are numbered in the order they were inserted. Unlike a plain `rowid`, an
+/// `AUTOINCREMENT` key is never handed out twice, even once the newest events are deleted, so
+/// checkpoints and exported sequences stay valid. Each exporter's checkpoint is the sequence of
+/// the last event it exported, and the visitor salt table holds at most the current day's salt.
+/// The buffer id is generated once per buffer and exported next to each sequence, since
+/// sequences start over with every new buffer. Sessions change after they are inserted, so each
+/// upsert numbers the sessions it writes from the buffer's `session_sequence` instead, and
+/// checkpoints keep the last one exported next to the last event.
+const SCHEMA: &str = r#"
 CREATE TABLE events (
-    id TEXT PRIMARY KEY NOT NULL,
+    sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT NOT NULL UNIQUE,
     recorded_at TIMESTAMP WITH TIME ZONE NOT NULL,
     recorded_by TEXT NOT NULL,
-    event JSONB NOT NULL
+    event JSONB NOT NULL,
+    context JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    recorded_by TEXT NOT NULL,
+    visitor_id TEXT NOT NULL,
+    started_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_seen_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    entry_path TEXT,
+    exit_path TEXT,
+    page_count INTEGER NOT NULL DEFAULT 0,
+    sequence INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX sessions_sequence ON sessions (sequence);
+
+CREATE TABLE dead_letters (
+    id TEXT PRIMARY KEY NOT NULL,
+    received_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    recorded_by TEXT,
+    reason TEXT NOT NULL,
+    error TEXT NOT NULL,
+    errors JSONB NOT NULL DEFAULT '[]',
+    payload TEXT NOT NULL,
+    headers JSONB NOT NULL DEFAULT '{}'
 );
+
+CREATE INDEX dead_letters_received_at ON dead_letters (received_at);
+
+CREATE TABLE export_checkpoints (
+    exporter TEXT PRIMARY KEY NOT NULL,
+    sequence INTEGER NOT NULL,
+    session_sequence INTEGER NOT NULL DEFAULT 0,
+    exported_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE TABLE visitor_salt (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+    day TEXT NOT NULL,
+    salt BLOB NOT NULL
+);
+
+CREATE TABLE buffer (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+    buffer_id TEXT NOT NULL,
+    session_sequence INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO buffer (id, buffer_id) VALUES (1, lower(hex(randomblob(16))));
 "#;
 
 #[cfg(feature = "export-parquet")]
@@ -0,0 +1,273 @@
+//! How far each exporter has exported the buffer.
+//!
+//! A checkpoint holds the sequence of the last event an exporter published, the sequence of the
+//! last session update it published and the time its last complete export started. It only advances
+//! past events and sessions once they were published, so a failed or interrupted export is repeated
+//! from the same point, and with a file-backed buffer it survives restarts. Operators can inspect
+//! and move checkpoints through the admin endpoints.
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use libsql::{Connection, de::from_row, params};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub exporter: String,
+    /// The sequence of the last exported event. Events inserted later have larger ones.
+    pub sequence: i64,
+    /// The sequence of the last exported session update. Sessions written later, including
+    /// sessions that are extended again, have larger ones.
+    #[serde(default)]
+    pub session_sequence: i64,
+    /// When the last complete export started.
+    pub exported_at: DateTime<Utc>,
+}
+
+impl Checkpoint {
+    /// Where an exporter without a checkpoint starts, the beginning of the buffer.
+    pub fn start(exporter: &str) -> Self {
+        Self {
+            exporter: exporter.to_string(),
+            sequence: 0,
+            session_sequence: 0,
+            exported_at: DateTime::UNIX_EPOCH,
+        }
+    }
+}
+
+const COLUMNS: &str = "exporter, sequence, session_sequence, exported_at";
+
+pub async fn get(connection: &Connection, exporter: &str) -> Result<Option<Checkpoint>> {
+    let mut rows = connection
+        .query(
+            &format!("SELECT {COLUMNS} FROM export_checkpoints WHERE exporter = ?"),
+            params![exporter],
+        )
+        .await?;
+
+    match rows.next().await? {
+        Some(row) => Ok(Some(from_row(&row)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn list(connection: &Connection) -> Result<Vec<Checkpoint>> {
+    let mut rows = connection
+        .query(
+            &format!("SELECT {COLUMNS} FROM export_checkpoints ORDER BY exporter"),
+            (),
+        )
+        .await?;
+
+    let mut checkpoints = Vec::new();
+    while let Some(row) = rows.next().await? {
+        checkpoints.push(from_row(&row)?);
+    }
+
+    Ok(checkpoints)
+}
+
+/// Moves a checkpoint on after a successful publish that started from `from`. Returns `false`
+/// without moving it if it was changed in the meantime, e.g. reset or deleted by an operator.
+/// Only an export that started without a checkpoint creates one.
+#[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+pub async fn advance(connection: &Connection, from: &Checkpoint, to: &Checkpoint) -> Result<bool> {
+    if *from != Checkpoint::start(&from.exporter) {
+        let updated = connection
+            .execute(
+                "UPDATE export_checkpoints SET \
+                     sequence = ?, session_sequence = ?, exported_at = ? \
+                 WHERE exporter = ? AND sequence = ? AND session_sequence = ? \
+                     AND exported_at = ?",
+                params![
+                    to.sequence,
+                    to.session_sequence,
+                    to.exported_at.to_rfc3339(),
+                    from.exporter.as_str(),
+                    from.sequence,
+                    from.session_sequence,
+                    from.exported_at.to_rfc3339()
+                ],
+            )
+            .await?;
+
+        return Ok(updated > 0);
+    }
+
+    let updated = connection
+        .execute(
+            "INSERT INTO export_checkpoints (exporter, sequence, session_sequence, exported_at) \
+             VALUES (?, ?, ?, ?) \
+             ON CONFLICT (exporter) DO UPDATE SET \
+                 sequence = excluded.sequence, \
+                 session_sequence = excluded.session_sequence, \
+                 exported_at = excluded.exported_at \
+             WHERE export_checkpoints.sequence = ? \
+                 AND export_checkpoints.session_sequence = ? \
+                 AND export_checkpoints.exported_at = ?",
+            params![
+                to.exporter.as_str(),
+                to.sequence,
+                to.session_sequence,
+                to.exported_at.to_rfc3339(),
+                from.sequence,
+                from.session_sequence,
+                from.exported_at.to_rfc3339()
+            ],
+        )
+        .await?;
+
+    Ok(updated > 0)
+}
+
+/// Sets a checkpoint, whatever it was before.
+pub async fn set(connection: &Connection, checkpoint: &Checkpoint) -> Result<()> {
+    connection
+        .execute(
+            "INSERT INTO export_checkpoints (exporter, sequence, session_sequence, exported_at) \
+             VALUES (?, ?, ?, ?) \
+             ON CONFLICT (exporter) DO UPDATE SET \
+                 sequence = excluded.sequence, \
+                 session_sequence = excluded.session_sequence, \
+                 exported_at = excluded.exported_at",
+            params![
+                checkpoint.exporter.as_str(),
+                checkpoint.sequence,
+                checkpoint.session_sequence,
+                checkpoint.exported_at.to_rfc3339()
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Removes a checkpoint, so the exporter starts over from the beginning of the buffer. Returns
+/// whether there was one.
+pub async fn delete(connection: &Connection, exporter: &str) -> Result<bool> {
+    let deleted = connection
+        .execute(
+            "DELETE FROM export_checkpoints WHERE exporter = ?",
+            params![exporter],
+        )
+        .await?;
+
+    Ok(deleted > 0)
+}
+
+/// The number of buffered events after a checkpoint, that the exporter has yet to publish.
+pub async fn pending(connection: &Connection, checkpoint: &Checkpoint) -> Result<u64> {
+    let mut rows = connection
+        .query(
+            "SELECT COUNT(*) FROM events WHERE sequence > ?",
+            params![checkpoint.sequence],
+        )
+        .await?;
+
+    match rows.next().await? {
+        Some(row) => Ok(row.get(0)?),
+        None => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::initialize;
+    use chrono::TimeZone;
+
+    fn checkpoint(exporter: &str, sequence: i64, minute: u32) -> Checkpoint {
+        Checkpoint {
+            exporter: exporter.to_string(),
+            sequence,
+            session_sequence: 0,
+            exported_at: Utc.with_ymd_and_hms(2030, 1, 1, 12, minute, 0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_get_and_list() {
+        let connection = initialize().await.unwrap();
+
+        assert_eq!(get(&connection, "parquet").await.unwrap(), None);
+
+        set(&connection, &checkpoint("postgresql", 7, 1))
+            .await
+            .unwrap();
+        set(&connection, &checkpoint("parquet", 3, 0))
+            .await
+            .unwrap();
+        set(&connection, &checkpoint("parquet", 5, 2))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get(&connection, "parquet").await.unwrap(),
+            Some(checkpoint("parquet", 5, 2))
+        );
+        assert_eq!(
+            list(&connection).await.unwrap(),
+            [checkpoint("parquet", 5, 2), checkpoint("postgresql", 7, 1)]
+        );
+    }
+
+    #[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+    #[tokio::test]
+    async fn test_advance_does_not_overwrite_a_reset() {
+        let connection = initialize().await.unwrap();
+        let start = Checkpoint::start("parquet");
+
+        assert!(
+            advance(&connection, &start, &checkpoint("parquet", 10, 0))
+                .await
+                .unwrap()
+        );
+
+        // An operator resets the checkpoint while an export from 10 is running
+        set(&connection, &checkpoint("parquet", 2, 0))
+            .await
+            .unwrap();
+
+        assert!(
+            !advance(
+                &connection,
+                &checkpoint("parquet", 10, 0),
+                &checkpoint("parquet", 20, 1)
+            )
+            .await
+            .unwrap()
+        );
+        assert_eq!(
+            get(&connection, "parquet").await.unwrap(),
+            Some(checkpoint("parquet", 2, 0))
+        );
+    }
+
+    #[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+    #[tokio::test]
+    async fn test_advance_does_not_restore_a_deleted_checkpoint() {
+        let connection = initialize().await.unwrap();
+        let start = Checkpoint::start("parquet");
+
+        assert!(
+            advance(&connection, &start, &checkpoint("parquet", 10, 0))
+                .await
+                .unwrap()
+        );
+
+        // An operator deletes the checkpoint while an export from 10 is running
+        assert!(delete(&connection, "parquet").await.unwrap());
+
+        assert!(
+            !advance(
+                &connection,
+                &checkpoint("parquet", 10, 0),
+                &checkpoint("parquet", 20, 1)
+            )
+            .await
+            .unwrap()
+        );
+        assert_eq!(get(&connection, "parquet").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,421 @@
+//! Rejected payloads kept for inspection and replay.
+//!
+//! Events of authorized clients that fail validation are stored with the reason they were
+//! rejected and the request headers needed to ingest them again, so data sent by a broken
+//! client release can be recovered once the cause is fixed. The table is capped at
+//! `DEAD_LETTER_MAX_ROWS`, dropping the oldest rows first.
+
+use crate::{
+    enrichment::RequestContext,
+    errors::{ApplicationError, ValidationIssue},
+    utilities::{generate_uuid_v4, get_environment_variable_with_default},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use libsql::{Connection, Value, de::from_row, params, params_from_iter};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+
+const DEFAULT_MAX_ROWS: u64 = 10_000;
+
+/// The request headers an event is ingested with. The client address is never stored, so
+/// replayed events have no location or visitor id, and neither is the app's key, which anyone
+/// reading the dead letters could otherwise send events with.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestHeaders {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+impl From<&RequestContext> for RequestHeaders {
+    fn from(request: &RequestContext) -> Self {
+        Self {
+            user_agent: request.user_agent.clone(),
+            origin: request.origin.clone(),
+            referer: request.referer.clone(),
+            idempotency_key: request.idempotency_key.clone(),
+        }
+    }
+}
+
+impl From<&RequestHeaders> for RequestContext {
+    fn from(headers: &RequestHeaders) -> Self {
+        Self {
+            user_agent: headers.user_agent.clone(),
+            client_ip: None,
+            referer: headers.referer.clone(),
+            origin: headers.origin.clone(),
+            api_key: None,
+            idempotency_key: headers.idempotency_key.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub received_at: DateTime<Utc>,
+    /// The payload's `appId`, if it has one.
+    pub recorded_by: Option<String>,
+    pub reason: String,
+    pub error: String,
+    #[serde(deserialize_with = "from_json_text")]
+    pub errors: Vec<ValidationIssue>,
+    /// The rejected payload as it was received.
+    pub payload: String,
+    #[serde(deserialize_with = "from_json_text")]
+    pub headers: RequestHeaders,
+}
+
+impl DeadLetter {
+    /// Keeps a payload rejected with `error`, unless the error is not caused by the payload.
+    pub fn from_error(
+        error: &ApplicationError,
+        payload: String,
+        request: &RequestContext,
+    ) -> Option<Self> {
+        let reason = error.dead_letter_reason()?;
+        let recorded_by = serde_json::from_str::<serde_json::Value>(&payload)
+            .ok()
+            .and_then(|value| value.get("appId")?.as_str().map(str::to_string));
+
+        Some(Self {
+            id: generate_uuid_v4(),
+            received_at: Utc::now(),
+            recorded_by,
+            reason: reason.to_string(),
+            error: error.to_string(),
+            errors: error.validation_issues().to_vec(),
+            payload,
+            headers: RequestHeaders::from(request),
+        })
+    }
+}
+
+fn from_json_text<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let text = String::deserialize(deserializer)?;
+    serde_json::from_str(&text).map_err(serde::de::Error::custom)
+}
+
+/// Narrows a listing or bulk replay of dead letters. Results are ordered newest first, and
+/// `before` continues a listing after its oldest row.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DeadLetterFilter {
+    pub reason: Option<String>,
+    pub app_id: Option<String>,
+    pub before: Option<DateTime<Utc>>,
+    pub limit: Option<u32>,
+}
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+const COLUMNS: &str = "id, received_at, recorded_by, reason, error, errors, payload, headers";
+
+#[derive(Debug)]
+pub struct DeadLetters {
+    max_rows: u64,
+}
+
+impl Default for DeadLetters {
+    fn default() -> Self {
+        Self {
+            max_rows: DEFAULT_MAX_ROWS,
+        }
+    }
+}
+
+impl DeadLetters {
+    /// Reads the row cap from `DEAD_LETTER_MAX_ROWS`.
+    pub fn build() -> Self {
+        let max_rows = get_environment_variable_with_default(
+            "DEAD_LETTER_MAX_ROWS",
+            DEFAULT_MAX_ROWS.to_string(),
+        )
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_MAX_ROWS);
+
+        Self { max_rows }
+    }
+
+    /// Stores dead letters and drops the oldest rows beyond the cap.
+    pub async fn insert(&self, connection: &Connection, letters: &[DeadLetter]) -> Result<u64> {
+        if letters.is_empty() {
+            return Ok(0);
+        }
+
+        let placeholders = vec!["(?, ?, ?, ?, ?, json(?), ?, json(?))"; letters.len()].join(", ");
+        let query = format!("INSERT INTO dead_letters ({COLUMNS}) VALUES {placeholders}");
+
+        let mut values = Vec::<Value>::with_capacity(letters.len() * 8);
+        for letter in letters {
+            values.push(Value::from(letter.id.clone()));
+            values.push(Value::from(letter.received_at.to_rfc3339()));
+            values.push(letter.recorded_by.clone().map_or(Value::Null, Value::from));
+            values.push(Value::from(letter.reason.clone()));
+            values.push(Value::from(letter.error.clone()));
+            values.push(Value::from(serde_json::to_string(&letter.errors)?));
+            values.push(Value::from(letter.payload.clone()));
+            values.push(Value::from(serde_json::to_string(&letter.headers)?));
+        }
+
+        let inserted = connection.execute(&query, params_from_iter(values)).await?;
+
+        connection
+            .execute(
+                "DELETE FROM dead_letters WHERE id NOT IN \
+                 (SELECT id FROM dead_letters ORDER BY received_at DESC LIMIT ?)",
+                params![self.max_rows],
+            )
+            .await?;
+
+        Ok(inserted)
+    }
+
+    pub async fn list(
+        &self,
+        connection: &Connection,
+        filter: &DeadLetterFilter,
+    ) -> Result<Vec<DeadLetter>> {
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let query = format!(
+            "SELECT {COLUMNS} FROM dead_letters \
+             WHERE (?1 IS NULL OR reason = ?1) \
+                 AND (?2 IS NULL OR recorded_by = ?2) \
+                 AND (?3 IS NULL OR received_at < ?3) \
+             ORDER BY received_at DESC LIMIT ?4"
+        );
+
+        let mut rows = connection
+            .query(
+                &query,
+                params![
+                    filter.reason.clone().map_or(Value::Null, Value::from),
+                    filter.app_id.clone().map_or(Value::Null, Value::from),
+                    filter
+                        .before
+                        .map_or(Value::Null, |before| Value::from(before.to_rfc3339())),
+                    limit
+                ],
+            )
+            .await?;
+
+        let mut letters = Vec::new();
+        while let Some(row) = rows.next().await? {
+            letters.push(from_row::<DeadLetter>(&row)?);
+        }
+
+        Ok(letters)
+    }
+
+    pub async fn get(&self, connection: &Connection, id: &str) -> Result<Option<DeadLetter>> {
+        let mut rows = connection
+            .query(
+                &format!("SELECT {COLUMNS} FROM dead_letters WHERE id = ?"),
+                params![id],
+            )
+            .await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(Some(from_row::<DeadLetter>(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete(&self, connection: &Connection, id: &str) -> Result<bool> {
+        let deleted = connection
+            .execute("DELETE FROM dead_letters WHERE id = ?", params![id])
+            .await?;
+
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::initialize;
+    use chrono::TimeDelta;
+
+    fn dead_letter(reason: &str, payload: &str, received_at: DateTime<Utc>) -> DeadLetter {
+        DeadLetter {
+            id: generate_uuid_v4(),
+            received_at,
+            recorded_by: None,
+            reason: reason.to_string(),
+            error: "rejected".to_string(),
+            errors: Vec::new(),
+            payload: payload.to_string(),
+            headers: RequestHeaders::default(),
+        }
+    }
+
+    #[test]
+    fn test_from_error_keeps_reason_and_headers() {
+        let request = RequestContext {
+            user_agent: Some("Firefox".to_string()),
+            client_ip: Some("203.0.113.7".parse().unwrap()),
+            api_key: Some("pk_blog".to_string()),
+            ..RequestContext::default()
+        };
+        let error = ApplicationError::ValidationFailed(vec![ValidationIssue {
+            instance_path: "/action".to_string(),
+            keyword: "const".to_string(),
+            message: "\"view\" was expected".to_string(),
+        }]);
+
+        let letter = DeadLetter::from_error(
+            &error,
+            r#"{"entity":"page","action":"click","appId":"blog"}"#.to_string(),
+            &request,
+        )
+        .unwrap();
+
+        assert_eq!(letter.reason, "schema_validation");
+        assert_eq!(letter.recorded_by.as_deref(), Some("blog"));
+        assert_eq!(letter.errors.len(), 1);
+        assert_eq!(letter.headers.user_agent.as_deref(), Some("Firefox"));
+        assert!(
+            !serde_json::to_string(&letter.headers)
+                .unwrap()
+                .contains("pk_blog")
+        );
+        assert_eq!(RequestContext::from(&letter.headers).client_ip, None);
+
+        let throttled =
+            ApplicationError::TooManyRequests("Too many requests".to_string(), Default::default());
+        assert!(DeadLetter::from_error(&throttled, "{}".to_string(), &request).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_insert_and_get_round_trip() {
+        let connection = initialize().await.unwrap();
+        let dead_letters = DeadLetters::default();
+        let letter = DeadLetter {
+            recorded_by: Some("blog".to_string()),
+            errors: vec![ValidationIssue {
+                instance_path: "/action".to_string(),
+                keyword: "const".to_string(),
+                message: "\"view\" was expected".to_string(),
+            }],
+            headers: RequestHeaders {
+                origin: Some("https://example.com".to_string()),
+                ..RequestHeaders::default()
+            },
+            ..dead_letter(
+                "schema_validation",
+                r#"{"entity":"page","action":"click","appId":"blog"}"#,
+                Utc::now(),
+            )
+        };
+
+        dead_letters
+            .insert(&connection, std::slice::from_ref(&letter))
+            .await
+            .unwrap();
+        let stored = dead_letters
+            .get(&connection, &letter.id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(stored, letter);
+    }
+
+    #[tokio::test]
+    async fn test_insert_drops_oldest_rows_beyond_cap() {
+        let connection = initialize().await.unwrap();
+        let dead_letters = DeadLetters { max_rows: 2 };
+        let now = Utc::now();
+
+        let letters: Vec<DeadLetter> = (0..3)
+            .map(|age| dead_letter("invalid_payload", "{", now - TimeDelta::minutes(age)))
+            .collect();
+        dead_letters.insert(&connection, &letters).await.unwrap();
+
+        let stored = dead_letters
+            .list(&connection, &DeadLetterFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].id, letters[0].id);
+        assert_eq!(stored[1].id, letters[1].id);
+    }
+
+    #[tokio::test]
+    async fn test_list_filters_and_pages() {
+        let connection = initialize().await.unwrap();
+        let dead_letters = DeadLetters::default();
+        let now = Utc::now();
+
+        let for_app = |reason, app_id: &str, received_at| DeadLetter {
+            recorded_by: Some(app_id.to_string()),
+            ..dead_letter(reason, "{}", received_at)
+        };
+        let letters = [
+            for_app("schema_validation", "blog", now),
+            for_app("schema_validation", "shop", now - TimeDelta::minutes(1)),
+            for_app("invalid_payload", "blog", now - TimeDelta::minutes(2)),
+        ];
+        dead_letters.insert(&connection, &letters).await.unwrap();
+
+        let by_reason = dead_letters
+            .list(
+                &connection,
+                &DeadLetterFilter {
+                    reason: Some("schema_validation".to_string()),
+                    ..DeadLetterFilter::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(by_reason.len(), 2);
+
+        let by_app = dead_letters
+            .list(
+                &connection,
+                &DeadLetterFilter {
+                    app_id: Some("blog".to_string()),
+                    before: Some(now),
+                    ..DeadLetterFilter::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(by_app.len(), 1);
+        assert_eq!(by_app[0].reason, "invalid_payload");
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        let connection = initialize().await.unwrap();
+        let dead_letters = DeadLetters::default();
+        let letter = dead_letter("invalid_payload", "{}", Utc::now());
+
+        dead_letters
+            .insert(&connection, std::slice::from_ref(&letter))
+            .await
+            .unwrap();
+
+        assert!(dead_letters.delete(&connection, &letter.id).await.unwrap());
+        assert!(!dead_letters.delete(&connection, &letter.id).await.unwrap());
+        assert!(
+            dead_letters
+                .get(&connection, &letter.id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -13,6 +13,12 @@ pub struct GoogleStorageClient {
 }
 
 impl GoogleStorageClient {
+    /// Whether workload identity and the bucket are configured, without which `new` fails.
+    pub fn configured() -> bool {
+        WorkloadIdentityConfig::default().enabled()
+            && std::env::var("PARQUET_STORAGE_BUCKET").is_ok()
+    }
+
     /// Create a new GoogleStorageClient instance
     ///
     /// # Arguments
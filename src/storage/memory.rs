@@ -1,21 +1,28 @@
-use super::SCHEMA;
-use anyhow::Result;
+use super::MIGRATIONS;
+use crate::{
+    enrichment::campaign::Campaign,
+    sessions::{Session, Visit},
+    utilities::generate_uuid_v4,
+};
+use anyhow::{Result, bail};
 use chrono::{DateTime, Utc};
-use libsql::{Builder, Connection};
-use serde::{Deserialize, Deserializer, de::Visitor};
+use libsql::{Builder, Connection, Database, Value, params_from_iter};
+use serde::{Deserialize, Deserializer, Serialize, de::Visitor};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+use tracing::info;
 
-#[cfg(feature = "export-parquet")]
+#[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+use anyhow::Context;
+
+#[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
 use libsql::{de::from_row, params};
 
 #[cfg(feature = "export-parquet")]
 use std::sync::Arc;
 
-#[cfg(feature = "export-parquet")]
-use tokio_stream::StreamExt;
-
-#[cfg(feature = "export-parquet")]
-use tracing::error;
-
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct Event {
@@ -24,6 +31,35 @@ pub struct Event {
     pub action: String,
     pub path: Option<String>,
     pub app_id: String,
+    pub name: Option<String>,
+    pub props: Option<BTreeMap<String, PropertyValue>>,
+    pub campaign: Campaign,
+}
+
+/// A single value of a custom event's `props` object.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+#[derive(Deserialize)]
+struct IntermediateEvent {
+    ts: Option<DateTime<Utc>>,
+    entity: String,
+    action: String,
+    path: Option<String>,
+
+    #[serde(rename = "appId")]
+    app_id: String,
+
+    name: Option<String>,
+    props: Option<BTreeMap<String, PropertyValue>>,
+
+    #[serde(flatten)]
+    campaign: Campaign,
 }
 
 struct EventVisitor;
@@ -39,44 +75,13 @@ impl<'de> Visitor<'de> for EventVisitor {
     where
         E: serde::de::Error,
     {
-        #[derive(Deserialize)]
-        struct IntermediateEvent {
-            ts: Option<DateTime<Utc>>,
-            entity: String,
-            action: String,
-            path: Option<String>,
-
-            #[serde(rename = "appId")]
-            app_id: String,
-        }
-
-        let intermediate: IntermediateEvent =
-            serde_json::from_str(&v).map_err(serde::de::Error::custom)?;
-
-        Ok(Event {
-            ts: intermediate.ts,
-            entity: intermediate.entity,
-            action: intermediate.action,
-            path: intermediate.path,
-            app_id: intermediate.app_id,
-        })
+        self.visit_str(&v)
     }
 
     fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        #[derive(Deserialize)]
-        struct IntermediateEvent {
-            ts: Option<DateTime<Utc>>,
-            entity: String,
-            action: String,
-            path: Option<String>,
-
-            #[serde(rename = "appId")]
-            app_id: String,
-        }
-
         let intermediate: IntermediateEvent =
             serde_json::from_str(v).map_err(serde::de::Error::custom)?;
 
@@ -86,6 +91,9 @@ impl<'de> Visitor<'de> for EventVisitor {
             action: intermediate.action,
             path: intermediate.path,
             app_id: intermediate.app_id,
+            name: intermediate.name,
+            props: intermediate.props,
+            campaign: intermediate.campaign,
         })
     }
 }
@@ -99,58 +107,916 @@ impl<'de> Deserialize<'de> for Event {
     }
 }
 
+/// Attributes the collector derives while ingesting an event. They are stored as JSON in the
+/// `context` column next to the untouched payload.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct EventContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub browser: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub browser_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visitor_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer_domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_bot: Option<bool>,
+}
+
+impl EventContext {
+    fn from_json_text<'de, D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        serde_json::from_str(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A validated event waiting to be written to the buffer.
+#[derive(Debug)]
+pub struct PendingEvent {
+    pub id: String,
+    pub recorded_at: DateTime<Utc>,
+    pub recorded_by: String,
+    pub event: String,
+    pub context: EventContext,
+    /// The visitor the event belongs to, if it can be sessionized.
+    pub visit: Option<Visit>,
+    /// The state of the event's session after this event, set by the writer once it has
+    /// assigned the event to a session.
+    pub session: Option<Session>,
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
 pub struct EventRecord {
+    /// The buffer the event was numbered in.
+    #[serde(default)]
+    pub buffer_id: String,
+    /// The event's position in the buffer, which exporters keep their checkpoints by.
+    #[serde(default)]
+    pub sequence: i64,
     pub id: String,
     pub recorded_at: DateTime<Utc>,
     pub recorded_by: Option<String>,
     pub event: Event,
+    #[serde(default, deserialize_with = "EventContext::from_json_text")]
+    pub context: EventContext,
 }
 
-pub async fn initialize() -> Result<Connection> {
-    let memory_database = Builder::new_local(":memory:")
-        .build()
-        .await
-        .expect("failed to create in-memory database");
+/// The connections to the event buffer. The writer task gets a connection of its own, so its
+/// transactions never take in the statements other tasks run on the shared `connection`.
+#[derive(Debug)]
+pub struct Buffer {
+    pub connection: Connection,
+    pub writer: Connection,
+}
+
+impl Buffer {
+    /// Opens the event buffer. With `BUFFER_PATH` set, events are kept in that database file so
+    /// they survive a crash or restart until every exporter has picked them up; otherwise they
+    /// are only kept in memory.
+    pub async fn build() -> Result<Self> {
+        match std::env::var("BUFFER_PATH") {
+            Ok(path) if !path.is_empty() => Self::open(Path::new(&path)).await,
+            _ => Self::in_memory().await,
+        }
+    }
 
-    let connection = memory_database.connect()?;
-    connection.execute(SCHEMA, ()).await?;
+    /// Creates an empty in-memory buffer. Its connections share the database through SQLite's
+    /// `memdb` VFS, under a name of its own.
+    pub async fn in_memory() -> Result<Self> {
+        let name = format!("file:/buffer-{}?vfs=memdb", generate_uuid_v4());
+        let database = Builder::new_local(name).build().await?;
+
+        let connection = connect(&database).await?;
+        migrate(&connection).await?;
+
+        Ok(Self {
+            connection,
+            writer: connect(&database).await?,
+        })
+    }
+
+    /// Opens or creates a file-backed buffer in WAL mode, so exports can read while events are
+    /// written. With `synchronous = NORMAL` a committed event survives the process being killed,
+    /// though not necessarily a power loss.
+    pub async fn open(path: &Path) -> Result<Self> {
+        let database = Builder::new_local(path).build().await?;
+        let connection = connect(&database).await?;
+
+        // The journal mode is kept in the file, and answers with its new value
+        connection.query("PRAGMA journal_mode = WAL", ()).await?;
+        connection
+            .execute("PRAGMA synchronous = NORMAL", ())
+            .await?;
+
+        migrate(&connection).await?;
+
+        let mut rows = connection.query("SELECT COUNT(*) FROM events", ()).await?;
+        let buffered: i64 = match rows.next().await? {
+            Some(row) => row.get(0)?,
+            None => 0,
+        };
+        info!(
+            "Opened buffer {} with {buffered} events, exporters resume from their checkpoints",
+            path.display()
+        );
+
+        let writer = connect(&database).await?;
+        writer.execute("PRAGMA synchronous = NORMAL", ()).await?;
+
+        Ok(Self { connection, writer })
+    }
+}
+
+/// Connects to the buffer, waiting for a transaction on another connection to finish rather
+/// than failing right away.
+async fn connect(database: &Database) -> Result<Connection> {
+    let connection = database.connect()?;
+
+    // `busy_timeout` answers with its new value, so it has to be queried
+    connection.query("PRAGMA busy_timeout = 5000", ()).await?;
 
     Ok(connection)
 }
 
+/// The shared connection to a new in-memory buffer.
+#[cfg(test)]
+pub async fn initialize() -> Result<Connection> {
+    Ok(Buffer::in_memory().await?.connection)
+}
+
+/// Applies the migrations the buffer has not seen yet, each in its own transaction.
+async fn migrate(connection: &Connection) -> Result<()> {
+    let mut rows = connection.query("PRAGMA user_version", ()).await?;
+    let applied: i64 = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => 0,
+    };
+    // A pending statement would keep migrations from dropping tables
+    drop(rows);
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let transaction = connection.transaction().await?;
+        transaction.execute_batch(migration).await?;
+        transaction
+            .execute(&format!("PRAGMA user_version = {}", version + 1), ())
+            .await?;
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
+
+/// The events a statement inserted, and the bytes of payload and context they take up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stored {
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+/// The ids of the events a statement inserted and the space they take up. Events whose id was
+/// already stored are left out of both.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Inserted {
+    pub ids: HashSet<String>,
+    pub stored: Stored,
+}
+
+/// The size of a stored event, as counted against the buffer's byte cap.
+pub const EVENT_SIZE: &str = "length(CAST(event AS BLOB)) + length(CAST(context AS BLOB))";
+
+/// Inserts all pending events with a single multi-row statement and upserts the sessions of the
+/// events it inserted, within a savepoint so the events and their sessions are either stored
+/// together or not at all.
+pub async fn insert_events(connection: &Connection, events: &[PendingEvent]) -> Result<Inserted> {
+    if events.is_empty() {
+        return Ok(Inserted::default());
+    }
+
+    connection.execute("SAVEPOINT insert_events", ()).await?;
+
+    match insert_rows(connection, events).await {
+        Ok(inserted) => {
+            connection.execute("RELEASE insert_events", ()).await?;
+            Ok(inserted)
+        }
+        Err(e) => {
+            connection.execute("ROLLBACK TO insert_events", ()).await?;
+            connection.execute("RELEASE insert_events", ()).await?;
+            Err(e)
+        }
+    }
+}
+
+async fn insert_rows(connection: &Connection, events: &[PendingEvent]) -> Result<Inserted> {
+    let placeholders = vec!["(?, ?, ?, json(?), json(?))"; events.len()].join(", ");
+    // Client-supplied ids may repeat once they have left the dedup window.
+    let query = format!(
+        "INSERT INTO events (id, recorded_at, recorded_by, event, context) VALUES {placeholders} \
+         ON CONFLICT (id) DO NOTHING RETURNING id, {EVENT_SIZE}"
+    );
+
+    let mut values = Vec::<Value>::with_capacity(events.len() * 5);
+    for event in events {
+        values.push(Value::from(event.id.clone()));
+        values.push(Value::from(event.recorded_at.to_rfc3339()));
+        values.push(Value::from(event.recorded_by.clone()));
+        values.push(Value::from(event.event.clone()));
+        values.push(Value::from(serde_json::to_string(&event.context)?));
+    }
+
+    // Only the rows that were inserted are returned, skipped ids are not
+    let mut rows = connection.query(&query, params_from_iter(values)).await?;
+    let mut inserted = Inserted::default();
+    while let Some(row) = rows.next().await? {
+        inserted.ids.insert(row.get::<String>(0)?);
+        inserted.stored.rows += 1;
+        inserted.stored.bytes += row.get::<u64>(1)?;
+    }
+
+    // A skipped event was counted in its session when it was first stored
+    upsert_sessions(
+        connection,
+        events
+            .iter()
+            .filter(|event| inserted.ids.contains(&event.id))
+            .filter_map(|event| event.session.as_ref()),
+    )
+    .await?;
+
+    Ok(inserted)
+}
+
+/// Writes the latest state of each session. Requests are handled concurrently, so an update
+/// is only applied if it is not older than the stored state. The sessions written are numbered
+/// with the next session sequence, which exporters keep their checkpoints by.
+async fn upsert_sessions<'a>(
+    connection: &Connection,
+    sessions: impl IntoIterator<Item = &'a Session>,
+) -> Result<u64> {
+    // A batch usually contains several events of the same session; only the last state matters.
+    let sessions: BTreeMap<&str, &Session> = sessions
+        .into_iter()
+        .map(|session| (session.id.as_str(), session))
+        .collect();
+
+    if sessions.is_empty() {
+        return Ok(0);
+    }
+
+    // Only the writer upserts sessions, so sequences are committed in the order they are taken
+    let mut rows = connection
+        .query(
+            "UPDATE buffer SET session_sequence = session_sequence + 1 RETURNING session_sequence",
+            (),
+        )
+        .await?;
+    let sequence: i64 = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => bail!("the buffer has no session sequence"),
+    };
+    drop(rows);
+
+    let placeholders = vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?)"; sessions.len()].join(", ");
+    let query = format!(
+        "INSERT INTO sessions (id, recorded_by, visitor_id, started_at, last_seen_at, entry_path, exit_path, page_count, sequence) \
+         VALUES {placeholders} \
+         ON CONFLICT (id) DO UPDATE SET \
+             last_seen_at = excluded.last_seen_at, \
+             entry_path = excluded.entry_path, \
+             exit_path = excluded.exit_path, \
+             page_count = excluded.page_count, \
+             sequence = excluded.sequence \
+         WHERE excluded.page_count >= sessions.page_count \
+             AND excluded.last_seen_at >= sessions.last_seen_at"
+    );
+
+    let mut values = Vec::<Value>::with_capacity(sessions.len() * 9);
+    for session in sessions.values() {
+        values.push(Value::from(session.id.clone()));
+        values.push(Value::from(session.recorded_by.clone()));
+        values.push(Value::from(session.visitor_id.clone()));
+        values.push(Value::from(session.started_at.to_rfc3339()));
+        values.push(Value::from(session.last_seen_at.to_rfc3339()));
+        values.push(session.entry_path.clone().map_or(Value::Null, Value::from));
+        values.push(session.exit_path.clone().map_or(Value::Null, Value::from));
+        values.push(Value::from(session.page_count));
+        values.push(Value::from(sequence));
+    }
+
+    Ok(connection.execute(&query, params_from_iter(values)).await?)
+}
+
+/// Reads up to `limit` events inserted after the one numbered `sequence`, in insertion order.
+/// An event that cannot be read fails the whole chunk, so the checkpoint never moves past it
+/// and it is not pruned before an operator has looked at it.
 #[cfg(feature = "export-parquet")]
-pub async fn flush_since(
+pub async fn flush_after(
     connection: Arc<Connection>,
-    since: DateTime<Utc>,
+    sequence: i64,
+    limit: u64,
 ) -> Result<Vec<EventRecord>> {
-    let rows = connection
+    let mut rows = connection
         .query(
-            "SELECT id, event, recorded_by, recorded_at FROM events WHERE recorded_at > ?",
-            params!(since.to_rfc3339()),
+            "SELECT buffer.buffer_id, sequence, events.id, event, recorded_by, recorded_at, \
+             context FROM events, buffer WHERE sequence > ? ORDER BY sequence LIMIT ?",
+            params!(sequence, limit),
         )
         .await?;
 
-    Ok(rows
-        .into_stream()
-        .filter_map(|row| match row {
-            Ok(valid_row) => from_row::<EventRecord>(&valid_row).ok(),
-            Err(e) => {
-                error!("Failed to process row: {:?}", e);
-                None
-            }
-        })
-        .collect::<Vec<EventRecord>>()
-        .await)
+    let mut records = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let sequence: i64 = row.get(1)?;
+        records.push(
+            from_row::<EventRecord>(&row)
+                .with_context(|| format!("could not read the event numbered {sequence}"))?,
+        );
+    }
+
+    Ok(records)
+}
+
+/// The sessions written after a session sequence, and the sequence of the last one.
+#[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+#[derive(Debug, Default)]
+pub struct SessionUpdates {
+    pub sessions: Vec<Session>,
+    /// Where the next export continues, the given sequence if there were no updates.
+    pub sequence: i64,
+}
+
+/// Reads the sessions written after the upsert numbered `sequence`. A session is read again
+/// every time it is extended, so exported rows are snapshots and the one with the latest
+/// `last_seen_at` wins.
+#[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+pub async fn flush_sessions_after(
+    connection: &Connection,
+    sequence: i64,
+) -> Result<SessionUpdates> {
+    let mut rows = connection
+        .query(
+            "SELECT id, recorded_by, visitor_id, started_at, last_seen_at, entry_path, exit_path, page_count, sequence \
+             FROM sessions WHERE sequence > ? ORDER BY sequence",
+            params!(sequence),
+        )
+        .await?;
+
+    let mut updates = SessionUpdates {
+        sessions: Vec::new(),
+        sequence,
+    };
+    while let Some(row) = rows.next().await? {
+        let id: String = row.get(0)?;
+        updates.sessions.push(
+            from_row::<Session>(&row).with_context(|| format!("could not read session {id}"))?,
+        );
+        updates.sequence = row.get(8)?;
+    }
+
+    Ok(updates)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::checkpoints::{self, Checkpoint};
     use chrono::TimeZone;
+    use libsql::params;
     use serde_json::json;
 
+    fn pending_event(id: &str, event: &str) -> PendingEvent {
+        PendingEvent {
+            id: id.to_string(),
+            recorded_at: Utc::now(),
+            recorded_by: "test-app".to_string(),
+            event: event.to_string(),
+            context: EventContext {
+                schema_version: Some("default@1.0.0".to_string()),
+                ..EventContext::default()
+            },
+            visit: None,
+            session: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_events_stores_every_event() {
+        let connection = initialize().await.unwrap();
+        let events = [
+            pending_event(
+                "first",
+                r#"{"entity":"page","action":"view","appId":"test-app"}"#,
+            ),
+            pending_event(
+                "second",
+                r#"{"entity":"anchor","action":"click","appId":"test-app"}"#,
+            ),
+        ];
+
+        let inserted = insert_events(&connection, &events).await.unwrap();
+        assert_eq!(inserted.stored.rows, 2);
+        assert!(inserted.stored.bytes > 0);
+
+        let mut rows = connection
+            .query("SELECT COUNT(*) FROM events", ())
+            .await
+            .unwrap();
+        let count: i64 = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_insert_events_is_all_or_nothing() {
+        let connection = initialize().await.unwrap();
+        let events = [
+            pending_event(
+                "valid",
+                r#"{"entity":"page","action":"view","appId":"test-app"}"#,
+            ),
+            pending_event("malformed", "not json"),
+        ];
+
+        assert!(insert_events(&connection, &events).await.is_err());
+
+        let mut rows = connection
+            .query("SELECT COUNT(*) FROM events", ())
+            .await
+            .unwrap();
+        let count: i64 = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_insert_events_skips_existing_ids() {
+        let connection = initialize().await.unwrap();
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+
+        let inserted = insert_events(&connection, &[pending_event("retried", event)])
+            .await
+            .unwrap();
+        assert_eq!(inserted.stored.rows, 1);
+
+        let inserted = insert_events(
+            &connection,
+            &[pending_event("retried", event), pending_event("new", event)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(inserted.ids, HashSet::from(["new".to_string()]));
+        assert_eq!(inserted.stored.rows, 1);
+
+        let mut rows = connection
+            .query("SELECT COUNT(*) FROM events", ())
+            .await
+            .unwrap();
+        let count: i64 = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[cfg(feature = "export-parquet")]
+    #[tokio::test]
+    async fn test_flush_after_reads_context() {
+        let connection = initialize().await.unwrap();
+        let events = [pending_event(
+            "with-context",
+            r#"{"entity":"page","action":"view","appId":"test-app"}"#,
+        )];
+        insert_events(&connection, &events).await.unwrap();
+
+        let records = flush_after(Arc::new(connection), 0, 100).await.unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].context.schema_version,
+            Some("default@1.0.0".to_string())
+        );
+    }
+
+    #[cfg(feature = "export-parquet")]
+    #[tokio::test]
+    async fn test_flush_after_skips_exported_events() {
+        let connection = Arc::new(initialize().await.unwrap());
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+
+        // Events sharing a timestamp are still told apart by their sequence
+        let first = pending_event("first", event);
+        let mut second = pending_event("second", event);
+        second.recorded_at = first.recorded_at;
+        insert_events(&connection, &[first, second]).await.unwrap();
+
+        let records = flush_after(connection.clone(), 0, 100).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].sequence < records[1].sequence);
+
+        let records = flush_after(connection, records[0].sequence, 100)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, "second");
+    }
+
+    #[cfg(feature = "export-parquet")]
+    #[tokio::test]
+    async fn test_flush_after_fails_on_unreadable_events() {
+        let connection = Arc::new(initialize().await.unwrap());
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+        insert_events(&connection, &[pending_event("readable", event)])
+            .await
+            .unwrap();
+        // Without an appId the payload cannot be read back
+        insert_events(
+            &connection,
+            &[pending_event("unreadable", r#"{"entity":"page"}"#)],
+        )
+        .await
+        .unwrap();
+
+        let error = flush_after(connection.clone(), 0, 100).await.unwrap_err();
+        assert_eq!(error.to_string(), "could not read the event numbered 2");
+
+        // Events before it are still read on their own
+        let records = flush_after(connection, 0, 1).await.unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[cfg(feature = "export-parquet")]
+    #[tokio::test]
+    async fn test_flush_after_reads_in_chunks() {
+        let connection = Arc::new(initialize().await.unwrap());
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+        let events: Vec<PendingEvent> = (0..5)
+            .map(|i| pending_event(&format!("event-{i}"), event))
+            .collect();
+        insert_events(&connection, &events).await.unwrap();
+
+        let mut sequence = 0;
+        let mut chunks = Vec::new();
+        loop {
+            let records = flush_after(connection.clone(), sequence, 2).await.unwrap();
+            let Some(last) = records.last() else {
+                break;
+            };
+            sequence = last.sequence;
+            chunks.push(records.iter().map(|r| r.id.clone()).collect::<Vec<_>>());
+        }
+
+        assert_eq!(
+            chunks,
+            [
+                vec!["event-0", "event-1"],
+                vec!["event-2", "event-3"],
+                vec!["event-4"]
+            ]
+        );
+    }
+
+    #[cfg(feature = "export-parquet")]
+    #[tokio::test]
+    async fn test_flush_after_reads_the_buffer_id() {
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+        let mut buffer_ids = Vec::new();
+        for _ in 0..2 {
+            let connection = Arc::new(initialize().await.unwrap());
+            insert_events(
+                &connection,
+                &[
+                    pending_event("first", event),
+                    pending_event("second", event),
+                ],
+            )
+            .await
+            .unwrap();
+
+            let records = flush_after(connection, 0, 100).await.unwrap();
+            assert_eq!(records[0].buffer_id.len(), 32);
+            assert_eq!(records[0].buffer_id, records[1].buffer_id);
+            buffer_ids.push(records[0].buffer_id.clone());
+        }
+
+        // Every new in-memory buffer numbers its events from the start again
+        assert_ne!(buffer_ids[0], buffer_ids[1]);
+    }
+
+    #[tokio::test]
+    async fn test_sequence_is_not_reused_after_deleting_the_newest_event() {
+        let connection = initialize().await.unwrap();
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+
+        insert_events(&connection, &[pending_event("first", event)])
+            .await
+            .unwrap();
+        connection.execute("DELETE FROM events", ()).await.unwrap();
+        insert_events(&connection, &[pending_event("second", event)])
+            .await
+            .unwrap();
+
+        let mut rows = connection
+            .query("SELECT sequence FROM events WHERE id = 'second'", ())
+            .await
+            .unwrap();
+        let sequence: i64 = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(sequence, 2);
+    }
+
+    #[tokio::test]
+    async fn test_insert_events_rolls_back_events_when_sessions_fail() {
+        let connection = initialize().await.unwrap();
+        let at = Utc::now();
+        let mut event = pending_event("sessionized", r#"{"entity":"page","action":"view"}"#);
+        event.session = Some(Session {
+            id: "session".to_string(),
+            recorded_by: "test-app".to_string(),
+            visitor_id: "visitor".to_string(),
+            started_at: at,
+            last_seen_at: at,
+            entry_path: None,
+            exit_path: None,
+            page_count: 1,
+        });
+
+        // Without the table the session upsert fails after the events were inserted
+        connection.execute("DROP TABLE sessions", ()).await.unwrap();
+        assert!(insert_events(&connection, &[event]).await.is_err());
+
+        let mut rows = connection
+            .query("SELECT COUNT(*) FROM events", ())
+            .await
+            .unwrap();
+        let count: i64 = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_insert_events_upserts_latest_session_state() {
+        let connection = initialize().await.unwrap();
+        let started_at = Utc.with_ymd_and_hms(2030, 1, 1, 12, 0, 0).unwrap();
+        let session = Session {
+            id: "session".to_string(),
+            recorded_by: "test-app".to_string(),
+            visitor_id: "visitor".to_string(),
+            started_at,
+            last_seen_at: started_at,
+            entry_path: Some("/".to_string()),
+            exit_path: Some("/".to_string()),
+            page_count: 1,
+        };
+        let later = Session {
+            last_seen_at: started_at + chrono::TimeDelta::minutes(5),
+            exit_path: Some("/about".to_string()),
+            page_count: 2,
+            ..session.clone()
+        };
+
+        let mut first = pending_event("first", r#"{"entity":"page","action":"view"}"#);
+        first.session = Some(session.clone());
+        let mut second = pending_event("second", r#"{"entity":"page","action":"view"}"#);
+        second.session = Some(later.clone());
+        insert_events(&connection, &[first, second]).await.unwrap();
+
+        // A stale update from a concurrent request must not roll the session back.
+        let mut stale = pending_event("stale", r#"{"entity":"page","action":"view"}"#);
+        stale.session = Some(session);
+        insert_events(&connection, &[stale]).await.unwrap();
+
+        let mut rows = connection
+            .query("SELECT exit_path, page_count FROM sessions", ())
+            .await
+            .unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        assert_eq!(row.get::<String>(0).unwrap(), "/about");
+        assert_eq!(row.get::<i64>(1).unwrap(), 2);
+        assert!(rows.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_insert_events_skips_sessions_of_existing_ids() {
+        let connection = initialize().await.unwrap();
+        let started_at = Utc.with_ymd_and_hms(2030, 1, 1, 12, 0, 0).unwrap();
+        let session = Session {
+            id: "session".to_string(),
+            recorded_by: "test-app".to_string(),
+            visitor_id: "visitor".to_string(),
+            started_at,
+            last_seen_at: started_at,
+            entry_path: Some("/".to_string()),
+            exit_path: Some("/".to_string()),
+            page_count: 1,
+        };
+
+        let mut event = pending_event("retried", r#"{"entity":"page","action":"view"}"#);
+        event.session = Some(session.clone());
+        insert_events(&connection, &[event]).await.unwrap();
+
+        // A replay of the same event, e.g. after a restart, is sessionized as another page view
+        let mut replayed = pending_event("retried", r#"{"entity":"page","action":"view"}"#);
+        replayed.session = Some(Session {
+            last_seen_at: started_at + chrono::TimeDelta::minutes(5),
+            page_count: 2,
+            ..session
+        });
+        let inserted = insert_events(&connection, &[replayed]).await.unwrap();
+        assert!(inserted.ids.is_empty());
+
+        let mut rows = connection
+            .query("SELECT page_count FROM sessions", ())
+            .await
+            .unwrap();
+        assert_eq!(rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap(), 1);
+    }
+
+    #[cfg(any(feature = "export-parquet", feature = "export-postgres"))]
+    #[tokio::test]
+    async fn test_flush_sessions_after_reads_sessions_written_since() {
+        let connection = initialize().await.unwrap();
+        let now = Utc::now();
+        let session = |id: &str, last_seen_at: DateTime<Utc>| {
+            let mut event = pending_event(id, r#"{"entity":"page","action":"view"}"#);
+            event.session = Some(Session {
+                id: id.to_string(),
+                recorded_by: "test-app".to_string(),
+                visitor_id: "visitor".to_string(),
+                started_at: last_seen_at,
+                last_seen_at,
+                entry_path: None,
+                exit_path: None,
+                page_count: 0,
+            });
+            event
+        };
+        insert_events(&connection, &[session("active", now)])
+            .await
+            .unwrap();
+
+        let updates = flush_sessions_after(&connection, 0).await.unwrap();
+        assert_eq!(updates.sessions.len(), 1);
+        assert_eq!(updates.sessions[0].id, "active");
+        assert_eq!(updates.sessions[0].entry_path, None);
+
+        let exported = updates.sequence;
+        let updates = flush_sessions_after(&connection, exported).await.unwrap();
+        assert!(updates.sessions.is_empty());
+        assert_eq!(updates.sequence, exported);
+
+        // A replayed event's session is older than any export, but written after it
+        insert_events(
+            &connection,
+            &[session("replayed", now - chrono::TimeDelta::hours(1))],
+        )
+        .await
+        .unwrap();
+
+        let updates = flush_sessions_after(&connection, exported).await.unwrap();
+        assert_eq!(updates.sessions.len(), 1);
+        assert_eq!(updates.sessions[0].id, "replayed");
+        assert!(updates.sequence > exported);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_connections_share_events_but_not_transactions() {
+        // A file-backed buffer lets the shared connection read during the writer's transaction
+        let path = std::env::temp_dir().join(format!("buffer-{}.db", generate_uuid_v4()));
+        let buffer = Buffer::open(&path).await.unwrap();
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+        let ids = |connection: &Connection| {
+            let connection = connection.clone();
+            async move {
+                let mut rows = connection.query("SELECT id FROM events", ()).await.unwrap();
+                let mut ids = Vec::new();
+                while let Some(row) = rows.next().await.unwrap() {
+                    ids.push(row.get::<String>(0).unwrap());
+                }
+                ids
+            }
+        };
+
+        let transaction = buffer.writer.transaction().await.unwrap();
+        insert_events(&transaction, &[pending_event("rolled-back", event)])
+            .await
+            .unwrap();
+        assert!(ids(&buffer.connection).await.is_empty());
+        transaction.rollback().await.unwrap();
+
+        insert_events(&buffer.writer, &[pending_event("stored", event)])
+            .await
+            .unwrap();
+        assert_eq!(ids(&buffer.connection).await, ["stored"]);
+
+        drop(buffer);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_keeps_events_across_restarts() {
+        let path = std::env::temp_dir().join(format!(
+            "buffer-{}.db",
+            crate::utilities::generate_uuid_v4()
+        ));
+
+        let connection = Buffer::open(&path).await.unwrap().connection;
+        let event = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+        insert_events(
+            &connection,
+            &[
+                pending_event("exported", event),
+                pending_event("durable", event),
+            ],
+        )
+        .await
+        .unwrap();
+        let acknowledged = Checkpoint {
+            sequence: 1,
+            ..Checkpoint::start(crate::exporter::POSTGRESQL)
+        };
+        checkpoints::set(&connection, &acknowledged).await.unwrap();
+
+        let mut rows = connection.query("PRAGMA journal_mode", ()).await.unwrap();
+        let journal_mode: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(journal_mode, "wal");
+        let mut rows = connection
+            .query("SELECT buffer_id FROM buffer", ())
+            .await
+            .unwrap();
+        let buffer_id: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        drop(rows);
+        drop(connection);
+
+        // Reopening must not run the migrations again
+        let connection = Buffer::open(&path).await.unwrap().connection;
+        let mut rows = connection.query("PRAGMA user_version", ()).await.unwrap();
+        let version: i64 = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(version, MIGRATIONS.len() as i64);
+
+        // Exporters resume after the last event they acknowledged before the restart
+        let checkpoint = checkpoints::get(&connection, crate::exporter::POSTGRESQL)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint, acknowledged);
+        assert_eq!(
+            checkpoints::pending(&connection, &checkpoint)
+                .await
+                .unwrap(),
+            1
+        );
+
+        let mut rows = connection
+            .query(
+                "SELECT id FROM events WHERE sequence > ?",
+                params![checkpoint.sequence],
+            )
+            .await
+            .unwrap();
+        let id: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(id, "durable");
+
+        // The buffer keeps its id, so its sequences continue rather than start over
+        let mut rows = connection
+            .query("SELECT buffer_id FROM buffer", ())
+            .await
+            .unwrap();
+        let reopened: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(reopened, buffer_id);
+
+        drop(rows);
+        drop(connection);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_events_with_no_events() {
+        let connection = initialize().await.unwrap();
+        assert_eq!(
+            insert_events(&connection, &[]).await.unwrap(),
+            Inserted::default()
+        );
+    }
+
     #[test]
     fn test_event_record_deserialization_success() {
         let json_data = json!({
@@ -206,6 +1072,34 @@ mod tests {
         assert_eq!(event_record.event.app_id, "my-app");
     }
 
+    #[test]
+    fn test_event_record_deserialization_with_campaign() {
+        let json_data = json!({
+            "id": "test-id-321",
+            "recorded_at": "2023-01-01T12:00:00Z",
+            "recorded_by": "test-user",
+            "event": r#"{
+                "entity": "page",
+                "action": "view",
+                "path": "/pricing",
+                "appId": "my-app",
+                "utm_source": "newsletter",
+                "utm_medium": "email"
+            }"#
+        });
+
+        let event_record: EventRecord = serde_json::from_value(json_data).unwrap();
+
+        assert_eq!(
+            event_record.event.campaign,
+            Campaign {
+                utm_source: Some("newsletter".to_string()),
+                utm_medium: Some("email".to_string()),
+                ..Campaign::default()
+            }
+        );
+    }
+
     #[test]
     fn test_event_record_deserialization_invalid_event_json() {
         let json_data = json!({
@@ -287,6 +1181,35 @@ mod tests {
         assert_eq!(wrapper.event_data.app_id, "holiday-tracker");
     }
 
+    #[test]
+    fn test_event_deserialization_custom_props() {
+        let wrapper_json = json!({
+            "event_data": r#"{
+                "entity": "custom",
+                "action": "track",
+                "name": "video_play",
+                "props": {"title": "intro", "seconds": 12.5, "autoplay": true},
+                "appId": "media-app"
+            }"#
+        });
+
+        #[derive(Deserialize)]
+        struct EventWrapper {
+            event_data: Event,
+        }
+
+        let wrapper: EventWrapper = serde_json::from_value(wrapper_json).unwrap();
+        let props = wrapper.event_data.props.unwrap();
+
+        assert_eq!(wrapper.event_data.name, Some("video_play".to_string()));
+        assert_eq!(
+            props.get("title"),
+            Some(&PropertyValue::String("intro".to_string()))
+        );
+        assert_eq!(props.get("seconds"), Some(&PropertyValue::Number(12.5)));
+        assert_eq!(props.get("autoplay"), Some(&PropertyValue::Bool(true)));
+    }
+
     #[test]
     fn test_complete_event_record_roundtrip() {
         let original_json = json!({
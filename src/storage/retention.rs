@@ -0,0 +1,639 @@
+//! Bounded retention of the event buffer.
+//!
+//! Exported events are pruned once they are behind the checkpoint of every enabled exporter and
+//! a grace period has passed. Until then the buffer is capped at `BUFFER_MAX_ROWS` events and
+//! `BUFFER_MAX_BYTES` bytes of payload and context, and events that do not fit are either
+//! rejected or make room by dropping the oldest events, as set by `BUFFER_OVERFLOW_POLICY`.
+
+use crate::{
+    errors::ApplicationError,
+    exporter::prometheus::{Metrics, Overflowed},
+    storage::{
+        checkpoints,
+        memory::{EVENT_SIZE, PendingEvent, Stored, insert_events},
+    },
+    utilities::get_environment_variable_with_default,
+};
+use anyhow::{Result, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use libsql::{Connection, Rows, params};
+use std::collections::HashSet;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const DEFAULT_GRACE_SECONDS: i64 = 300;
+/// The deployment limits the collector to 128M of memory, which an in-memory buffer shares with
+/// SQLite's indexes and page cache, the writer's queue and everything else. A quarter of it is
+/// left to payload and context, and the row cap allows for events of about 320 bytes each, so
+/// that whichever cap applies first the pod stays well within its limit. Raise both along with
+/// the memory limit.
+const DEFAULT_MAX_ROWS: u64 = 100_000;
+const DEFAULT_MAX_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Dropping up to a hundredth of the buffer at once keeps a full buffer from dropping on every
+/// insert, but never more than this many events.
+const DROP_CHUNK: u64 = 100;
+
+/// What happens to events that do not fit into a full buffer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// New events are answered with `503 Service Unavailable`, so clients can retry them.
+    #[default]
+    Reject,
+    /// The oldest events are dropped, exported or not, to make room for new ones.
+    DropOldest,
+}
+
+impl OverflowPolicy {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "drop_oldest" => Ok(Self::DropOldest),
+            _ => bail!("invalid buffer overflow policy {value}, expected reject or drop_oldest"),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::DropOldest => "drop_oldest",
+        }
+    }
+}
+
+/// Events up to `sequence` that were recorded before `recorded_at`, and sessions written up to
+/// `session_sequence` that were last seen before then, can be pruned.
+#[derive(Debug)]
+struct Cutoff {
+    sequence: i64,
+    session_sequence: i64,
+    recorded_at: DateTime<Utc>,
+}
+
+/// The events in the buffer and the bytes of payload and context they take up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, stored: Stored) {
+        self.rows += stored.rows;
+        self.bytes += stored.bytes;
+    }
+
+    fn remove(&mut self, removed: Stored) {
+        self.rows = self.rows.saturating_sub(removed.rows);
+        self.bytes = self.bytes.saturating_sub(removed.bytes);
+    }
+}
+
+#[derive(Debug)]
+pub struct Retention {
+    grace: TimeDelta,
+    /// Zero disables the cap, as does `max_bytes`.
+    max_rows: u64,
+    max_bytes: u64,
+    overflow: OverflowPolicy,
+    /// The exporters that have to export an event before it is pruned.
+    exporters: Vec<&'static str>,
+    /// Kept up to date by every insert and delete, so the caps are checked without counting
+    /// the buffer. Inserts wait for each other, which the single writer does anyway.
+    usage: Mutex<Usage>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            grace: TimeDelta::seconds(DEFAULT_GRACE_SECONDS),
+            max_rows: DEFAULT_MAX_ROWS,
+            max_bytes: DEFAULT_MAX_BYTES,
+            overflow: OverflowPolicy::default(),
+            exporters: Vec::new(),
+            usage: Mutex::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Retention {
+    /// Default retention with a cap of `max_rows` events, rejecting events that do not fit.
+    pub fn with_max_rows(max_rows: u64) -> Self {
+        Self {
+            max_rows,
+            ..Self::default()
+        }
+    }
+
+    /// Default retention with a cap of `max_rows` events, dropping the oldest ones for new ones.
+    pub fn dropping_oldest(max_rows: u64) -> Self {
+        Self {
+            max_rows,
+            overflow: OverflowPolicy::DropOldest,
+            ..Self::default()
+        }
+    }
+}
+
+impl Retention {
+    /// Reads `BUFFER_RETENTION_GRACE_SECONDS`, `BUFFER_MAX_ROWS`, `BUFFER_MAX_BYTES` and
+    /// `BUFFER_OVERFLOW_POLICY`.
+    pub fn build(exporters: Vec<&'static str>) -> Result<Self> {
+        let grace = get_environment_variable_with_default(
+            "BUFFER_RETENTION_GRACE_SECONDS",
+            DEFAULT_GRACE_SECONDS.to_string(),
+        )
+        .parse::<i64>()
+        .unwrap_or(DEFAULT_GRACE_SECONDS);
+
+        let max_rows =
+            get_environment_variable_with_default("BUFFER_MAX_ROWS", DEFAULT_MAX_ROWS.to_string())
+                .parse::<u64>()
+                .unwrap_or(DEFAULT_MAX_ROWS);
+
+        let max_bytes = get_environment_variable_with_default(
+            "BUFFER_MAX_BYTES",
+            DEFAULT_MAX_BYTES.to_string(),
+        )
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_MAX_BYTES);
+
+        let overflow = match std::env::var("BUFFER_OVERFLOW_POLICY") {
+            Ok(policy) => OverflowPolicy::parse(&policy)?,
+            Err(_) => OverflowPolicy::default(),
+        };
+
+        Ok(Self {
+            grace: TimeDelta::seconds(grace.max(0)),
+            max_rows,
+            max_bytes,
+            overflow,
+            exporters,
+            usage: Mutex::default(),
+        })
+    }
+
+    /// Counts the events already in the buffer, e.g. after a file-backed buffer was reopened.
+    pub async fn refresh(&self, connection: &Connection, metrics: &Metrics) -> Result<Usage> {
+        let mut usage = self.usage.lock().await;
+
+        let rows = connection
+            .query(&format!("SELECT {EVENT_SIZE} FROM events"), ())
+            .await?;
+        *usage = Usage::default();
+        usage.add(sum(rows).await?);
+
+        record(&usage, metrics);
+        Ok(*usage)
+    }
+
+    /// Stores events if they fit into the buffer, applying the overflow policy if they do not.
+    /// Returns the ids of the events stored, which leaves out ids that were already stored.
+    /// The usage is only updated once the events are stored, so it still matches the buffer
+    /// once the caller rolls back a failed insert, along with any events it dropped.
+    pub async fn insert(
+        &self,
+        connection: &Connection,
+        metrics: &Metrics,
+        events: &[PendingEvent],
+    ) -> Result<HashSet<String>, ApplicationError> {
+        let mut usage = self.usage.lock().await;
+        let incoming = estimate(events)?;
+        let mut next = *usage;
+
+        let mut dropped = Stored::default();
+        if !self.fits(&next, incoming) {
+            if self.overflow == OverflowPolicy::DropOldest {
+                dropped = self.drop_oldest(connection, &next, incoming).await?;
+            }
+            next.remove(dropped);
+
+            // A batch larger than the whole buffer cannot be made room for
+            if !self.fits(&next, incoming) {
+                metrics
+                    .overflowed_events
+                    .get_or_create(&Overflowed {
+                        policy: OverflowPolicy::Reject.label().to_string(),
+                    })
+                    .inc_by(events.len() as u64);
+                record(&usage, metrics);
+
+                return Err(ApplicationError::ServiceUnavailable(
+                    "The event buffer is full".to_string(),
+                ));
+            }
+        }
+
+        let inserted = insert_events(connection, events).await?;
+        next.add(inserted.stored);
+        *usage = next;
+
+        if dropped.rows > 0 {
+            warn!("Buffer is full, dropped the {} oldest events", dropped.rows);
+            metrics
+                .overflowed_events
+                .get_or_create(&Overflowed {
+                    policy: self.overflow.label().to_string(),
+                })
+                .inc_by(dropped.rows);
+        }
+
+        record(&usage, metrics);
+        Ok(inserted.ids)
+    }
+
+    /// Deletes the events and sessions every enabled exporter has exported, once they are older
+    /// than the grace period. Returns the number of events deleted.
+    pub async fn prune(&self, connection: &Connection, metrics: &Metrics) -> Result<u64> {
+        let Some(cutoff) = self.cutoff(connection, Utc::now()).await? else {
+            return Ok(0);
+        };
+
+        // The writer locks the usage within its transaction, so the usage is only locked once
+        // the delete got past that transaction, or each would wait for the other
+        let rows = connection
+            .query(
+                &format!(
+                    "DELETE FROM events WHERE sequence <= ? AND recorded_at <= ? \
+                     RETURNING {EVENT_SIZE}"
+                ),
+                params![cutoff.sequence, cutoff.recorded_at.to_rfc3339()],
+            )
+            .await?;
+        let pruned = sum(rows).await?;
+        let mut usage = self.usage.lock().await;
+        usage.remove(pruned);
+
+        // A session that is extended later is written again with its whole state
+        connection
+            .execute(
+                "DELETE FROM sessions WHERE sequence <= ? AND last_seen_at <= ?",
+                params![cutoff.session_sequence, cutoff.recorded_at.to_rfc3339()],
+            )
+            .await?;
+
+        if pruned.rows > 0 {
+            info!("Pruned {} exported events from the buffer", pruned.rows);
+        }
+
+        metrics.pruned_events.inc_by(pruned.rows);
+        record(&usage, metrics);
+        Ok(pruned.rows)
+    }
+
+    /// How far the buffer can be pruned, by the checkpoints of the enabled exporters and the
+    /// grace period. `None` while an exporter has not exported anything yet.
+    async fn cutoff(&self, connection: &Connection, now: DateTime<Utc>) -> Result<Option<Cutoff>> {
+        let mut cutoff = Cutoff {
+            sequence: i64::MAX,
+            session_sequence: i64::MAX,
+            recorded_at: now - self.grace,
+        };
+
+        for exporter in &self.exporters {
+            let Some(checkpoint) = checkpoints::get(connection, exporter).await? else {
+                return Ok(None);
+            };
+
+            cutoff.sequence = cutoff.sequence.min(checkpoint.sequence);
+            cutoff.session_sequence = cutoff.session_sequence.min(checkpoint.session_sequence);
+        }
+
+        Ok(Some(cutoff))
+    }
+
+    fn fits(&self, usage: &Usage, incoming: Stored) -> bool {
+        (self.max_rows == 0 || usage.rows + incoming.rows <= self.max_rows)
+            && (self.max_bytes == 0 || usage.bytes + incoming.bytes <= self.max_bytes)
+    }
+
+    /// Drops the oldest events until the incoming ones fit, a chunk at a time. Nothing is
+    /// dropped for events that would not even fit into an empty buffer.
+    async fn drop_oldest(
+        &self,
+        connection: &Connection,
+        usage: &Usage,
+        incoming: Stored,
+    ) -> Result<Stored> {
+        let mut dropped = Stored::default();
+
+        if !self.fits(&Usage::default(), incoming) {
+            return Ok(dropped);
+        }
+
+        let chunk = match self.max_rows {
+            0 => DROP_CHUNK,
+            max_rows => (max_rows / 100).clamp(1, DROP_CHUNK),
+        };
+
+        loop {
+            let mut remaining = *usage;
+            remaining.remove(dropped);
+
+            if self.fits(&remaining, incoming) {
+                return Ok(dropped);
+            }
+
+            let excess = (remaining.rows + incoming.rows).saturating_sub(self.max_rows);
+            let rows = connection
+                .query(
+                    &format!(
+                        "DELETE FROM events WHERE sequence IN \
+                         (SELECT sequence FROM events ORDER BY sequence LIMIT ?) \
+                         RETURNING {EVENT_SIZE}"
+                    ),
+                    params![excess.max(chunk)],
+                )
+                .await?;
+
+            let chunk = sum(rows).await?;
+            if chunk.rows == 0 {
+                return Ok(dropped);
+            }
+
+            dropped.rows += chunk.rows;
+            dropped.bytes += chunk.bytes;
+        }
+    }
+}
+
+/// The size of events before they are stored, which is about what they take up once stored.
+fn estimate(events: &[PendingEvent]) -> Result<Stored> {
+    let mut estimate = Stored {
+        rows: events.len() as u64,
+        bytes: 0,
+    };
+
+    for event in events {
+        estimate.bytes += (event.event.len() + serde_json::to_string(&event.context)?.len()) as u64;
+    }
+
+    Ok(estimate)
+}
+
+/// Adds up rows of event sizes.
+async fn sum(mut rows: Rows) -> Result<Stored> {
+    let mut sum = Stored::default();
+    while let Some(row) = rows.next().await? {
+        sum.rows += 1;
+        sum.bytes += row.get::<u64>(0)?;
+    }
+
+    Ok(sum)
+}
+
+fn record(usage: &Usage, metrics: &Metrics) {
+    metrics.buffered_events.set(usage.rows as i64);
+    metrics.buffered_bytes.set(usage.bytes as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        exporter::PARQUET,
+        sessions::Session,
+        storage::{checkpoints::Checkpoint, memory::initialize},
+    };
+    use chrono::TimeZone;
+
+    const EVENT: &str = r#"{"entity":"page","action":"view","appId":"test-app"}"#;
+
+    fn pending_event(id: &str, recorded_at: DateTime<Utc>) -> PendingEvent {
+        PendingEvent {
+            id: id.to_string(),
+            recorded_at,
+            recorded_by: "test-app".to_string(),
+            event: EVENT.to_string(),
+            context: Default::default(),
+            visit: None,
+            session: None,
+        }
+    }
+
+    fn at(minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2020, 1, 1, 12, minute, 0).unwrap()
+    }
+
+    fn capped(max_rows: u64, overflow: OverflowPolicy) -> Retention {
+        Retention {
+            max_rows,
+            overflow,
+            ..Retention::default()
+        }
+    }
+
+    async fn ids(connection: &Connection) -> Vec<String> {
+        let mut rows = connection
+            .query("SELECT id FROM events ORDER BY recorded_at", ())
+            .await
+            .unwrap();
+
+        let mut ids = Vec::new();
+        while let Some(row) = rows.next().await.unwrap() {
+            ids.push(row.get(0).unwrap());
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_insert_rejects_events_beyond_cap() {
+        let connection = initialize().await.unwrap();
+        let metrics = Metrics::default();
+        let retention = capped(2, OverflowPolicy::Reject);
+
+        for (id, minute) in [("first", 0), ("second", 1)] {
+            retention
+                .insert(&connection, &metrics, &[pending_event(id, at(minute))])
+                .await
+                .unwrap();
+        }
+
+        let result = retention
+            .insert(&connection, &metrics, &[pending_event("third", at(2))])
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(ApplicationError::ServiceUnavailable(_))
+        ));
+        assert_eq!(ids(&connection).await, ["first", "second"]);
+        assert_eq!(metrics.buffered_events.get(), 2);
+        assert_eq!(
+            metrics
+                .overflowed_events
+                .get_or_create(&Overflowed {
+                    policy: "reject".to_string()
+                })
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_insert_drops_oldest_events_beyond_cap() {
+        let connection = initialize().await.unwrap();
+        let metrics = Metrics::default();
+        let retention = capped(3, OverflowPolicy::DropOldest);
+
+        for (id, minute) in [("first", 0), ("second", 1), ("third", 2), ("fourth", 3)] {
+            retention
+                .insert(&connection, &metrics, &[pending_event(id, at(minute))])
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(ids(&connection).await, ["second", "third", "fourth"]);
+        assert_eq!(metrics.buffered_events.get(), 3);
+
+        // A batch that would not fit into an empty buffer does not drop anything
+        let batch: Vec<PendingEvent> = (0..4)
+            .map(|i| pending_event(&format!("batch-{i}"), at(10 + i)))
+            .collect();
+        let result = retention.insert(&connection, &metrics, &batch).await;
+
+        assert!(matches!(
+            result,
+            Err(ApplicationError::ServiceUnavailable(_))
+        ));
+        assert_eq!(ids(&connection).await, ["second", "third", "fourth"]);
+    }
+
+    #[tokio::test]
+    async fn test_insert_enforces_byte_cap() {
+        let connection = initialize().await.unwrap();
+        let metrics = Metrics::default();
+        let retention = Retention {
+            max_rows: 0,
+            max_bytes: 3 * EVENT.len() as u64,
+            ..Retention::default()
+        };
+
+        let mut stored = 0;
+        for i in 0..5 {
+            if retention
+                .insert(
+                    &connection,
+                    &metrics,
+                    &[pending_event(&i.to_string(), at(i))],
+                )
+                .await
+                .is_ok()
+            {
+                stored += 1;
+            }
+        }
+
+        // Every event takes up its payload and an empty context
+        assert_eq!(stored, 2);
+        let usage = retention.refresh(&connection, &metrics).await.unwrap();
+        assert_eq!(usage.rows, 2);
+        assert!(usage.bytes <= retention.max_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_prune_waits_for_every_exporter() {
+        let connection = initialize().await.unwrap();
+        let metrics = Metrics::default();
+        let retention = Retention {
+            grace: TimeDelta::zero(),
+            exporters: vec![PARQUET],
+            ..Retention::default()
+        };
+
+        for (id, minute) in [("exported", 0), ("pending", 5)] {
+            retention
+                .insert(&connection, &metrics, &[pending_event(id, at(minute))])
+                .await
+                .unwrap();
+        }
+
+        // Nothing is pruned before the exporter exported anything
+        assert_eq!(retention.prune(&connection, &metrics).await.unwrap(), 0);
+
+        checkpoints::set(
+            &connection,
+            &Checkpoint {
+                sequence: 1,
+                ..Checkpoint::start(PARQUET)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(retention.prune(&connection, &metrics).await.unwrap(), 1);
+        assert_eq!(ids(&connection).await, ["pending"]);
+
+        assert_eq!(metrics.pruned_events.get(), 1);
+        assert_eq!(metrics.buffered_events.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prune_keeps_events_within_grace_period() {
+        let connection = initialize().await.unwrap();
+        let metrics = Metrics::default();
+        let retention = Retention::default();
+
+        let now = Utc::now();
+        for (id, recorded_at) in [("old", now - TimeDelta::hours(1)), ("recent", now)] {
+            retention
+                .insert(&connection, &metrics, &[pending_event(id, recorded_at)])
+                .await
+                .unwrap();
+        }
+
+        // Without exporters only the grace period applies
+        assert_eq!(retention.prune(&connection, &metrics).await.unwrap(), 1);
+        assert_eq!(ids(&connection).await, ["recent"]);
+    }
+
+    #[tokio::test]
+    async fn test_prune_deletes_sessions_last_seen_before_grace_period() {
+        let connection = initialize().await.unwrap();
+        let metrics = Metrics::default();
+        let retention = Retention::default();
+
+        let now = Utc::now();
+        for (id, last_seen_at) in [("old", now - TimeDelta::hours(1)), ("recent", now)] {
+            let mut event = pending_event(id, last_seen_at);
+            event.session = Some(Session {
+                id: id.to_string(),
+                recorded_by: "test-app".to_string(),
+                visitor_id: id.to_string(),
+                started_at: last_seen_at,
+                last_seen_at,
+                entry_path: None,
+                exit_path: None,
+                page_count: 1,
+            });
+            retention
+                .insert(&connection, &metrics, &[event])
+                .await
+                .unwrap();
+        }
+
+        retention.prune(&connection, &metrics).await.unwrap();
+
+        let mut rows = connection
+            .query("SELECT id FROM sessions", ())
+            .await
+            .unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        assert_eq!(row.get::<String>(0).unwrap(), "recent");
+        assert!(rows.next().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_overflow_policy_parse() {
+        assert_eq!(
+            OverflowPolicy::parse(" Drop_Oldest ").unwrap(),
+            OverflowPolicy::DropOldest
+        );
+        assert_eq!(
+            OverflowPolicy::parse("reject").unwrap(),
+            OverflowPolicy::Reject
+        );
+        assert!(OverflowPolicy::parse("block").is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! The salt visitor ids are hashed with.
+//!
+//! The buffer keeps only the current day's salt, so a restart during the day keeps visitor ids
+//! stable while the previous day's salt is gone for good once it is replaced.
+
+use anyhow::{Result, anyhow};
+use chrono::NaiveDate;
+use libsql::{Connection, params};
+
+/// Returns the stored salt and the day it is used for, if one was stored.
+pub async fn get(connection: &Connection) -> Result<Option<(NaiveDate, [u8; 32])>> {
+    let mut rows = connection
+        .query("SELECT day, salt FROM visitor_salt WHERE id = 1", ())
+        .await?;
+
+    let Some(row) = rows.next().await? else {
+        return Ok(None);
+    };
+
+    let day = row.get::<String>(0)?.parse::<NaiveDate>()?;
+    let salt = row
+        .get::<Vec<u8>>(1)?
+        .try_into()
+        .map_err(|_| anyhow!("the stored visitor salt is not 32 bytes long"))?;
+
+    Ok(Some((day, salt)))
+}
+
+/// Stores the salt for a day, replacing the previous one.
+pub async fn replace(connection: &Connection, day: NaiveDate, salt: &[u8; 32]) -> Result<()> {
+    connection
+        .execute(
+            "INSERT INTO visitor_salt (id, day, salt) VALUES (1, ?, ?)
+             ON CONFLICT (id) DO UPDATE SET day = excluded.day, salt = excluded.salt",
+            params![day.to_string(), salt.to_vec()],
+        )
+        .await?;
+
+    Ok(())
+}
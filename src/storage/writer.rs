@@ -0,0 +1,519 @@
+//! Batched writes to the event buffer.
+//!
+//! Requests hand their events to a single writer task through a bounded queue instead of each
+//! inserting them on the shared connection. The writer stores the events of every request that
+//! arrives within `WRITE_BATCH_INTERVAL_MS`, up to `WRITE_BATCH_SIZE` events, in one
+//! transaction on a connection of its own and answers each request once it is committed. It
+//! also assigns the events to sessions, which are only tracked once the transaction is
+//! committed. When `WRITE_QUEUE_CAPACITY` requests are waiting, further requests are answered
+//! with `503 Service Unavailable` right away rather than queueing up without bound.
+
+use crate::{
+    errors::ApplicationError,
+    exporter::prometheus::Metrics,
+    sessions::{SessionTracker, Staged},
+    storage::{memory::PendingEvent, retention::Retention},
+    utilities::get_environment_variable_with_default,
+};
+use anyhow::anyhow;
+use libsql::Connection;
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::{Instant, timeout_at},
+};
+use tracing::{Instrument, error, info_span};
+
+const DEFAULT_BATCH_SIZE: usize = 500;
+const DEFAULT_BATCH_INTERVAL_MS: u64 = 5;
+const DEFAULT_QUEUE_CAPACITY: usize = 1_000;
+
+/// How the writer groups requests into transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batching {
+    /// A transaction is committed once it holds this many events, without waiting any longer.
+    pub size: usize,
+    /// How long the writer waits for more requests after the first one of a batch.
+    pub interval: Duration,
+    /// The number of requests that can wait for the writer.
+    pub capacity: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_BATCH_SIZE,
+            interval: Duration::from_millis(DEFAULT_BATCH_INTERVAL_MS),
+            capacity: DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+}
+
+impl Batching {
+    /// Reads `WRITE_BATCH_SIZE`, `WRITE_BATCH_INTERVAL_MS` and `WRITE_QUEUE_CAPACITY`.
+    pub fn build() -> Self {
+        let size = get_environment_variable_with_default(
+            "WRITE_BATCH_SIZE",
+            DEFAULT_BATCH_SIZE.to_string(),
+        )
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_BATCH_SIZE);
+
+        let interval = get_environment_variable_with_default(
+            "WRITE_BATCH_INTERVAL_MS",
+            DEFAULT_BATCH_INTERVAL_MS.to_string(),
+        )
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_BATCH_INTERVAL_MS);
+
+        let capacity = get_environment_variable_with_default(
+            "WRITE_QUEUE_CAPACITY",
+            DEFAULT_QUEUE_CAPACITY.to_string(),
+        )
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_QUEUE_CAPACITY);
+
+        Self {
+            size: size.max(1),
+            interval: Duration::from_millis(interval),
+            // A channel needs room for at least one request
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+/// The events of a single request and where to report how they were stored.
+struct Write {
+    events: Vec<PendingEvent>,
+    reply: oneshot::Sender<Result<u64, ApplicationError>>,
+}
+
+/// Queues events for the writer task. Clones share the same queue.
+#[derive(Debug, Clone)]
+pub struct Writer {
+    sender: mpsc::Sender<Write>,
+    metrics: Arc<Metrics>,
+}
+
+impl Writer {
+    /// Starts the writer task, which runs until every `Writer` sharing its queue is dropped. It
+    /// then stores the requests still queued and finishes, so awaiting the returned handle after
+    /// dropping every `Writer` waits until every accepted event is committed.
+    pub fn spawn(
+        connection: Connection,
+        retention: Arc<Retention>,
+        sessions: Arc<SessionTracker>,
+        metrics: Arc<Metrics>,
+        batching: Batching,
+    ) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(batching.capacity);
+        let task = Task {
+            connection,
+            retention,
+            sessions,
+            metrics: metrics.clone(),
+            batching,
+        };
+        let handle = tokio::spawn(task.run(receiver));
+
+        (Self { sender, metrics }, handle)
+    }
+
+    /// Queues events and waits until they are stored within the buffer's caps and counted.
+    /// Returns the number of events stored, which leaves out ids that were already stored.
+    pub async fn write(&self, events: Vec<PendingEvent>) -> Result<u64, ApplicationError> {
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        let (reply, stored) = oneshot::channel();
+        self.sender
+            .try_send(Write { events, reply })
+            .map_err(|e| match e {
+                TrySendError::Full(_) => {
+                    self.metrics.rejected_writes.inc();
+                    ApplicationError::ServiceUnavailable("The write queue is full".to_string())
+                }
+                TrySendError::Closed(_) => {
+                    ApplicationError::Unknown(anyhow!("the buffer writer has stopped"))
+                }
+            })?;
+
+        stored
+            .await
+            .map_err(|_| ApplicationError::Unknown(anyhow!("the buffer writer has stopped")))?
+    }
+}
+
+struct Task {
+    /// Only the writer uses this connection, so no other statement ends up in its transactions.
+    connection: Connection,
+    retention: Arc<Retention>,
+    sessions: Arc<SessionTracker>,
+    metrics: Arc<Metrics>,
+    batching: Batching,
+}
+
+impl Task {
+    async fn run(self, mut receiver: mpsc::Receiver<Write>) {
+        while let Some(first) = receiver.recv().await {
+            let deadline = Instant::now() + self.batching.interval;
+            let mut count = first.events.len();
+            let mut batch = vec![first];
+
+            while count < self.batching.size {
+                match timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(write)) => {
+                        count += write.events.len();
+                        batch.push(write);
+                    }
+                    // The interval is over, or every request has been received
+                    Ok(None) | Err(_) => break,
+                }
+            }
+
+            self.store(batch)
+                .instrument(info_span!("write_batch", count))
+                .await;
+        }
+    }
+
+    /// Stores a batch and answers its requests. A failed commit fails every request in it.
+    async fn store(&self, mut batch: Vec<Write>) {
+        let results = match self.insert(&mut batch).await {
+            Ok(results) => results,
+            Err(e) => {
+                error!(
+                    "failed to write {} requests to the buffer: {e}",
+                    batch.len()
+                );
+
+                // The rolled back events were already counted against the caps
+                if let Err(e) = self
+                    .retention
+                    .refresh(&self.connection, &self.metrics)
+                    .await
+                {
+                    error!("failed to measure the buffer: {e}");
+                }
+
+                batch
+                    .iter()
+                    .map(|_| Err(ApplicationError::Unknown(anyhow!("{e}"))))
+                    .collect()
+            }
+        };
+
+        for (write, result) in batch.into_iter().zip(results) {
+            // Events whose id was already stored are neither counted nor reported as stored
+            let result = result.map(|ids| {
+                self.metrics
+                    .count_stored(write.events.iter().filter(|event| ids.contains(&event.id)));
+                ids.len() as u64
+            });
+
+            // The request may have been cancelled while it waited
+            let _ = write.reply.send(result);
+        }
+    }
+
+    /// Inserts each request's events within a savepoint of their own, so a request that fails,
+    /// e.g. because it does not fit into the buffer, is rolled back without failing the others
+    /// in the transaction. The sessions of failed requests are rolled back as well, and the rest
+    /// are tracked after the commit.
+    async fn insert(
+        &self,
+        batch: &mut [Write],
+    ) -> anyhow::Result<Vec<Result<HashSet<String>, ApplicationError>>> {
+        let transaction = self.connection.transaction().await?;
+        let mut staged = Staged::default();
+
+        let mut results = Vec::with_capacity(batch.len());
+        for write in batch.iter_mut() {
+            let savepoint = staged.savepoint();
+            self.sessionize(&mut staged, &mut write.events);
+
+            transaction.execute("SAVEPOINT write", ()).await?;
+            let result = self
+                .retention
+                .insert(&transaction, &self.metrics, &write.events)
+                .await;
+            if result.is_err() {
+                // Also restores the oldest events the request dropped to make room
+                transaction.execute("ROLLBACK TO write", ()).await?;
+                staged.rollback_to(savepoint);
+            }
+            transaction.execute("RELEASE write", ()).await?;
+            results.push(result);
+        }
+
+        transaction.commit().await?;
+        self.sessions.apply(staged);
+        Ok(results)
+    }
+
+    fn sessionize(&self, staged: &mut Staged, events: &mut [PendingEvent]) {
+        for event in events {
+            if let Some(visit) = &event.visit {
+                let session =
+                    self.sessions
+                        .track(staged, &event.recorded_by, visit, event.recorded_at);
+                event.context.session_id = Some(session.id.clone());
+                event.session = Some(session);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sessions::{PageView, Visit},
+        storage::memory::Buffer,
+        utilities::generate_uuid_v4,
+    };
+    use chrono::Utc;
+
+    fn pending_event() -> PendingEvent {
+        PendingEvent {
+            id: generate_uuid_v4(),
+            recorded_at: Utc::now(),
+            recorded_by: "test-app".to_string(),
+            event: r#"{"entity":"page","action":"view","appId":"test-app"}"#.to_string(),
+            context: Default::default(),
+            visit: None,
+            session: None,
+        }
+    }
+
+    async fn count(connection: &Connection) -> i64 {
+        let mut rows = connection
+            .query("SELECT COUNT(*) FROM events", ())
+            .await
+            .unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_write_stores_concurrent_requests() {
+        let Buffer { connection, writer } = Buffer::in_memory().await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let (writer, _) = Writer::spawn(
+            writer,
+            Arc::default(),
+            Arc::default(),
+            metrics.clone(),
+            Batching {
+                size: 3,
+                ..Batching::default()
+            },
+        );
+
+        let mut writes = tokio::task::JoinSet::new();
+        for _ in 0..5 {
+            let writer = writer.clone();
+            writes.spawn(async move { writer.write(vec![pending_event(), pending_event()]).await });
+        }
+        while let Some(stored) = writes.join_next().await {
+            assert_eq!(stored.unwrap().unwrap(), 2);
+        }
+
+        assert_eq!(count(&connection).await, 10);
+        assert_eq!(metrics.buffered_events.get(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_writer_task_stores_queued_requests_before_it_finishes() {
+        let Buffer { connection, writer } = Buffer::in_memory().await.unwrap();
+        let (writer, task) = Writer::spawn(
+            writer,
+            Arc::default(),
+            Arc::default(),
+            Arc::default(),
+            Batching {
+                interval: Duration::from_secs(60),
+                ..Batching::default()
+            },
+        );
+
+        // Requests that were accepted but not stored yet, as when the server stops
+        let mut replies = Vec::new();
+        for _ in 0..3 {
+            let (reply, stored) = oneshot::channel();
+            let queued = writer.sender.try_send(Write {
+                events: vec![pending_event()],
+                reply,
+            });
+            assert!(queued.is_ok());
+            replies.push(stored);
+        }
+        drop(writer);
+
+        // Once the last sender is gone the queue is stored without waiting for the interval
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        for stored in replies {
+            assert_eq!(stored.await.unwrap().unwrap(), 1);
+        }
+        assert_eq!(count(&connection).await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_write_rejects_requests_that_do_not_fit_on_their_own() {
+        let Buffer { connection, writer } = Buffer::in_memory().await.unwrap();
+        let retention = Arc::new(Retention::with_max_rows(3));
+        let (writer, _) = Writer::spawn(
+            writer,
+            retention,
+            Arc::default(),
+            Arc::default(),
+            Batching::default(),
+        );
+
+        let (fits, overflows) = tokio::join!(
+            writer.write(vec![pending_event(), pending_event()]),
+            writer.write(vec![pending_event(), pending_event()]),
+        );
+
+        assert_eq!(fits.unwrap(), 2);
+        assert!(matches!(
+            overflows,
+            Err(ApplicationError::ServiceUnavailable(_))
+        ));
+        assert_eq!(count(&connection).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_write_rolls_back_everything_a_failed_request_changed() {
+        let Buffer { connection, writer } = Buffer::in_memory().await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let (writer, _) = Writer::spawn(
+            writer,
+            Arc::new(Retention::dropping_oldest(2)),
+            Arc::default(),
+            metrics.clone(),
+            Batching::default(),
+        );
+        connection
+            .execute(
+                "CREATE TRIGGER reject_failing_app BEFORE INSERT ON events \
+                 WHEN NEW.recorded_by = 'failing-app' BEGIN SELECT RAISE(ABORT, 'rejected'); END",
+                (),
+            )
+            .await
+            .unwrap();
+
+        writer
+            .write(vec![pending_event(), pending_event()])
+            .await
+            .unwrap();
+        let failing = PendingEvent {
+            recorded_by: "failing-app".to_string(),
+            ..pending_event()
+        };
+        assert!(writer.write(vec![failing]).await.is_err());
+
+        // The event dropped to make room for the failed request is back
+        assert_eq!(count(&connection).await, 2);
+        assert_eq!(metrics.buffered_events.get(), 2);
+
+        assert_eq!(writer.write(vec![pending_event()]).await.unwrap(), 1);
+        assert_eq!(count(&connection).await, 2);
+        assert_eq!(metrics.buffered_events.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_write_counts_only_events_it_stored() {
+        let Buffer { writer, .. } = Buffer::in_memory().await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let (writer, _) = Writer::spawn(
+            writer,
+            Arc::default(),
+            Arc::default(),
+            metrics.clone(),
+            Batching::default(),
+        );
+        let event = pending_event();
+        let retried = PendingEvent {
+            id: event.id.clone(),
+            ..pending_event()
+        };
+
+        assert_eq!(writer.write(vec![event]).await.unwrap(), 1);
+        assert_eq!(writer.write(vec![retried]).await.unwrap(), 0);
+
+        let mut registry = prometheus_client::registry::Registry::default();
+        registry.register("events", "", metrics.events.clone());
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, &registry).unwrap();
+        assert!(
+            encoded.contains(
+                r#"events_total{entity="page",action="view",app_id="test-app",path=""} 1"#
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_tracks_sessions_of_stored_requests_only() {
+        let Buffer { connection, writer } = Buffer::in_memory().await.unwrap();
+        let (writer, _) = Writer::spawn(
+            writer,
+            Arc::new(Retention::with_max_rows(2)),
+            Arc::default(),
+            Arc::default(),
+            Batching::default(),
+        );
+        let page_view = || PendingEvent {
+            visit: Some(Visit {
+                visitor_id: "visitor".to_string(),
+                page_view: Some(PageView { path: None }),
+            }),
+            ..pending_event()
+        };
+
+        writer.write(vec![page_view()]).await.unwrap();
+        assert!(writer.write(vec![page_view(), page_view()]).await.is_err());
+        writer.write(vec![page_view()]).await.unwrap();
+
+        let mut rows = connection
+            .query("SELECT page_count FROM sessions", ())
+            .await
+            .unwrap();
+        let page_count: i64 = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(page_count, 2);
+        assert!(rows.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_rejects_requests_when_the_queue_is_full() {
+        let (sender, _receiver) = mpsc::channel(1);
+        let metrics = Arc::new(Metrics::default());
+        let writer = Writer {
+            sender,
+            metrics: metrics.clone(),
+        };
+
+        // Nothing drains the queue, so the first request waits for good
+        let waiting = writer.write(vec![pending_event()]);
+        tokio::pin!(waiting);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), &mut waiting)
+                .await
+                .is_err()
+        );
+
+        let rejected = writer.write(vec![pending_event()]).await;
+        assert!(matches!(
+            rejected,
+            Err(ApplicationError::ServiceUnavailable(_))
+        ));
+        assert_eq!(metrics.rejected_writes.get(), 1);
+    }
+}
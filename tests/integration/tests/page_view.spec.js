@@ -1,6 +1,25 @@
 import { test, expect } from '@playwright/test';
 
-test('captures a page view and returns as a metric', async ({ page }) => {
+// Headless Chromium announces itself in its User-Agent, which the default bot filter tags
+const BROWSER_USER_AGENT = 'Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36';
+
+test.describe('from a browser', () => {
+  test.use({ userAgent: BROWSER_USER_AGENT });
+
+  test('captures a page view and returns as a metric', async ({ page }) => {
+    await Promise.all([
+      page.waitForRequest(request => request.url().includes('31003') && request.method() === 'POST'),
+      page.goto('http://localhost:3000/')
+    ])
+
+    const metrics = await page.goto('http://localhost:31004/metrics')
+    const results = await metrics.body()
+
+    expect(results.toString()).toContain('entity="page",action="view",app_id="integration-tests"');
+  });
+});
+
+test('tags a page view from a headless browser as a bot', async ({ page }) => {
   await Promise.all([
     page.waitForRequest(request => request.url().includes('31003') && request.method() === 'POST'),
     page.goto('http://localhost:3000/')
@@ -9,5 +28,5 @@ test('captures a page view and returns as a metric', async ({ page }) => {
   const metrics = await page.goto('http://localhost:31004/metrics')
   const results = await metrics.body()
   
-  expect(results.toString()).toContain('entity="page",action="view",app_id="integration-tests"');
+  expect(results.toString()).toContain('filtered_events_total{reason="headless",app_id="integration-tests"}');
 });